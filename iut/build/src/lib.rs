@@ -0,0 +1,252 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::{
+    collections::{BTreeSet, HashMap},
+    env, fs,
+    path::PathBuf,
+    process::Command,
+};
+
+/// The identity of the library an IUT is built with, as resolved by cargo.
+#[derive(Clone, Debug, PartialEq)]
+struct Identity {
+    crate_name: String,
+    version: String,
+    /// Commit of a git dependency
+    git_rev: Option<String>,
+    /// Features cargo enabled on the library crate
+    features: Vec<String>,
+    tls: Option<String>,
+}
+
+/// Writes the identity of the direct dependency `crate_name` to `$OUT_DIR/library.rs`,
+/// as a `common::Library` named `name`. Call it from the build script of an IUT and
+/// include the file with `include!(concat!(env!("OUT_DIR"), "/library.rs"))`.
+pub fn write_library_file(name: &str, crate_name: &str) -> Result<()> {
+    let manifest = PathBuf::from(env::var("CARGO_MANIFEST_DIR")?).join("Cargo.toml");
+    let mut cargo = Command::new(env::var("CARGO").unwrap_or_else(|_| String::from("cargo")));
+    cargo
+        .args(["metadata", "--format-version", "1", "--offline"])
+        .arg("--manifest-path")
+        .arg(&manifest);
+    if let Ok(target) = env::var("TARGET") {
+        cargo.args(["--filter-platform", &target]);
+    }
+    let output = cargo.output().context("run cargo metadata")?;
+    if !output.status.success() {
+        bail!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let metadata: Value = serde_json::from_slice(&output.stdout)?;
+
+    println!("cargo:rerun-if-changed={}", manifest.display());
+    if let Some(root) = metadata["workspace_root"].as_str() {
+        println!("cargo:rerun-if-changed={}/Cargo.lock", root);
+    }
+
+    let identity = identify(&metadata, crate_name)?;
+    let out = PathBuf::from(env::var("OUT_DIR")?).join("library.rs");
+    fs::write(&out, library_expr(name, &identity))
+        .with_context(|| format!("write {}", out.display()))
+}
+
+/// Finds `crate_name` among the dependencies of the root package in `cargo metadata` output.
+fn identify(metadata: &Value, crate_name: &str) -> Result<Identity> {
+    let packages: HashMap<&str, &Value> = metadata["packages"]
+        .as_array()
+        .ok_or_else(|| anyhow!("cargo metadata has no packages"))?
+        .iter()
+        .filter_map(|p| Some((p["id"].as_str()?, p)))
+        .collect();
+    let nodes: HashMap<&str, &Value> = metadata["resolve"]["nodes"]
+        .as_array()
+        .ok_or_else(|| anyhow!("cargo metadata has no resolve"))?
+        .iter()
+        .filter_map(|n| Some((n["id"].as_str()?, n)))
+        .collect();
+    let root = metadata["resolve"]["root"]
+        .as_str()
+        .and_then(|id| nodes.get(id))
+        .ok_or_else(|| anyhow!("cargo metadata has no root package"))?;
+
+    // match the package name exactly, so that `quinn` is not mistaken for `quinn-proto`
+    let id = normal_deps(root)
+        .find(|id| packages.get(id).and_then(|p| p["name"].as_str()) == Some(crate_name))
+        .ok_or_else(|| anyhow!("{} is not a dependency", crate_name))?;
+    let package = packages[id];
+    let features = strings(&nodes[id]["features"]);
+
+    // the packages the library links, to tell its TLS backend
+    let mut linked = BTreeSet::from([id]);
+    let mut queue = vec![id];
+    while let Some(id) = queue.pop() {
+        for dep in nodes.get(id).into_iter().flat_map(|n| normal_deps(n)) {
+            if linked.insert(dep) {
+                queue.push(dep);
+            }
+        }
+    }
+    let linked: HashMap<&str, Vec<String>> = linked
+        .into_iter()
+        .filter_map(|id| {
+            let name = packages.get(id)?["name"].as_str()?;
+            Some((name, strings(&nodes.get(id)?["features"])))
+        })
+        .collect();
+
+    Ok(Identity {
+        crate_name: crate_name.to_string(),
+        version: package["version"].as_str().unwrap_or("unknown").to_string(),
+        git_rev: package["source"]
+            .as_str()
+            .filter(|source| source.starts_with("git+"))
+            .and_then(|source| source.rsplit_once('#'))
+            .map(|(_, rev)| rev.to_string()),
+        tls: tls_backend(&features, &linked),
+        features,
+    })
+}
+
+/// The ids of the normal, i.e. not dev or build, dependencies of a resolve node
+fn normal_deps(node: &Value) -> impl Iterator<Item = &str> {
+    node["deps"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|dep| {
+            let normal = dep["dep_kinds"]
+                .as_array()
+                .is_none_or(|kinds| kinds.iter().any(|k| k["kind"].is_null()));
+            normal.then(|| dep["pkg"].as_str()).flatten()
+        })
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    let mut strings: Vec<String> = value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.as_str().map(String::from))
+        .collect();
+    strings.sort();
+    strings
+}
+
+/// Tells the TLS backend from the features of the library and the features of the
+/// packages it links, e.g. `rustls-ring` or `nss`.
+fn tls_backend(features: &[String], linked: &HashMap<&str, Vec<String>>) -> Option<String> {
+    if features.iter().any(|f| f.starts_with("boringssl")) || linked.contains_key("boring-sys") {
+        return Some(String::from("boringssl"));
+    }
+    if features.iter().any(|f| f.starts_with("openssl")) || linked.contains_key("openssl-sys") {
+        return Some(String::from("openssl"));
+    }
+    if linked.contains_key("nss-rs") || linked.contains_key("nss-gk-api") {
+        return Some(String::from("nss"));
+    }
+    let rustls = linked.get("rustls")?;
+    let provider = ["aws_lc_rs", "aws-lc-rs", "ring"]
+        .into_iter()
+        .find(|p| rustls.iter().any(|f| f == p));
+    Some(match provider {
+        Some(provider) => format!("rustls-{}", provider.replace('_', "-")),
+        None => String::from("rustls"),
+    })
+}
+
+fn library_expr(name: &str, identity: &Identity) -> String {
+    format!(
+        "Library {{ name: {:?}, crate_name: {:?}, version: {:?}, git_rev: {:?}, features: &{:?}, tls: {:?} }}\n",
+        name,
+        identity.crate_name,
+        identity.version,
+        identity.git_rev,
+        identity.features,
+        identity.tls
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn package(name: &str, version: &str, source: Option<&str>) -> Value {
+        json!({ "name": name, "version": version, "id": format!("{}@{}", name, version), "source": source })
+    }
+
+    fn node(id: &str, deps: &[(&str, Option<&str>)], features: &[&str]) -> Value {
+        let deps: Vec<Value> = deps
+            .iter()
+            .map(|(pkg, kind)| json!({ "pkg": pkg, "dep_kinds": [{ "kind": kind }] }))
+            .collect();
+        json!({ "id": id, "deps": deps, "features": features })
+    }
+
+    #[test]
+    fn rustls() {
+        let metadata = json!({
+            "packages": [
+                package("quinn-iut", "0.1.0", None),
+                package("quinn-proto", "0.11.14", Some("registry+https://github.com/rust-lang/crates.io-index")),
+                package("quinn", "0.11.9", Some("registry+https://github.com/rust-lang/crates.io-index")),
+                package("rustls", "0.23.38", Some("registry+https://github.com/rust-lang/crates.io-index")),
+                package("openssl-sys", "0.9.0", None),
+            ],
+            "resolve": {
+                "root": "quinn-iut@0.1.0",
+                "nodes": [
+                    node("quinn-iut@0.1.0", &[("quinn-proto@0.11.14", None), ("quinn@0.11.9", None), ("rustls@0.23.38", None)], &[]),
+                    node("quinn@0.11.9", &[("quinn-proto@0.11.14", None), ("rustls@0.23.38", None), ("openssl-sys@0.9.0", Some("dev"))], &["runtime-tokio", "rustls", "default"]),
+                    node("quinn-proto@0.11.14", &[("rustls@0.23.38", None)], &[]),
+                    node("rustls@0.23.38", &[], &["ring", "std"]),
+                ],
+            },
+        });
+
+        assert_eq!(
+            identify(&metadata, "quinn").unwrap(),
+            Identity {
+                crate_name: String::from("quinn"),
+                version: String::from("0.11.9"),
+                git_rev: None,
+                features: ["default", "runtime-tokio", "rustls"]
+                    .map(String::from)
+                    .to_vec(),
+                tls: Some(String::from("rustls-ring")),
+            }
+        );
+        assert!(identify(&metadata, "quin").is_err());
+    }
+
+    #[test]
+    fn git() {
+        let metadata = json!({
+            "packages": [
+                package("neqo-iut", "0.1.0", None),
+                package("neqo-transport", "0.28.1", Some("git+https://github.com/mozilla/neqo.git?tag=v0.28.1#3f1c0a2b9d8e")),
+                package("neqo-crypto", "0.28.1", Some("git+https://github.com/mozilla/neqo.git?tag=v0.28.1#3f1c0a2b9d8e")),
+                package("nss-rs", "0.9.0", Some("git+https://github.com/mozilla/nss-rs.git?rev=0.9.0#77aa01")),
+            ],
+            "resolve": {
+                "root": "neqo-iut@0.1.0",
+                "nodes": [
+                    node("neqo-iut@0.1.0", &[("neqo-transport@0.28.1", None)], &[]),
+                    node("neqo-transport@0.28.1", &[("neqo-crypto@0.28.1", None)], &[]),
+                    node("neqo-crypto@0.28.1", &[("nss-rs@0.9.0", None)], &[]),
+                    node("nss-rs@0.9.0", &[], &[]),
+                ],
+            },
+        });
+
+        let identity = identify(&metadata, "neqo-transport").unwrap();
+        assert_eq!(identity.git_rev.as_deref(), Some("3f1c0a2b9d8e"));
+        assert_eq!(identity.tls.as_deref(), Some("nss"));
+        assert_eq!(
+            library_expr("neqo", &identity),
+            "Library { name: \"neqo\", crate_name: \"neqo-transport\", version: \"0.28.1\", git_rev: Some(\"3f1c0a2b9d8e\"), features: &[], tls: Some(\"nss\") }\n"
+        );
+    }
+}
@@ -3,20 +3,30 @@ use clap::{Parser, Subcommand};
 use core_affinity::{self, CoreId};
 use futures::future::Either::*;
 use socket2::{Domain, Protocol, Socket, Type};
+use spans::SpanLayer;
 use std::net::SocketAddr;
-use std::{collections::HashMap, env, future::Future, mem::MaybeUninit};
-use tokio::{
-    signal::unix::{signal, SignalKind},
-    sync::oneshot,
-};
-use tracing::{error, info, trace, warn};
+use std::path::PathBuf;
+use std::{collections::HashMap, future::Future};
+use tokio::signal::unix::{signal, SignalKind};
+#[cfg(not(feature = "metrics"))]
+use tracing::warn;
+use tracing::{error, info, trace};
+use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt};
 use utils::{
     bin::{Client, ClientArgs, Server, ServerArgs},
+    labels::{parse_label, user_labels},
+    metadata,
     perf::{Request, Stats},
 };
 
+mod library;
+#[cfg(feature = "metrics")]
+mod metrics;
+pub mod spans;
 pub mod test;
 
+pub use library::Library;
+
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about)]
 pub struct Cli {
@@ -46,14 +56,29 @@ pub struct CommonArgs {
     #[clap(short, long)]
     pub job: Option<String>,
 
-    #[clap(short = 'L')]
-    pub labels: Option<Vec<String>>,
+    /// Run label `key:value`, may be repeated. Values may contain colons
+    #[clap(short = 'L', value_parser = parse_label)]
+    pub labels: Vec<(String, String)>,
+
+    /// File with one `key:value` label per line
+    #[clap(long, value_name = "FILE")]
+    pub labels_file: Option<PathBuf>,
 
     #[clap(long)]
     pub quic_cpu: Option<usize>,
 
     #[clap(long)]
     pub metric_cpu: Option<usize>,
+
+    /// Where the in-process collector writes the metrics, see `nesquic --sink`
+    #[cfg(feature = "metrics")]
+    #[clap(long = "sink", value_name = "SINK")]
+    pub sinks: Vec<String>,
+
+    /// Where InfluxDB batches that were not accepted are kept for `nesquic flush`
+    #[cfg(feature = "metrics")]
+    #[clap(long, default_value = "nesquic-spool")]
+    pub spool: PathBuf,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -85,10 +110,11 @@ async fn run_client<C: Client>(args: ClientArgs) -> Result<()> {
     stats.add_bytes(req.len())?;
     stats.stop_measurement()?;
 
-    // THROUGHPUT_SAMPLES
-    //     .lock()
-    //     .unwrap()
-    //     .push(stats.throughputs().mean());
+    #[cfg(feature = "metrics")]
+    nesquic::metrics::THROUGHPUT_SAMPLES
+        .lock()
+        .unwrap()
+        .push(stats.throughputs().mean());
 
     Ok(())
 }
@@ -98,33 +124,22 @@ async fn run_server<S: Server>(args: ServerArgs) -> Result<()> {
     s.listen().await
 }
 
-fn build_labels(cli: &Cli, lib_name: &str, lib_version: &str) -> HashMap<String, String> {
+fn build_labels(cli: &Cli, library: &Library) -> Result<HashMap<String, String>> {
     let log_level = tracing::level_filters::LevelFilter::current().to_string();
     let mode = match cli.command {
         Command::Client(_) => String::from("client"),
         Command::Server(_) => String::from("server"),
     };
 
-    let run_labels = cli
-        .command
-        .args()
-        .labels
-        .clone()
-        .unwrap_or_default()
-        .iter()
-        .map(|h| {
-            let hs: Vec<&str> = h.split_terminator(':').map(|s| s.trim()).collect();
-            (hs[0].to_string(), hs[1].to_string())
-        })
-        .collect::<HashMap<String, String>>();
-
-    let mut labels = HashMap::new();
+    let args = cli.command.args();
+    let run_labels = user_labels(&args.labels, args.labels_file.as_deref())?;
+
+    let mut labels = metadata::run_metadata(None);
     labels.insert(String::from("log_level"), log_level);
-    labels.insert(String::from("library"), lib_name.to_string());
+    labels.extend(library.labels());
     labels.insert(String::from("mode"), mode);
-    labels.insert(String::from("version"), lib_version.to_string());
     labels.extend(run_labels);
-    labels
+    Ok(labels)
 }
 
 fn get_core_id(idx: usize) -> Result<CoreId> {
@@ -147,83 +162,62 @@ async fn select_with_term_signals<T>(future: impl Future<Output = T>) -> Option<
     }
 }
 
-/// Run the IUT binary: parse CLI, collect eBPF metrics, and execute client or server.
+/// Run the IUT binary: parse CLI, collect eBPF metrics with the `metrics` feature, and
+/// execute client or server.
 ///
-/// `lib_name` and `lib_version` are embedded as InfluxDB tags on every measurement.
-pub async fn run<C: Client, S: Server>(lib_name: &str, lib_version: &str) -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::FULL)
+/// The identity of `library` is embedded as InfluxDB tags on every measurement.
+pub async fn run<C: Client, S: Server>(library: Library) -> Result<()> {
+    let spans = SpanLayer::from_env()?;
+    let span_writer = spans.as_ref().map(SpanLayer::writer);
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::FULL))
+        .with(spans)
+        .with(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
     let cli = Cli::parse();
-    let labels = build_labels(&cli, lib_name, lib_version);
+    let labels = build_labels(&cli, &library)?;
     let job = cli.command.args().job.clone();
-
-    // let (job_start_tx, job_start_rx) = oneshot::channel();
-    // let (job_done_tx, job_done_rx) = oneshot::channel();
+    trace!("Job {:?} with labels {:?}", job, labels);
 
     let quic_core = cli.command.args().quic_cpu.map(get_core_id).transpose()?;
     let metric_core = cli.command.args().metric_cpu.map(get_core_id).transpose()?;
 
-    // let monitor_handle = tokio::spawn(async move {
-    //     if let Some(core) = metric_core {
-    //         trace!("Set metric core to {}", core.id);
-    //         core_affinity::set_for_current(core);
-    //     }
-
-    //     let mut open_obj = MaybeUninit::uninit();
-    //     let mut monitor = MetricsCollector::new(&mut open_obj).expect("metrics collector");
-    //     monitor.monitor_io().expect("monitor IO");
-
-    //     _ = job_start_tx.send(());
-    //     _ = job_done_rx.await;
-
-    //     if let Some(job) = job {
-    //         if let (Ok(url), Ok(token), Ok(org), Ok(bucket)) = (
-    //             env::var("INFLUX_URL"),
-    //             env::var("INFLUX_TOKEN"),
-    //             env::var("INFLUX_ORG"),
-    //             env::var("INFLUX_BUCKET"),
-    //         ) {
-    //             info!("Pushing metrics to InfluxDB at {}", url);
-
-    //             if let Some(Err(e)) =
-    //                 select_with_term_signals(monitor.push_all(url, token, org, bucket, job, labels))
-    //                     .await
-    //             {
-    //                 error!("Error pushing metrics to InfluxDB: {}", e);
-    //             }
-    //         }
-    //     } else if let Err(e) = monitor.report() {
-    //         error!("Error reporting metrics: {}", e);
-    //     }
-    // });
+    #[cfg(feature = "metrics")]
+    let monitor = {
+        let args = cli.command.args();
+        metrics::Monitor::start(job, labels, &args.sinks, &args.spool, metric_core).await?
+    };
+    #[cfg(not(feature = "metrics"))]
+    if metric_core.is_some() {
+        warn!("--metric-cpu needs the metrics feature");
+    }
 
     if let Some(core) = quic_core {
         trace!("Set quic core to {}", core.id);
         core_affinity::set_for_current(core);
     }
 
-    // job_start_rx.await.expect("Failed to start job");
-
     let job = match &cli.command {
         Command::Client(args) => Left(run_client::<C>(args.client.clone())),
         Command::Server(args) => Right(run_server::<S>(args.server.clone())),
     };
 
-    match select_with_term_signals(job).await {
+    let result = select_with_term_signals(job).await;
+    if let Some(Err(e)) = span_writer.map(|writer| writer.flush()) {
+        error!("Failed to write spans: {:#}", e);
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(monitor) = monitor {
+        monitor.stop().await;
+    }
+
+    match result {
         Some(Ok(())) => info!("Job completed"),
         Some(Err(e)) => bail!(e),
         _ => trace!("Job cancelled"),
     }
 
-    // if job_done_tx.send(()).is_ok() {
-    //     monitor_handle.await?;
-    // } else {
-    //     warn!("Pushing metrics potentially failed");
-    // }
-
     Ok(())
 }
 
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+/// Identity of the QUIC library an IUT is built with, generated at build time by
+/// `iut_build::write_library_file`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Library {
+    /// Short name, e.g. `neqo`
+    pub name: &'static str,
+    /// Crate the version belongs to, e.g. `neqo-transport`
+    pub crate_name: &'static str,
+    pub version: &'static str,
+    /// Commit of a git dependency
+    pub git_rev: Option<&'static str>,
+    pub features: &'static [&'static str],
+    pub tls: Option<&'static str>,
+}
+
+impl Library {
+    /// The identity as metric labels, leaving out what is unknown.
+    pub fn labels(&self) -> HashMap<String, String> {
+        let mut labels = HashMap::from([
+            (String::from("library"), self.name.to_string()),
+            (String::from("crate"), self.crate_name.to_string()),
+            (String::from("version"), self.version.to_string()),
+        ]);
+        if let Some(git_rev) = self.git_rev {
+            labels.insert(String::from("git_rev"), git_rev.to_string());
+        }
+        if !self.features.is_empty() {
+            labels.insert(String::from("features"), self.features.join(","));
+        }
+        if let Some(tls) = self.tls {
+            labels.insert(String::from("tls"), tls.to_string());
+        }
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        let library = Library {
+            name: "neqo",
+            crate_name: "neqo-transport",
+            version: "0.28.1",
+            git_rev: Some("3f1c0a2b9d8e"),
+            features: &[],
+            tls: Some("nss"),
+        };
+        let mut labels: Vec<(String, String)> = library.labels().into_iter().collect();
+        labels.sort();
+        assert_eq!(
+            labels,
+            [
+                ("crate", "neqo-transport"),
+                ("git_rev", "3f1c0a2b9d8e"),
+                ("library", "neqo"),
+                ("tls", "nss"),
+                ("version", "0.28.1"),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+    }
+}
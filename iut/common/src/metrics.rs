@@ -0,0 +1,94 @@
+use crate::spans;
+use anyhow::Result;
+use core_affinity::CoreId;
+use nesquic::{metrics::sink, MetricsCollector};
+use std::{collections::HashMap, mem::MaybeUninit, path::Path, thread};
+use tokio::{runtime, sync::oneshot};
+use tracing::{error, trace, warn};
+
+/// The eBPF collector an IUT runs on itself, on a thread of its own, from before
+/// the job starts until it is done. The syscalls and CPU time of that thread are
+/// left out, but the eBPF programs still run in the context of the IUT's threads.
+pub struct Monitor {
+    done: oneshot::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Monitor {
+    /// Starts the collector and waits until its probes are attached. The metrics go
+    /// to the `sinks`, see `nesquic --sink`. If the eBPF programs cannot be loaded,
+    /// the IUT runs without a collector.
+    pub async fn start(
+        job: Option<String>,
+        labels: HashMap<String, String>,
+        sinks: &[String],
+        spool: &Path,
+        core: Option<CoreId>,
+    ) -> Result<Option<Self>> {
+        let sinks = sink::parse_sinks(sinks, job.is_some(), spool)?;
+        let (started_tx, started_rx) = oneshot::channel::<Result<()>>();
+        let (done, done_rx) = oneshot::channel();
+        let handle = thread::Builder::new()
+            .name(String::from("nesquic-metrics"))
+            .spawn(move || {
+                if let Some(core) = core {
+                    trace!("Set metric core to {}", core.id);
+                    core_affinity::set_for_current(core);
+                }
+                let runtime = match runtime::Builder::new_current_thread().enable_all().build() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        _ = started_tx.send(Err(e.into()));
+                        return;
+                    }
+                };
+                runtime.block_on(async move {
+                    let mut open_obj = MaybeUninit::uninit();
+                    let builder = MetricsCollector::builder()
+                        .collector_tid(spans::gettid())
+                        .job(job)
+                        .labels(labels)
+                        .sinks(sinks);
+                    let started = builder.build(&mut open_obj).and_then(|mut monitor| {
+                        monitor.start()?;
+                        Ok(monitor)
+                    });
+                    let mut monitor = match started {
+                        Ok(monitor) => monitor,
+                        Err(e) => {
+                            _ = started_tx.send(Err(e));
+                            return;
+                        }
+                    };
+                    _ = started_tx.send(Ok(()));
+
+                    _ = done_rx.await;
+                    if let Err(e) = monitor.finish(Vec::new()).await {
+                        error!("Error collecting metrics: {}", e);
+                    }
+                });
+            })?;
+
+        match started_rx.await {
+            Ok(Ok(())) => Ok(Some(Monitor { done, handle })),
+            Ok(Err(e)) => {
+                warn!("Not collecting metrics: {:#}", e);
+                Ok(None)
+            }
+            Err(_) => {
+                warn!("Not collecting metrics, the collector exited");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Stops the collector and waits until it wrote the metrics.
+    pub async fn stop(self) {
+        _ = self.done.send(());
+        let handle = self.handle;
+        match tokio::task::spawn_blocking(move || handle.join()).await {
+            Ok(Ok(())) => {}
+            _ => error!("The metrics collector panicked"),
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{Context as _, Result};
+use serde_json::{json, Map, Value};
+use std::{
+    cell::RefCell,
+    env,
+    fmt::Debug,
+    fs::File,
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Path of the file the spans are written to, read by `nesquic --spans`
+pub const SPANS_ENV: &str = "NESQUIC_SPANS";
+/// Spans are buffered, so that writing them hardly shows up in the syscall metrics
+const BUFFER_SIZE: usize = 1 << 20;
+
+thread_local! {
+    /// Spans entered on this thread, with the time they were entered
+    static ENTERED: RefCell<Vec<(Id, u64)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Records every time a span was entered as a Chrome trace event, one JSON
+/// object per line. Timestamps are `CLOCK_MONOTONIC` like those of the eBPF
+/// collector, so that `nesquic --trace` can merge both on one timeline.
+pub struct SpanLayer {
+    pid: u32,
+    writer: SpanWriter,
+}
+
+/// The buffered span file, which must be flushed before the IUT exits.
+#[derive(Clone)]
+pub struct SpanWriter(Arc<Mutex<BufWriter<File>>>);
+
+/// The fields of a span
+struct Fields(Map<String, Value>);
+
+impl SpanLayer {
+    /// Creates the file at [`SPANS_ENV`], if it is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(path) = env::var(SPANS_ENV) else {
+            return Ok(None);
+        };
+        let file = File::create(&path).with_context(|| format!("create {}", path))?;
+        Ok(Some(SpanLayer {
+            pid: std::process::id(),
+            writer: SpanWriter(Arc::new(Mutex::new(BufWriter::with_capacity(
+                BUFFER_SIZE,
+                file,
+            )))),
+        }))
+    }
+
+    pub fn writer(&self) -> SpanWriter {
+        self.writer.clone()
+    }
+}
+
+impl SpanWriter {
+    pub fn flush(&self) -> Result<()> {
+        let mut out = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        out.flush().context("flush spans")
+    }
+
+    fn write(&self, event: &Value) -> Result<()> {
+        let mut out = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_writer(&mut *out, event)?;
+        out.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+impl<S> Layer<S> for SpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields(Map::new());
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, _ctx: Context<'_, S>) {
+        ENTERED.with(|entered| entered.borrow_mut().push((id.clone(), monotonic_ns())));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let end_ns = monotonic_ns();
+        let start_ns = ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            let pos = entered.iter().rposition(|(entered, _)| entered == id)?;
+            Some(entered.remove(pos).1)
+        });
+        let (Some(start_ns), Some(span)) = (start_ns, ctx.span(id)) else {
+            return;
+        };
+
+        let args = span
+            .extensions()
+            .get::<Fields>()
+            .map(|fields| fields.0.clone())
+            .unwrap_or_default();
+        let event = chrome_event(
+            span.name(),
+            span.metadata().target(),
+            self.pid,
+            gettid(),
+            start_ns,
+            end_ns,
+            args,
+        );
+        if let Err(e) = self.writer.write(&event) {
+            // not through tracing, which would record into this layer again
+            eprintln!("Failed to write span: {}", e);
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().to_string(), json!(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+}
+
+/// A complete event, whose timestamps are in microseconds.
+fn chrome_event(
+    name: &str,
+    target: &str,
+    pid: u32,
+    tid: u32,
+    start_ns: u64,
+    end_ns: u64,
+    args: Map<String, Value>,
+) -> Value {
+    json!({
+        "name": name,
+        "cat": target,
+        "ph": "X",
+        "ts": start_ns as f64 / 1e3,
+        "dur": end_ns.saturating_sub(start_ns) as f64 / 1e3,
+        "pid": pid,
+        "tid": tid,
+        "args": args,
+    })
+}
+
+/// The clock of `bpf_ktime_get_ns`
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: ts is a valid timespec that outlives the call
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+pub(crate) fn gettid() -> u32 {
+    // SAFETY: gettid takes no arguments and cannot fail
+    unsafe { libc::gettid() as u32 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn spans() {
+        let path = env::temp_dir().join(format!("nesquic-spans-{}.jsonl", std::process::id()));
+        let layer = SpanLayer {
+            pid: 7,
+            writer: SpanWriter(Arc::new(Mutex::new(BufWriter::new(
+                File::create(&path).unwrap(),
+            )))),
+        };
+        let writer = layer.writer();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let conn = info_span!("connection", peer = "127.0.0.1:4433");
+            let _conn = conn.enter();
+            let send = info_span!("send", bytes = 1200u64);
+            send.record("bytes", 1350u64);
+            drop(send.enter());
+        });
+        writer.flush().unwrap();
+
+        let events: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["name"], "send");
+        assert_eq!(events[0]["args"]["bytes"], 1350);
+        assert_eq!(events[1]["name"], "connection");
+        assert_eq!(events[1]["args"]["peer"], "127.0.0.1:4433");
+        assert_eq!(events[1]["pid"], 7);
+        assert_eq!(events[1]["tid"], gettid());
+        let start = |e: &Value| e["ts"].as_f64().unwrap();
+        let end = |e: &Value| start(e) + e["dur"].as_f64().unwrap();
+        assert!(start(&events[1]) <= start(&events[0]));
+        assert!(end(&events[0]) <= end(&events[1]));
+    }
+}
@@ -1,3 +1,4 @@
 fn main() {
-    built::write_built_file().expect("Failed to write built file");
+    iut_build::write_library_file("neqo", "neqo-transport")
+        .expect("Failed to write library identity");
 }
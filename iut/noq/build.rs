@@ -1,3 +1,3 @@
 fn main() {
-    built::write_built_file().expect("Failed to write built file");
+    iut_build::write_library_file("noq", "noq").expect("Failed to write library identity");
 }
@@ -1,19 +1,11 @@
-use common::run;
+use common::{run, Library};
 use quiche_iut::{Client, Server};
 
-mod built_info {
-    include!(concat!(env!("OUT_DIR"), "/built.rs"));
-}
+const LIBRARY: Library = include!(concat!(env!("OUT_DIR"), "/library.rs"));
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let version = built_info::INDIRECT_DEPENDENCIES
-        .iter()
-        .find(|(name, _)| name.contains("quiche"))
-        .map(|(_, v)| *v)
-        .unwrap_or("unknown");
-
-    run::<Client, Server>("quiche", version).await
+    run::<Client, Server>(LIBRARY).await
 }
 
 #[cfg(test)]
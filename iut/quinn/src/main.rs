@@ -1,23 +1,15 @@
 use client::Client;
-use common::run;
+use common::{run, Library};
 use server::Server;
 
 mod client;
 mod server;
 
-mod built_info {
-    include!(concat!(env!("OUT_DIR"), "/built.rs"));
-}
+const LIBRARY: Library = include!(concat!(env!("OUT_DIR"), "/library.rs"));
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let version = built_info::INDIRECT_DEPENDENCIES
-        .iter()
-        .find(|(name, _)| name.contains("quinn"))
-        .map(|(_, v)| *v)
-        .unwrap_or("unknown");
-
-    run::<Client, Server>("quinn", version).await
+    run::<Client, Server>(LIBRARY).await
 }
 
 #[cfg(test)]
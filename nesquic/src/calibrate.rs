@@ -0,0 +1,333 @@
+use anyhow::{bail, Result};
+use clap::Args;
+use libbpf_rs::OpenObject;
+use nesquic::{
+    metrics::{components::ComponentConfig, procfs::ProcStats, symbols},
+    MetricsCollector, ProbeSet,
+};
+use std::{
+    fs, io,
+    mem::MaybeUninit,
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{Child, Command},
+    ptr,
+    time::{Duration, Instant},
+};
+use tracing::info;
+use utils::perf::Request;
+
+#[derive(Args, Debug, Clone)]
+pub struct CalibrateArgs {
+    /// How often to run the benchmark with each probe set
+    #[clap(long, default_value_t = 3)]
+    pub runs: usize,
+
+    /// Size of the blob the benchmark transfers, to report absolute throughputs
+    #[clap(long)]
+    pub blob: Option<String>,
+
+    /// Component config to attach with all probes, see `res/components.yaml`
+    #[clap(long)]
+    pub components: Option<PathBuf>,
+
+    /// Sampling frequency (Hz) of the profiler with all probes
+    #[clap(long, default_value_t = 99)]
+    pub profile: u64,
+
+    /// The IUT client to benchmark, after `--`
+    #[clap(last = true, required = true)]
+    pub command: Vec<String>,
+}
+
+/// One benchmark run.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Run {
+    wall: Duration,
+    /// CPU time of the benchmark, which includes the probes running in its context
+    iut_cpu_seconds: f64,
+    /// CPU time the collector spent while the benchmark ran
+    collector_cpu_seconds: f64,
+}
+
+/// The mean of the runs with one probe set.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Calibration {
+    wall_seconds: f64,
+    iut_cpu_seconds: f64,
+    collector_cpu_seconds: f64,
+}
+
+impl Calibration {
+    fn mean(runs: &[Run]) -> Self {
+        let n = runs.len().max(1) as f64;
+        Calibration {
+            wall_seconds: runs.iter().map(|r| r.wall.as_secs_f64()).sum::<f64>() / n,
+            iut_cpu_seconds: runs.iter().map(|r| r.iut_cpu_seconds).sum::<f64>() / n,
+            collector_cpu_seconds: runs.iter().map(|r| r.collector_cpu_seconds).sum::<f64>() / n,
+        }
+    }
+
+    /// Relative throughput change against `baseline` in percent. With the same
+    /// blob in every run, the throughput is inversely proportional to the duration.
+    fn throughput_delta(&self, baseline: &Calibration) -> Option<f64> {
+        if self.wall_seconds <= 0.0 {
+            return None;
+        }
+        relative_delta(1.0 / self.wall_seconds, 1.0 / baseline.wall_seconds)
+    }
+
+    /// Relative change of the benchmark's CPU time against `baseline` in percent.
+    fn cpu_delta(&self, baseline: &Calibration) -> Option<f64> {
+        relative_delta(self.iut_cpu_seconds, baseline.iut_cpu_seconds)
+    }
+}
+
+/// `(value - baseline) / baseline` in percent, if the baseline is usable.
+fn relative_delta(value: f64, baseline: f64) -> Option<f64> {
+    if !baseline.is_finite() || baseline <= 0.0 {
+        return None;
+    }
+    Some((value - baseline) / baseline * 100.0)
+}
+
+fn format_delta(delta: Option<f64>) -> String {
+    delta
+        .map(|d| format!("{:+.1}%", d))
+        .unwrap_or_else(|| String::from("n/a"))
+}
+
+/// Runs the benchmark with every [`ProbeSet`] in turn and reports how much
+/// throughput and CPU time each one costs compared to the detached baseline.
+pub async fn calibrate(args: &CalibrateArgs) -> Result<()> {
+    if args.runs == 0 {
+        bail!("Calibration needs at least one run");
+    }
+    let blob_bytes = args
+        .blob
+        .clone()
+        .map(Request::try_from)
+        .transpose()?
+        .map(|request| request.len());
+    let components = args
+        .components
+        .as_ref()
+        .map(|path| ComponentConfig::load(path))
+        .transpose()?;
+
+    let mut runs: Vec<Vec<Run>> = vec![Vec::new(); ProbeSet::ALL.len()];
+    // interleaves the probe sets so that drift affects all of them alike
+    for round in 0..args.runs {
+        for (set, runs) in ProbeSet::ALL.iter().zip(runs.iter_mut()) {
+            info!(
+                "Calibration run {}/{} with {} probes",
+                round + 1,
+                args.runs,
+                set
+            );
+            runs.push(run_once(args, *set, components.clone()).await?);
+        }
+    }
+
+    let calibrations: Vec<Calibration> = runs.iter().map(|r| Calibration::mean(r)).collect();
+    let baseline = &calibrations[0];
+    println!(
+        "calibration over {} runs of `{}`",
+        args.runs,
+        args.command.join(" ")
+    );
+    for (set, calibration) in ProbeSet::ALL.iter().zip(calibrations.iter()) {
+        let throughput = blob_bytes
+            .map(|bytes| {
+                format!(
+                    ", throughput={:.3}Mbit/s",
+                    bytes as f64 * 8.0 / 1e6 / calibration.wall_seconds
+                )
+            })
+            .unwrap_or_default();
+        println!(
+            "{}: wall={:.3}s{} ({}), iut cpu={:.3}s ({}), collector cpu={:.3}s",
+            set,
+            calibration.wall_seconds,
+            throughput,
+            format_delta(calibration.throughput_delta(baseline)),
+            calibration.iut_cpu_seconds,
+            format_delta(calibration.cpu_delta(baseline)),
+            calibration.collector_cpu_seconds,
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs the benchmark once. The process is held at its `execve` until the
+/// probes are attached, so that they see the whole run.
+async fn run_once(
+    args: &CalibrateArgs,
+    set: ProbeSet,
+    components: Option<ComponentConfig>,
+) -> Result<Run> {
+    let mut child = spawn_stopped(&args.command)?;
+    let pid = child.id();
+
+    let mut open_obj = MaybeUninit::uninit();
+    let resumed = attach(args, set, components, pid, &mut open_obj).and_then(|monitor| {
+        let collector_start = ProcStats::read(std::process::id())?;
+        let started = Instant::now();
+        resume(pid)?;
+        Ok((monitor, collector_start, started))
+    });
+    let (monitor, collector_start, started) = match resumed {
+        Ok(resumed) => resumed,
+        Err(e) => {
+            _ = child.kill();
+            _ = child.wait();
+            return Err(e);
+        }
+    };
+
+    let iut_cpu_seconds = tokio::task::spawn_blocking(move || wait(pid)).await??;
+    let wall = started.elapsed();
+    let collector = ProcStats::read(std::process::id())?.since(&collector_start);
+
+    if let Some(mut monitor) = monitor {
+        monitor.stop().await;
+    }
+
+    Ok(Run {
+        wall,
+        iut_cpu_seconds,
+        collector_cpu_seconds: collector.cpu_seconds(),
+    })
+}
+
+/// Attaches the probes of `set` to the process `pid` held at its `execve`. Its
+/// shared libraries are not mapped yet, so they are probed by path.
+fn attach<'obj>(
+    args: &CalibrateArgs,
+    set: ProbeSet,
+    components: Option<ComponentConfig>,
+    pid: u32,
+    open_obj: &'obj mut MaybeUninit<OpenObject>,
+) -> Result<Option<MetricsCollector<'obj>>> {
+    if set == ProbeSet::Detached {
+        return Ok(None);
+    }
+
+    let binary = fs::read_link(format!("/proc/{}/exe", pid))?;
+    let folded = std::env::temp_dir().join("nesquic-calibrate.folded");
+    let mut builder = MetricsCollector::builder()
+        .pid(pid)
+        .probes(set)
+        .objects(symbols::linked_objects(&binary)?)
+        .profile(args.profile, folded);
+    if let Some(config) = components {
+        builder = builder.components(config);
+    }
+    let mut monitor = builder.build(open_obj)?;
+    monitor.start()?;
+    Ok(Some(monitor))
+}
+
+/// Spawns `command` and waits until it stops right after its `execve`, when its
+/// binary is mapped but has not run yet.
+fn spawn_stopped(command: &[String]) -> Result<Child> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("No command to calibrate");
+    };
+    let mut command = Command::new(program);
+    command.args(args);
+    // SAFETY: ptrace is async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            if libc::ptrace(
+                libc::PTRACE_TRACEME,
+                0,
+                ptr::null_mut::<libc::c_void>(),
+                ptr::null_mut::<libc::c_void>(),
+            ) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    let child = command.spawn()?;
+
+    let mut status = 0;
+    // SAFETY: status is a valid c_int
+    if unsafe { libc::waitpid(child.id() as i32, &mut status, 0) } == -1 {
+        bail!("waitpid failed: {}", io::Error::last_os_error());
+    }
+    if !libc::WIFSTOPPED(status) {
+        bail!("{} exited before it started", program);
+    }
+    Ok(child)
+}
+
+/// Lets the process held by [`spawn_stopped`] run.
+fn resume(pid: u32) -> Result<()> {
+    // SAFETY: detaching takes no pointers
+    let detached = unsafe {
+        libc::ptrace(
+            libc::PTRACE_DETACH,
+            pid as i32,
+            ptr::null_mut::<libc::c_void>(),
+            ptr::null_mut::<libc::c_void>(),
+        )
+    };
+    if detached == -1 {
+        bail!("Cannot resume {}: {}", pid, io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reaps the process and returns the CPU seconds it used.
+fn wait(pid: u32) -> Result<f64> {
+    let mut status = 0;
+    // SAFETY: rusage is plain old data
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: status and usage are valid
+    if unsafe { libc::wait4(pid as i32, &mut status, 0, &mut usage) } == -1 {
+        bail!("wait4 failed: {}", io::Error::last_os_error());
+    }
+    if libc::WIFSIGNALED(status) {
+        bail!("Benchmark killed by signal {}", libc::WTERMSIG(status));
+    }
+    if libc::WEXITSTATUS(status) != 0 {
+        bail!("Benchmark exited with {}", libc::WEXITSTATUS(status));
+    }
+
+    let seconds = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1e6;
+    Ok(seconds(usage.ru_utime) + seconds(usage.ru_stime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(110.0, 100.0, Some(10.0))]
+    #[test_case(80.0, 100.0, Some(-20.0))]
+    #[test_case(1.0, 0.0, None)]
+    fn deltas(value: f64, baseline: f64, delta: Option<f64>) {
+        let actual = relative_delta(value, baseline);
+        assert_eq!(actual.map(|d| (d * 1e6).round()), delta.map(|d| d * 1e6));
+    }
+
+    #[test]
+    fn calibration() {
+        let run = |wall_ms, iut_cpu_seconds| Run {
+            wall: Duration::from_millis(wall_ms),
+            iut_cpu_seconds,
+            collector_cpu_seconds: 0.0,
+        };
+        let baseline = Calibration::mean(&[run(900, 0.5), run(1100, 0.7)]);
+        assert_eq!(baseline.wall_seconds, 1.0);
+        assert!((baseline.iut_cpu_seconds - 0.6).abs() < 1e-9);
+
+        let traced = Calibration::mean(&[run(1250, 0.9)]);
+        assert!((traced.throughput_delta(&baseline).unwrap() + 20.0).abs() < 1e-9);
+        assert!((traced.cpu_delta(&baseline).unwrap() - 50.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,10 @@
+//! The eBPF collector of nesquic, to embed into IUTs and other harnesses.
+//! [`MetricsCollector::builder`] sets up a collector for a process.
+
+pub mod metrics;
+
+pub use metrics::{
+    builder::{MetricsCollectorBuilder, ProbeSet},
+    sink::MetricsSink,
+    MetricsCollector, Snapshot,
+};
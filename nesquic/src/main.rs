@@ -1,12 +1,36 @@
-use anyhow::{anyhow, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, Result};
+use calibrate::CalibrateArgs;
+use clap::{Parser, Subcommand};
 use core_affinity::{self, CoreId};
-use metrics::MetricsCollector;
-use std::{collections::HashMap, env, mem::MaybeUninit};
-use tokio::signal::unix::{signal, SignalKind};
-use tracing::{error, info, trace};
+use nesquic::metrics::{
+    components::ComponentConfig,
+    influx::InfluxSink,
+    interval::{self, IntervalRecorder},
+    procfs::ProcCollector,
+    scrape,
+    sink::{self, MetricsSink, Point},
+    MetricsCollector,
+};
+use std::{
+    collections::HashMap,
+    future::{self, Future},
+    mem::MaybeUninit,
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+use tracing::{error, trace, warn};
+use utils::{
+    labels::{parse_label, user_labels},
+    metadata,
+    perf::Request,
+};
 
-mod metrics;
+mod calibrate;
 
 mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -15,38 +39,108 @@ mod built_info {
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(short, long)]
     pub job: Option<String>,
 
-    #[clap(short = 'L')]
-    pub labels: Option<Vec<String>>,
+    /// Run label `key:value`, may be repeated. Values may contain colons
+    #[clap(short = 'L', value_parser = parse_label)]
+    pub labels: Vec<(String, String)>,
+
+    /// File with one `key:value` label per line
+    #[clap(long, value_name = "FILE")]
+    pub labels_file: Option<PathBuf>,
 
     #[clap(long)]
     pub cpu: Option<usize>,
+
+    /// PID of the process to monitor, defaults to the collector itself
+    #[clap(short, long)]
+    pub pid: Option<u32>,
+
+    /// Stream every traced syscall, scheduling slice and uprobe hit through the
+    /// ring buffer, in addition to the in-kernel aggregation
+    #[clap(long)]
+    pub events: bool,
+
+    /// Write the streamed events as a Chrome trace JSON file, which Perfetto opens.
+    /// Implies `--events`
+    #[clap(long, value_name = "FILE")]
+    pub trace: Option<PathBuf>,
+
+    /// Spans the IUT wrote to `NESQUIC_SPANS`, merged into `--trace`
+    #[clap(long, value_name = "FILE", requires = "trace")]
+    pub spans: Option<PathBuf>,
+
+    /// YAML file that maps symbol patterns of the monitored process to components,
+    /// see `res/components.yaml`
+    #[clap(long)]
+    pub components: Option<PathBuf>,
+
+    /// Sample the user and kernel stacks of the monitored process at this
+    /// frequency (Hz). Defaults to 99 Hz, which does not run in lockstep with timers
+    #[clap(long, value_name = "HZ", num_args = 0..=1, default_missing_value = "99")]
+    pub profile: Option<u64>,
+
+    /// Where `--profile` writes the sampled stacks in the folded format
+    #[clap(long, default_value = "nesquic.folded")]
+    pub folded: PathBuf,
+
+    /// Size of the blob the benchmark transfers, e.g. `50MiB`, to report the
+    /// protocol overhead of the UDP bytes
+    #[clap(long)]
+    pub blob: Option<String>,
+
+    /// Classify the QUIC packets on this network interface by header type
+    #[clap(long)]
+    pub iface: Option<String>,
+
+    /// UDP port of the QUIC server whose packets `--iface` classifies
+    #[clap(long, default_value_t = 4433)]
+    pub port: u16,
+
+    /// Where to write the metrics: `influx`, `jsonl:PATH`, `csv:PATH`, `openmetrics:PATH`
+    /// or `pushgateway:URL`. Repeatable. Without any, the metrics are printed
+    #[clap(long = "sink", value_name = "SINK")]
+    pub sinks: Vec<String>,
+
+    /// Serve the live metrics in the OpenMetrics format at `http://ADDR/metrics`
+    #[clap(long, value_name = "ADDR")]
+    pub listen: Option<SocketAddr>,
+
+    /// Additionally record the syscalls, bytes and CPU time of every interval, e.g. `100ms`.
+    /// The intervals go to the sinks
+    #[clap(long, value_name = "DURATION", value_parser = interval::parse_interval)]
+    pub interval: Option<Duration>,
+
+    /// Where batches that InfluxDB did not accept are kept for `nesquic flush`
+    #[clap(long, global = true, default_value = "nesquic-spool")]
+    pub spool: PathBuf,
 }
 
-fn labels(cli: &Cli) -> HashMap<String, String> {
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Measure how much each probe set slows down an IUT benchmark
+    Calibrate(CalibrateArgs),
+    /// Write the batches spooled after failed InfluxDB writes
+    Flush,
+}
+
+fn labels(cli: &Cli) -> Result<HashMap<String, String>> {
     let log_level = tracing::level_filters::LevelFilter::current().to_string();
-    let run_labels = cli
-        .labels
-        .clone()
-        .unwrap_or(vec![])
-        .iter()
-        .map(|h| {
-            let hs: Vec<&str> = h.split_terminator(":").map(|s| s.trim()).collect();
-            (hs[0].to_string(), hs[1].to_string())
-        })
-        .collect::<HashMap<String, String>>();
+    let run_labels = user_labels(&cli.labels, cli.labels_file.as_deref())?;
     trace!("Run labels: {:?}", run_labels);
 
-    let mut labels = HashMap::new();
+    let mut labels = metadata::run_metadata(cli.iface.as_deref());
     labels.insert(String::from("log_level"), log_level);
     labels.insert(String::from("version"), built_info::PKG_VERSION.into());
     labels.extend(run_labels.into_iter());
 
     trace!("Final labels: {:?}", labels);
 
-    labels
+    Ok(labels)
 }
 
 fn get_core_id(idx: usize) -> Result<CoreId> {
@@ -60,6 +154,39 @@ fn get_core_id(idx: usize) -> Result<CoreId> {
     Ok(cores[idx])
 }
 
+/// The sinks given with `--sink`. Without any, a job goes to InfluxDB if it is configured.
+fn parse_sinks(cli: &Cli) -> Result<Vec<Box<dyn MetricsSink>>> {
+    sink::parse_sinks(&cli.sinks, cli.job.is_some(), &cli.spool)
+}
+
+/// Waits for SIGINT or SIGTERM. Meanwhile, `listener` serves the live `points`
+/// and `intervals` records the interval deltas.
+async fn wait_for_termination(
+    listener: Option<&TcpListener>,
+    points: impl Fn() -> Result<Vec<Point>>,
+    intervals: Option<impl Future<Output = ()>>,
+) {
+    let mut sigterm = signal(SignalKind::terminate()).expect("sigterm");
+    let scrapes = async {
+        match listener {
+            Some(listener) => scrape::serve(listener, points).await,
+            None => future::pending().await,
+        }
+    };
+    let intervals = async {
+        match intervals {
+            Some(intervals) => intervals.await,
+            None => future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = sigterm.recv() => (),
+        _ = scrapes => (),
+        _ = intervals => (),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
@@ -68,7 +195,11 @@ async fn main() -> Result<()> {
         .init();
 
     let cli = Cli::parse();
-    let labels = labels(&cli);
+    let mut labels = labels(&cli)?;
+    let sinks = parse_sinks(&cli)?;
+    if cli.interval.is_some() && sinks.is_empty() {
+        warn!("Intervals are only written to sinks, see --sink");
+    }
     let job = cli.job.clone();
     let core = cli.cpu.map(|idx| get_core_id(idx)).transpose()?;
 
@@ -77,33 +208,99 @@ async fn main() -> Result<()> {
         core_affinity::set_for_current(core);
     }
 
-    let mut open_obj = MaybeUninit::uninit();
-    let mut monitor = MetricsCollector::new(&mut open_obj).expect("metrics collector");
-    monitor.monitor_io().expect("monitor IO");
+    match &cli.command {
+        Some(Command::Calibrate(args)) => return calibrate::calibrate(args).await,
+        Some(Command::Flush) => {
+            let Some(influx) = InfluxSink::from_env(cli.spool.clone()) else {
+                bail!("Flushing needs INFLUX_URL, INFLUX_TOKEN, INFLUX_ORG and INFLUX_BUCKET");
+            };
+            return influx.flush().await;
+        }
+        None => {}
+    }
 
-    let mut sigterm = signal(SignalKind::terminate()).expect("sigterm");
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => (),
-        _ = sigterm.recv() => (),
+    let listener = match cli.listen {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+
+    let pid = cli.pid.unwrap_or_else(std::process::id);
+    let mut builder = MetricsCollector::builder()
+        .pid(pid)
+        .stream_events(cli.events)
+        .job(job.clone())
+        .labels(labels.clone());
+    if let Some(path) = &cli.trace {
+        builder = builder.trace(path.clone(), cli.spans.clone());
+    }
+    if let Some(blob) = &cli.blob {
+        builder = builder.goodput_bytes(Request::try_from(blob.clone())?.len() as u64);
+    }
+    if let Some(path) = &cli.components {
+        builder = builder.components(ComponentConfig::load(path)?);
+    }
+    if let Some(iface) = &cli.iface {
+        builder = builder.packets(iface.clone(), cli.port);
     }
+    if let Some(hz) = cli.profile {
+        builder = builder.profile(hz, cli.folded.clone());
+    }
+
+    let mut open_obj = MaybeUninit::uninit();
+    let mut monitor = match builder.sinks(sinks).build(&mut open_obj) {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            warn!(
+                "Cannot load the eBPF programs, falling back to /proc: {:#}",
+                e
+            );
+            let monitor = ProcCollector::new(pid)?;
+            labels.extend(monitor.metadata());
+            let mut recorder = cli.interval.map(IntervalRecorder::new);
+            wait_for_termination(
+                listener.as_ref(),
+                || monitor.points(job.clone(), labels.clone()),
+                recorder.as_mut().map(|recorder| {
+                    recorder.record(|| monitor.interval_stats(), job.clone(), labels.clone())
+                }),
+            )
+            .await;
+            let intervals = recorder.map(IntervalRecorder::into_points);
 
-    if let Some(job) = job {
-        if let (Ok(url), Ok(token), Ok(org), Ok(bucket)) = (
-            env::var("INFLUX_URL"),
-            env::var("INFLUX_TOKEN"),
-            env::var("INFLUX_ORG"),
-            env::var("INFLUX_BUCKET"),
-        ) {
-            info!("Pushing metrics to InfluxDB at {}", url);
-
-            if let Err(e) = monitor.push_all(url, token, org, bucket, job, labels).await {
-                error!("Error pushing metrics to InfluxDB: {}", e);
+            // the builder took the sinks
+            let sinks = parse_sinks(&cli)?;
+            if sinks.is_empty() {
+                if let Err(e) = monitor.report() {
+                    error!("Error reporting metrics: {}", e);
+                }
+            } else {
+                match monitor.points(job, labels) {
+                    Ok(mut points) => {
+                        points.extend(intervals.unwrap_or_default());
+                        sink::write_all(&sinks, &points).await
+                    }
+                    Err(e) => error!("Error collecting metrics: {}", e),
+                }
             }
+            return Ok(());
         }
-    } else {
-        if let Err(e) = monitor.report() {
-            error!("Error reporting metrics: {}", e);
-        }
+    };
+    monitor.start()?;
+
+    let tags = monitor.tags();
+    let mut recorder = cli.interval.map(IntervalRecorder::new);
+    wait_for_termination(
+        listener.as_ref(),
+        || monitor.live_points(job.clone(), tags.clone()),
+        recorder.as_mut().map(|recorder| {
+            recorder.record(|| monitor.interval_stats(), job.clone(), tags.clone())
+        }),
+    )
+    .await;
+    let intervals = recorder.map(IntervalRecorder::into_points);
+
+    if let Err(e) = monitor.finish(intervals.unwrap_or_default()).await {
+        error!("Error collecting metrics: {}", e);
     }
 
     Ok(())
@@ -0,0 +1,152 @@
+use super::{components::ComponentConfig, sink::MetricsSink, MetricsCollector};
+use anyhow::Result;
+use std::{collections::HashMap, fmt, mem::MaybeUninit, path::PathBuf};
+
+/// The probes [`MetricsCollector::start`] attaches to the monitored process.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProbeSet {
+    /// No eBPF programs attached, the baseline
+    Detached,
+    /// Syscall and event loop tracing
+    Syscalls,
+    /// Everything, including uprobes, perf counters and the configured components,
+    /// packets and profile
+    #[default]
+    All,
+}
+
+impl ProbeSet {
+    pub const ALL: [ProbeSet; 3] = [ProbeSet::Detached, ProbeSet::Syscalls, ProbeSet::All];
+}
+
+impl fmt::Display for ProbeSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeSet::Detached => write!(f, "detached"),
+            ProbeSet::Syscalls => write!(f, "syscalls"),
+            ProbeSet::All => write!(f, "all"),
+        }
+    }
+}
+
+/// What [`MetricsCollector::start`] attaches, as configured on the builder.
+#[derive(Default)]
+pub(super) struct Probes {
+    pub(super) set: ProbeSet,
+    pub(super) trace: Option<(PathBuf, Option<PathBuf>)>,
+    pub(super) components: Option<ComponentConfig>,
+    pub(super) profile: Option<(u64, PathBuf)>,
+    pub(super) packets: Option<(String, u16)>,
+    pub(super) goodput_bytes: Option<u64>,
+}
+
+/// Sets up a [`MetricsCollector`], see [`MetricsCollector::builder`].
+#[derive(Default)]
+pub struct MetricsCollectorBuilder {
+    pid: Option<u32>,
+    collector_tid: Option<u32>,
+    stream_events: bool,
+    probes: Probes,
+    objects: Vec<PathBuf>,
+    sinks: Vec<Box<dyn MetricsSink>>,
+    job: Option<String>,
+    labels: HashMap<String, String>,
+}
+
+impl MetricsCollectorBuilder {
+    /// The process to monitor, by default the calling process itself
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// The thread the collector runs on, if it is part of the monitored process.
+    /// Its syscalls, scheduling and probe hits are left out of the metrics.
+    pub fn collector_tid(mut self, tid: u32) -> Self {
+        self.collector_tid = Some(tid);
+        self
+    }
+
+    pub fn probes(mut self, set: ProbeSet) -> Self {
+        self.probes.set = set;
+        self
+    }
+
+    /// Stream every traced syscall, scheduling slice and uprobe hit through the
+    /// ring buffer, in addition to the in-kernel aggregation
+    pub fn stream_events(mut self, stream_events: bool) -> Self {
+        self.stream_events = stream_events;
+        self
+    }
+
+    /// Write the streamed events and the IUT's `spans` as a Chrome trace to `path`
+    /// when the collector stops. Implies [`Self::stream_events`].
+    pub fn trace(mut self, path: PathBuf, spans: Option<PathBuf>) -> Self {
+        self.stream_events = true;
+        self.probes.trace = Some((path, spans));
+        self
+    }
+
+    pub fn components(mut self, config: ComponentConfig) -> Self {
+        self.probes.components = Some(config);
+        self
+    }
+
+    /// Sample the stacks at `hz` and write them to `folded` when the collector stops
+    pub fn profile(mut self, hz: u64, folded: PathBuf) -> Self {
+        self.probes.profile = Some((hz, folded));
+        self
+    }
+
+    /// Classify the QUIC packets to and from `port` on `iface`
+    pub fn packets(mut self, iface: String, port: u16) -> Self {
+        self.probes.packets = Some((iface, port));
+        self
+    }
+
+    /// Report the protocol overhead of the UDP bytes against the `goodput_bytes` of the run
+    pub fn goodput_bytes(mut self, goodput_bytes: u64) -> Self {
+        self.probes.goodput_bytes = Some(goodput_bytes);
+        self
+    }
+
+    /// Shared objects to probe even if the process has not mapped them yet, e.g. the
+    /// libraries of a process held at its `execve`, see [`super::symbols::linked_objects`]
+    pub fn objects(mut self, objects: Vec<PathBuf>) -> Self {
+        self.objects.extend(objects);
+        self
+    }
+
+    /// Where [`MetricsCollector::finish`] writes the metrics. Without any, they are printed.
+    pub fn sinks(mut self, sinks: Vec<Box<dyn MetricsSink>>) -> Self {
+        self.sinks.extend(sinks);
+        self
+    }
+
+    pub fn job(mut self, job: Option<String>) -> Self {
+        self.job = job;
+        self
+    }
+
+    /// Tags of every point, on top of the collector's own metadata
+    pub fn labels(mut self, labels: HashMap<String, String>) -> Self {
+        self.labels.extend(labels);
+        self
+    }
+
+    /// Loads the eBPF programs into `open_obj`, without attaching them yet.
+    pub fn build(
+        self,
+        open_obj: &mut MaybeUninit<libbpf_rs::OpenObject>,
+    ) -> Result<MetricsCollector<'_>> {
+        let pid = self.pid.unwrap_or_else(std::process::id);
+        let mut collector =
+            MetricsCollector::new(open_obj, pid, self.collector_tid, self.stream_events)?;
+        collector.pending = Some(self.probes);
+        collector.objects = self.objects;
+        collector.sinks = self.sinks;
+        collector.job = self.job;
+        collector.labels = self.labels;
+        Ok(collector)
+    }
+}
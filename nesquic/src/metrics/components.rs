@@ -0,0 +1,164 @@
+use super::{escape_tag, hist::Log2Histogram, types};
+use anyhow::{bail, Context, Result};
+use libbpf_rs::{MapCore, MapFlags};
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+unsafe impl plain::Plain for types::component_stats {}
+
+/// Must match `max_entries` of the `components` map in `metrics.bpf.c`.
+const MAX_COMPONENTS: usize = 256;
+
+fn default_max_probes() -> usize {
+    512
+}
+
+/// Maps symbol patterns of the monitored process to components, e.g.
+///
+/// ```yaml
+/// components:
+///   - name: congestion_control
+///     symbols:
+///       - "*quinn_proto::congestion::*"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct ComponentConfig {
+    /// Upper bound of functions to attach to, as every probe slows down the IUT
+    #[serde(default = "default_max_probes")]
+    pub max_probes: usize,
+    pub components: Vec<Component>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Component {
+    /// Label of the component, e.g. `congestion_control`
+    pub name: String,
+    /// Symbol patterns, see [`super::symbols::wildcard_match`]
+    pub symbols: Vec<String>,
+}
+
+impl ComponentConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let config = fs::read_to_string(path)
+            .with_context(|| format!("read component config {}", path.display()))?;
+        Self::parse(&config).with_context(|| format!("parse component config {}", path.display()))
+    }
+
+    pub fn parse(config: &str) -> Result<Self> {
+        let config: ComponentConfig = serde_yaml::from_str(config)?;
+        if config.components.len() > MAX_COMPONENTS {
+            bail!(
+                "At most {} components are supported, got {}",
+                MAX_COMPONENTS,
+                config.components.len()
+            );
+        }
+        Ok(config)
+    }
+
+    /// The first component that claims the symbol `name`, if any.
+    pub fn component_of(&self, name: &str, mangled: &str) -> Option<usize> {
+        self.components.iter().position(|c| {
+            c.symbols.iter().any(|pattern| {
+                super::symbols::wildcard_match(pattern, name)
+                    || super::symbols::wildcard_match(pattern, mangled)
+            })
+        })
+    }
+}
+
+/// Time spent in a component. Calls within the same component are
+/// attributed to the outermost one.
+#[derive(Clone, Debug, Default)]
+pub struct ComponentStats {
+    pub calls: u64,
+    pub time_ns: u64,
+    /// log2 histogram of the call latency in nanoseconds
+    pub latency: Log2Histogram,
+}
+
+impl ComponentStats {
+    pub fn line_protocol(&self, component: &str, tag_str: &str, timestamp_ns: u128) -> String {
+        format!(
+            "nesquic_component{},component={} calls={}i,time_ns={}i,latency_p50_ns={}i,latency_p99_ns={}i {}",
+            tag_str,
+            escape_tag(component),
+            self.calls,
+            self.time_ns,
+            self.latency.percentile(0.5),
+            self.latency.percentile(0.99),
+            timestamp_ns
+        )
+    }
+}
+
+/// Read the per-CPU component statistics, labelled with the component names of `config`.
+pub fn read_components(
+    map: &impl MapCore,
+    config: &ComponentConfig,
+) -> Result<Vec<(String, ComponentStats)>> {
+    let mut components = Vec::new();
+    for key in map.keys() {
+        let Some(per_cpu) = map.lookup_percpu(&key, MapFlags::ANY)? else {
+            continue;
+        };
+
+        let id = u32::from_ne_bytes(key[..4].try_into()?);
+        let Some(component) = config.components.get(id as usize) else {
+            bail!("Unknown component {}", id);
+        };
+
+        let mut stats = ComponentStats::default();
+        for value in per_cpu {
+            let mut raw = types::component_stats::default();
+            if plain::copy_from_bytes(&mut raw, &value).is_err() {
+                bail!("Malformed component_stats value for {}", component.name);
+            }
+            stats.calls += raw.calls;
+            stats.time_ns += raw.time_ns;
+            stats
+                .latency
+                .merge(&Log2Histogram::from_slots(raw.latency_hist));
+        }
+        components.push((component.name.clone(), stats));
+    }
+
+    components.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = ComponentConfig::parse(
+            r#"
+components:
+  - name: congestion_control
+    symbols:
+      - "*quinn_proto::congestion::*"
+  - name: connection
+    symbols:
+      - "quinn_proto::connection::*"
+"#,
+        )
+        .expect("parse");
+
+        assert_eq!(config.max_probes, 512);
+        assert_eq!(
+            config.component_of(
+                "<quinn_proto::congestion::cubic::Cubic as quinn_proto::congestion::Controller>::on_ack",
+                ""
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            config.component_of("quinn_proto::connection::Connection::poll", ""),
+            Some(1)
+        );
+        assert_eq!(config.component_of("quiche::Connection::send", ""), None);
+        assert!(ComponentConfig::parse("components: 3").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use super::{escape_tag, types};
+use anyhow::{bail, Result};
+use libbpf_rs::{MapCore, MapFlags};
+use std::{collections::BTreeMap, fmt};
+
+unsafe impl plain::Plain for types::crypto_stats {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CryptoOp {
+    /// AEAD encryption of a packet payload
+    Seal,
+    /// AEAD decryption of a packet payload
+    Open,
+    /// Computing the header protection mask
+    HeaderProtection,
+}
+
+impl fmt::Display for CryptoOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoOp::Seal => write!(f, "seal"),
+            CryptoOp::Open => write!(f, "open"),
+            CryptoOp::HeaderProtection => write!(f, "header_protection"),
+        }
+    }
+}
+
+/// Where a probe takes the number of processed bytes from.
+#[derive(Clone, Copy, Debug)]
+pub enum ByteCount {
+    /// The n-th (1-based) argument of the function
+    Arg(u8),
+    /// Every call processes the same number of bytes
+    Fixed(u16),
+}
+
+/// A crypto function to attach a uprobe/uretprobe pair to.
+#[derive(Clone, Copy, Debug)]
+pub struct CryptoProbe {
+    /// Crypto library that implements the function
+    pub backend: &'static str,
+    pub op: CryptoOp,
+    /// Symbol pattern, see [`super::symbols::wildcard_match`]
+    pub symbol: &'static str,
+    pub bytes: ByteCount,
+}
+
+impl CryptoProbe {
+    /// The attach cookie understood by `crypto_enter`/`crypto_exit`.
+    pub fn cookie(&self, id: usize) -> u64 {
+        let (arg, fixed) = match self.bytes {
+            ByteCount::Arg(n) => (n as u64, 0),
+            ByteCount::Fixed(n) => (0, n as u64),
+        };
+        (id as u64 & 0xFFFF) | (arg << 16) | (fixed << 32)
+    }
+}
+
+/// The AEAD and header protection entry points of the crypto libraries used by the IUTs.
+/// Rust functions may be inlined in release builds, so we prefer the C/assembly
+/// functions underneath wherever their arguments tell us the number of bytes.
+pub const CRYPTO_PROBES: &[CryptoProbe] = &[
+    // ring (quinn, noq), whose C symbols carry a version prefix
+    CryptoProbe {
+        backend: "ring",
+        op: CryptoOp::Seal,
+        symbol: "ring_core_*_aesni_gcm_encrypt",
+        bytes: ByteCount::Arg(3),
+    },
+    CryptoProbe {
+        backend: "ring",
+        op: CryptoOp::Open,
+        symbol: "ring_core_*_aesni_gcm_decrypt",
+        bytes: ByteCount::Arg(3),
+    },
+    CryptoProbe {
+        backend: "ring",
+        op: CryptoOp::Seal,
+        symbol: "ring_core_*_chacha20_poly1305_seal",
+        bytes: ByteCount::Arg(3),
+    },
+    CryptoProbe {
+        backend: "ring",
+        op: CryptoOp::Open,
+        symbol: "ring_core_*_chacha20_poly1305_open",
+        bytes: ByteCount::Arg(3),
+    },
+    CryptoProbe {
+        backend: "ring",
+        op: CryptoOp::HeaderProtection,
+        symbol: "ring::aead::quic::HeaderProtectionKey::new_mask",
+        bytes: ByteCount::Fixed(16),
+    },
+    // BoringSSL (quiche)
+    CryptoProbe {
+        backend: "boringssl",
+        op: CryptoOp::Seal,
+        symbol: "EVP_AEAD_CTX_seal_scatter",
+        bytes: ByteCount::Arg(9),
+    },
+    CryptoProbe {
+        backend: "boringssl",
+        op: CryptoOp::Open,
+        symbol: "EVP_AEAD_CTX_open",
+        bytes: ByteCount::Arg(8),
+    },
+    CryptoProbe {
+        backend: "boringssl",
+        op: CryptoOp::HeaderProtection,
+        symbol: "AES_ecb_encrypt",
+        bytes: ByteCount::Fixed(16),
+    },
+    CryptoProbe {
+        backend: "boringssl",
+        op: CryptoOp::HeaderProtection,
+        symbol: "CRYPTO_chacha_20",
+        bytes: ByteCount::Arg(3),
+    },
+    // NSS (neqo), exposed through the experimental API
+    CryptoProbe {
+        backend: "nss",
+        op: CryptoOp::Seal,
+        symbol: "SSLExp_AeadEncrypt",
+        bytes: ByteCount::Arg(6),
+    },
+    CryptoProbe {
+        backend: "nss",
+        op: CryptoOp::Open,
+        symbol: "SSLExp_AeadDecrypt",
+        bytes: ByteCount::Arg(6),
+    },
+    CryptoProbe {
+        backend: "nss",
+        op: CryptoOp::HeaderProtection,
+        symbol: "SSLExp_CreateMask",
+        bytes: ByteCount::Arg(3),
+    },
+];
+
+/// Crypto cost of one operation of one backend, summed over all its probes.
+#[derive(Clone, Debug, Default)]
+pub struct CryptoStats {
+    pub calls: u64,
+    pub bytes: u64,
+    pub time_ns: u64,
+}
+
+impl CryptoStats {
+    pub fn line_protocol(
+        &self,
+        backend: &str,
+        op: CryptoOp,
+        tag_str: &str,
+        timestamp_ns: u128,
+    ) -> String {
+        format!(
+            "nesquic_crypto{},backend={},op={} calls={}i,bytes={}i,time_ns={}i {}",
+            tag_str,
+            escape_tag(backend),
+            op,
+            self.calls,
+            self.bytes,
+            self.time_ns,
+            timestamp_ns
+        )
+    }
+}
+
+/// Read the per-CPU crypto statistics, keyed by backend and operation.
+pub fn read_crypto(map: &impl MapCore) -> Result<BTreeMap<(&'static str, CryptoOp), CryptoStats>> {
+    let mut crypto: BTreeMap<(&'static str, CryptoOp), CryptoStats> = BTreeMap::new();
+    for key in map.keys() {
+        let Some(per_cpu) = map.lookup_percpu(&key, MapFlags::ANY)? else {
+            continue;
+        };
+
+        let id = u32::from_ne_bytes(key[..4].try_into()?);
+        let Some(probe) = CRYPTO_PROBES.get(id as usize) else {
+            bail!("Unknown crypto probe {}", id);
+        };
+
+        let stats = crypto.entry((probe.backend, probe.op)).or_default();
+        for value in per_cpu {
+            let mut raw = types::crypto_stats::default();
+            if plain::copy_from_bytes(&mut raw, &value).is_err() {
+                bail!("Malformed crypto_stats value for probe {}", id);
+            }
+            stats.calls += raw.calls;
+            stats.bytes += raw.bytes;
+            stats.time_ns += raw.time_ns;
+        }
+    }
+
+    Ok(crypto)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies() {
+        let probe = CryptoProbe {
+            backend: "boringssl",
+            op: CryptoOp::Seal,
+            symbol: "EVP_AEAD_CTX_seal_scatter",
+            bytes: ByteCount::Arg(9),
+        };
+        assert_eq!(probe.cookie(5), 5 | (9 << 16));
+
+        let probe = CryptoProbe {
+            bytes: ByteCount::Fixed(16),
+            ..probe
+        };
+        assert_eq!(probe.cookie(4), 4 | (16 << 32));
+    }
+}
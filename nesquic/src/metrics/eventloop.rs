@@ -0,0 +1,124 @@
+use super::{SyscallStats, FIRST_WAIT_SYSCALL, SYSCALLS};
+use std::{collections::HashMap, time::Duration};
+
+/// Syscalls that do not wait, but arm the timers of an event loop
+const TIMER_SYSCALLS: &[&str] = &["timerfd_settime"];
+
+/// Syscalls that return the number of ready events, so that 0 means a wakeup without work
+const POLL_SYSCALLS: &[&str] = &["epoll_wait", "epoll_pwait", "epoll_pwait2", "poll", "ppoll"];
+
+/// How often the event loop of the monitored process wakes up and how much work it finds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventLoopStats {
+    /// Returns from epoll, poll, io_uring, futex waits and sleeps
+    pub wakeups: u64,
+    pub wakeups_per_second: f64,
+    /// Polls that returned without any ready event
+    pub empty_wakeups: u64,
+    /// Ready events per poll
+    pub events_per_wakeup: f64,
+    /// Traced I/O syscalls per wakeup
+    pub io_batch: f64,
+    /// Timers armed with `timerfd_settime`
+    pub timer_arms: u64,
+}
+
+impl EventLoopStats {
+    /// Derive the event loop statistics from the syscalls traced during `duration`.
+    /// `None` if the process never waited.
+    pub fn from_syscalls(
+        syscalls: &HashMap<&'static str, SyscallStats>,
+        duration: Duration,
+    ) -> Option<Self> {
+        let mut stats = EventLoopStats::default();
+        let mut io_calls = 0;
+        let mut polls = 0;
+        let mut events = 0;
+
+        for (idx, name) in SYSCALLS.iter().enumerate() {
+            let Some(syscall) = syscalls.get(name) else {
+                continue;
+            };
+
+            if idx < FIRST_WAIT_SYSCALL {
+                io_calls += syscall.count;
+            } else if TIMER_SYSCALLS.contains(name) {
+                stats.timer_arms += syscall.count;
+            } else {
+                stats.wakeups += syscall.count;
+                if POLL_SYSCALLS.contains(name) {
+                    polls += syscall.count;
+                    events += syscall.events;
+                    stats.empty_wakeups += syscall.empty;
+                }
+            }
+        }
+
+        if stats.wakeups == 0 {
+            return None;
+        }
+
+        stats.io_batch = io_calls as f64 / stats.wakeups as f64;
+        if polls > 0 {
+            stats.events_per_wakeup = events as f64 / polls as f64;
+        }
+        if !duration.is_zero() {
+            stats.wakeups_per_second = stats.wakeups as f64 / duration.as_secs_f64();
+        }
+
+        Some(stats)
+    }
+
+    pub fn line_protocol(&self, tag_str: &str, timestamp_ns: u128) -> String {
+        format!(
+            "nesquic_eventloop{} wakeups={}i,wakeups_per_second={},empty_wakeups={}i,events_per_wakeup={},io_batch={},timer_arms={}i {}",
+            tag_str,
+            self.wakeups,
+            self.wakeups_per_second,
+            self.empty_wakeups,
+            self.events_per_wakeup,
+            self.io_batch,
+            self.timer_arms,
+            timestamp_ns
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calls(count: u64, empty: u64, events: u64) -> SyscallStats {
+        SyscallStats {
+            count,
+            empty,
+            events,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn wakeups() {
+        let mut syscalls = HashMap::new();
+        syscalls.insert("sendmsg", calls(30, 0, 0));
+        syscalls.insert("recvmsg", calls(50, 10, 0));
+        syscalls.insert("epoll_wait", calls(16, 4, 24));
+        syscalls.insert("futex", calls(4, 4, 0));
+        syscalls.insert("timerfd_settime", calls(7, 7, 0));
+
+        let stats = EventLoopStats::from_syscalls(&syscalls, Duration::from_secs(2)).unwrap();
+        assert_eq!(stats.wakeups, 20);
+        assert_eq!(stats.wakeups_per_second, 10.0);
+        assert_eq!(stats.empty_wakeups, 4);
+        assert_eq!(stats.events_per_wakeup, 1.5);
+        assert_eq!(stats.io_batch, 4.0);
+        assert_eq!(stats.timer_arms, 7);
+
+        syscalls.remove("epoll_wait");
+        syscalls.remove("futex");
+        assert_eq!(
+            EventLoopStats::from_syscalls(&syscalls, Duration::from_secs(2)),
+            None
+        );
+    }
+}
@@ -0,0 +1,123 @@
+/// Number of slots in the histograms aggregated by the eBPF programs.
+/// Must match `HIST_SLOTS` in `metrics.bpf.c`.
+pub const HIST_SLOTS: usize = 32;
+
+/// A log2 histogram as aggregated in-kernel. Slot 0 counts zeros, slot
+/// `i > 0` counts values in `[2^(i-1), 2^i)`. The last slot also holds
+/// everything above its range.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Log2Histogram {
+    slots: [u64; HIST_SLOTS],
+}
+
+impl Default for Log2Histogram {
+    fn default() -> Self {
+        Log2Histogram {
+            slots: [0; HIST_SLOTS],
+        }
+    }
+}
+
+impl Log2Histogram {
+    pub fn from_slots(slots: [u64; HIST_SLOTS]) -> Self {
+        Log2Histogram { slots }
+    }
+
+    /// The slot that counts `value`, like `hist_slot` in `metrics.bpf.c`.
+    pub fn slot(value: u64) -> usize {
+        if value == 0 {
+            return 0;
+        }
+        (value.ilog2() as usize + 1).min(HIST_SLOTS - 1)
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.slots[Self::slot(value)] += 1;
+    }
+
+    /// Adds the counts of `other` to this histogram, e.g. to merge per-CPU values.
+    pub fn merge(&mut self, other: &Log2Histogram) {
+        for (a, b) in self.slots.iter_mut().zip(other.slots.iter()) {
+            *a += b;
+        }
+    }
+
+    /// Total number of recorded values
+    pub fn count(&self) -> u64 {
+        self.slots.iter().sum()
+    }
+
+    /// Exclusive upper bound of the values counted in `slot`
+    pub fn upper_bound(slot: usize) -> u64 {
+        match slot {
+            0 => 0,
+            s if s >= 64 => u64::MAX,
+            s => 1 << s,
+        }
+    }
+
+    /// Approximates the `p`-th percentile (0.0..=1.0) with the upper bound
+    /// of the slot that contains it. Returns 0 for an empty histogram.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let total = self.count();
+        if total == 0 {
+            return 0;
+        }
+
+        let rank = ((total as f64) * p.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (slot, count) in self.slots.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::upper_bound(slot);
+            }
+        }
+
+        Self::upper_bound(HIST_SLOTS - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut slots = [0; HIST_SLOTS];
+        slots[0] = 1;
+        slots[3] = 8;
+        slots[10] = 1;
+        let hist = Log2Histogram::from_slots(slots);
+
+        assert_eq!(hist.count(), 10);
+        assert_eq!(hist.percentile(0.0), 0);
+        assert_eq!(hist.percentile(0.5), 8);
+        assert_eq!(hist.percentile(0.9), 8);
+        assert_eq!(hist.percentile(1.0), 1024);
+        assert_eq!(Log2Histogram::default().percentile(0.5), 0);
+    }
+
+    #[test]
+    fn slots_match_bpf() {
+        assert_eq!(Log2Histogram::slot(0), 0);
+        assert_eq!(Log2Histogram::slot(1), 1);
+        assert_eq!(Log2Histogram::slot(7), 3);
+        assert_eq!(Log2Histogram::slot(8), 4);
+        assert_eq!(Log2Histogram::slot(u64::MAX), HIST_SLOTS - 1);
+
+        let mut hist = Log2Histogram::default();
+        hist.record(1000);
+        assert_eq!(hist.percentile(0.5), 1024);
+    }
+
+    #[test]
+    fn merge_per_cpu_values() {
+        let mut slots = [0; HIST_SLOTS];
+        slots[5] = 2;
+        let mut a = Log2Histogram::from_slots(slots);
+        a.merge(&Log2Histogram::from_slots(slots));
+
+        assert_eq!(a.slots[5], 4);
+        assert_eq!(a.count(), 4);
+    }
+}
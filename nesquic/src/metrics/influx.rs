@@ -0,0 +1,350 @@
+use super::sink::{MetricsSink, Point};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use flate2::{write::GzEncoder, Compression};
+use reqwest::Client;
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, info, warn};
+
+/// Lines per write request, as recommended by InfluxDB
+const BATCH_LINES: usize = 5000;
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Subdirectory of the spool for batches InfluxDB refused, which `nesquic flush` skips
+const REJECTED: &str = "rejected";
+
+/// Writes line protocol to the InfluxDB v2 API in gzipped batches. Batches that
+/// still fail after retrying are kept in a spool directory for `nesquic flush`,
+/// batches InfluxDB refused, e.g. as malformed, in its `rejected` subdirectory.
+pub struct InfluxSink {
+    url: String,
+    token: String,
+    org: String,
+    bucket: String,
+    spool: PathBuf,
+    client: Client,
+}
+
+/// Why a write failed, and whether trying again may help.
+struct WriteError {
+    error: anyhow::Error,
+    transient: bool,
+}
+
+impl InfluxSink {
+    /// Reads `INFLUX_URL`, `INFLUX_TOKEN`, `INFLUX_ORG` and `INFLUX_BUCKET`, if all of them are set.
+    /// Failed batches go to `spool`.
+    pub fn from_env(spool: PathBuf) -> Option<Self> {
+        let url = env::var("INFLUX_URL").ok()?;
+        let token = env::var("INFLUX_TOKEN").ok()?;
+        let org = env::var("INFLUX_ORG").ok()?;
+        let bucket = env::var("INFLUX_BUCKET").ok()?;
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .inspect_err(|e| error!("Cannot create HTTP client: {}", e))
+            .ok()?;
+        Some(InfluxSink {
+            url,
+            token,
+            org,
+            bucket,
+            spool,
+            client,
+        })
+    }
+
+    /// Sends one batch, retrying transient failures with exponential backoff.
+    async fn send(&self, body: &str) -> Result<(), WriteError> {
+        let gzipped = gzip(body).map_err(|error| WriteError {
+            error,
+            transient: false,
+        })?;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.post(gzipped.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.transient && attempt < MAX_ATTEMPTS => {
+                    let delay = backoff(attempt);
+                    warn!(
+                        "InfluxDB write failed (attempt {}/{}), retrying in {:?}: {:#}",
+                        attempt, MAX_ATTEMPTS, delay, e.error
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn post(&self, gzipped: Vec<u8>) -> Result<(), WriteError> {
+        let write_url = format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=ns",
+            self.url, self.org, self.bucket
+        );
+        let resp = self
+            .client
+            .post(&write_url)
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .header("Content-Encoding", "gzip")
+            .body(gzipped)
+            .send()
+            .await
+            .map_err(|e| WriteError {
+                error: anyhow!("HTTP request failed: {} (kind: {:?})", e, e.url()),
+                transient: true,
+            })?;
+
+        let status = resp.status();
+        debug!("InfluxDB responded with HTTP {}", status);
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(WriteError {
+                error: anyhow!("InfluxDB write failed {}: {}", status, body),
+                transient: is_transient(status.as_u16()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Replays the spooled batches in the order they failed. Stops at the first
+    /// batch that still cannot be written, so that the rest stays spooled. Batches
+    /// InfluxDB refuses are moved to the `rejected` subdirectory instead.
+    pub async fn flush(&self) -> Result<()> {
+        let batches = spooled(&self.spool)?;
+        if batches.is_empty() {
+            info!("No spooled batches in {}", self.spool.display());
+            return Ok(());
+        }
+
+        let (mut flushed, mut rejected) = (0, 0);
+        for path in batches.iter() {
+            let body =
+                fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+            match self.send(&body).await {
+                Ok(()) => {
+                    fs::remove_file(path)?;
+                    info!("Flushed {}", path.display());
+                    flushed += 1;
+                }
+                Err(e) if e.transient => bail!(
+                    "Flushed {} of {} batches, {} stays spooled: {:#}",
+                    flushed,
+                    batches.len(),
+                    path.display(),
+                    e.error
+                ),
+                Err(e) => {
+                    let moved = reject(&self.spool, path)?;
+                    error!(
+                        "InfluxDB rejected {}, moved to {}: {:#}",
+                        path.display(),
+                        moved.display(),
+                        e.error
+                    );
+                    rejected += 1;
+                }
+            }
+        }
+        if rejected > 0 {
+            bail!(
+                "Flushed {} of {} batches, {} rejected by InfluxDB were moved to {}",
+                flushed,
+                batches.len(),
+                rejected,
+                self.spool.join(REJECTED).display()
+            );
+        }
+        info!(
+            "Flushed {} batches to InfluxDB (bucket: {}/{})",
+            batches.len(),
+            self.org,
+            self.bucket
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MetricsSink for InfluxSink {
+    fn describe(&self) -> String {
+        format!("InfluxDB at {}", self.url)
+    }
+
+    async fn write(&self, points: &[Point]) -> Result<()> {
+        let lines: Vec<String> = points.iter().map(|p| p.to_string()).collect();
+        for line in &lines {
+            debug!("  > {}", line);
+        }
+
+        let batches: Vec<&[String]> = lines.chunks(BATCH_LINES).collect();
+        let (mut failed, mut rejected) = (0, 0);
+        for (i, batch) in batches.iter().enumerate() {
+            let body = batch.join("\n");
+            match self.send(&body).await {
+                Ok(()) => {}
+                Err(e) if e.transient => {
+                    let path = spool(&self.spool, i, &body)?;
+                    error!(
+                        "Giving up on batch {}, spooled to {}: {:#}",
+                        i,
+                        path.display(),
+                        e.error
+                    );
+                    failed += 1;
+                }
+                Err(e) => {
+                    // replaying would fail again, keep the batch for inspection only
+                    let path = spool(&self.spool.join(REJECTED), i, &body)?;
+                    error!(
+                        "InfluxDB rejected batch {}, kept in {}: {:#}",
+                        i,
+                        path.display(),
+                        e.error
+                    );
+                    rejected += 1;
+                }
+            }
+        }
+        let mut errors = Vec::new();
+        if failed > 0 {
+            errors.push(format!(
+                "{} of {} batches spooled to {}, replay them with `nesquic flush`",
+                failed,
+                batches.len(),
+                self.spool.display()
+            ));
+        }
+        if rejected > 0 {
+            errors.push(format!(
+                "{} of {} batches rejected by InfluxDB, kept in {}",
+                rejected,
+                batches.len(),
+                self.spool.join(REJECTED).display()
+            ));
+        }
+        if !errors.is_empty() {
+            bail!("{}", errors.join("; "));
+        }
+
+        info!(
+            "Metrics written to InfluxDB (bucket: {}/{})",
+            self.org, self.bucket
+        );
+        Ok(())
+    }
+}
+
+/// Delay before retrying after the `attempt`th failure.
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// Throttling and server errors may pass, other errors repeat on every retry.
+fn is_transient(status: u16) -> bool {
+    status == 408 || status == 429 || status >= 500
+}
+
+fn gzip(body: &str) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes())?;
+    Ok(encoder.finish()?)
+}
+
+/// Keeps a failed batch in `dir`, named so that batches sort in the order they failed.
+fn spool(dir: &Path, batch: usize, body: &str) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    let timestamp_ns = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let path = dir.join(format!("{:020}-{:05}.lp", timestamp_ns, batch));
+    fs::write(&path, body).with_context(|| format!("write {}", path.display()))?;
+    Ok(path)
+}
+
+/// Moves the spooled batch at `path` to the `rejected` subdirectory of `dir`.
+fn reject(dir: &Path, path: &Path) -> Result<PathBuf> {
+    let rejected = dir.join(REJECTED);
+    fs::create_dir_all(&rejected).with_context(|| format!("create {}", rejected.display()))?;
+    let Some(name) = path.file_name() else {
+        bail!("{} is not a spooled batch", path.display());
+    };
+    let moved = rejected.join(name);
+    fs::rename(path, &moved).with_context(|| format!("move {}", path.display()))?;
+    Ok(moved)
+}
+
+/// The spooled batches in `dir`, oldest first, without the rejected ones.
+fn spooled(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut batches: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lp"))
+        .collect();
+    batches.sort();
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use test_case::test_case;
+
+    #[test_case(1, 500)]
+    #[test_case(2, 1000)]
+    #[test_case(4, 4000)]
+    #[test_case(7, 30000)]
+    #[test_case(40, 30000)]
+    fn backoffs(attempt: u32, millis: u64) {
+        assert_eq!(backoff(attempt), Duration::from_millis(millis));
+    }
+
+    #[test_case(503, true)]
+    #[test_case(429, true)]
+    #[test_case(400, false)]
+    #[test_case(401, false)]
+    fn transient(status: u16, expected: bool) {
+        assert_eq!(is_transient(status), expected);
+    }
+
+    #[test]
+    fn gzipped() {
+        let mut body = String::new();
+        GzDecoder::new(&gzip("nesquic throughput=1 1").unwrap()[..])
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "nesquic throughput=1 1");
+    }
+
+    #[test]
+    fn spool_order() {
+        let dir = env::temp_dir().join(format!("nesquic-spool-{}", std::process::id()));
+        assert!(spooled(&dir).unwrap().is_empty());
+
+        let first = spool(&dir, 0, "a").unwrap();
+        let second = spool(&dir, 1, "b").unwrap();
+        let third = spool(&dir, 2, "c").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        spool(&dir.join(REJECTED), 3, "d").unwrap();
+        assert_eq!(spooled(&dir).unwrap(), vec![first, second.clone(), third]);
+
+        let rejected = reject(&dir, &second).unwrap();
+        assert_eq!(fs::read_to_string(rejected).unwrap(), "b");
+        assert_eq!(spooled(&dir).unwrap().len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use super::{build_tag_str, sink::Point};
+use anyhow::{bail, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::MissedTickBehavior;
+use tracing::{error, warn};
+
+/// Calls and bytes of one syscall.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CallCounts {
+    pub calls: u64,
+    pub bytes: u64,
+}
+
+/// The cumulative counters that are sampled every interval.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntervalStats {
+    pub syscalls: BTreeMap<&'static str, CallCounts>,
+    /// User and system time of the process
+    pub cpu_ns: u64,
+}
+
+impl IntervalStats {
+    /// The counters accumulated since `start`, without syscalls that were not called.
+    pub fn since(&self, start: &IntervalStats) -> IntervalStats {
+        let syscalls = self
+            .syscalls
+            .iter()
+            .map(|(name, counts)| {
+                let before = start.syscalls.get(name).copied().unwrap_or_default();
+                let delta = CallCounts {
+                    calls: counts.calls.saturating_sub(before.calls),
+                    bytes: counts.bytes.saturating_sub(before.bytes),
+                };
+                (*name, delta)
+            })
+            .filter(|(_, delta)| delta.calls > 0)
+            .collect();
+        IntervalStats {
+            syscalls,
+            cpu_ns: self.cpu_ns.saturating_sub(start.cpu_ns),
+        }
+    }
+
+    /// The deltas of one `interval`, see [`IntervalStats::since`].
+    pub fn line_protocol(
+        &self,
+        tag_str: &str,
+        interval: Duration,
+        timestamp_ns: u128,
+    ) -> Vec<String> {
+        let calls: u64 = self.syscalls.values().map(|c| c.calls).sum();
+        let sent = self.bytes(|name| name.starts_with("write") || name.starts_with("send"));
+        let received = self.bytes(|name| name.starts_with("read") || name.starts_with("recv"));
+        let seconds = interval.as_secs_f64();
+        let mbps = |bytes| bytes as f64 * 8.0 / 1e6 / seconds;
+        let mut lines = vec![format!(
+            "nesquic_interval{} interval_ns={}i,syscalls={}i,sent_bytes={}i,received_bytes={}i,cpu_ns={}i,cpu_utilization={},send_mbps={},recv_mbps={} {}",
+            tag_str,
+            interval.as_nanos(),
+            calls,
+            sent,
+            received,
+            self.cpu_ns,
+            self.cpu_ns as f64 / 1e9 / seconds,
+            mbps(sent),
+            mbps(received),
+            timestamp_ns
+        )];
+        for (name, counts) in &self.syscalls {
+            lines.push(format!(
+                "nesquic_interval_io{},syscall={} calls={}i,bytes={}i {}",
+                tag_str, name, counts.calls, counts.bytes, timestamp_ns
+            ));
+        }
+        lines
+    }
+
+    /// Bytes of the syscalls whose name matches, e.g. the sending ones
+    fn bytes(&self, matches: impl Fn(&str) -> bool) -> u64 {
+        self.syscalls
+            .iter()
+            .filter(|(name, _)| matches(name))
+            .map(|(_, counts)| counts.bytes)
+            .sum()
+    }
+}
+
+/// Samples the counters of a collector every interval and keeps their deltas
+/// as points, so that dashboards can follow a transfer over time.
+pub struct IntervalRecorder {
+    interval: Duration,
+    points: Vec<Point>,
+}
+
+impl IntervalRecorder {
+    pub fn new(interval: Duration) -> Self {
+        IntervalRecorder {
+            interval,
+            points: Vec::new(),
+        }
+    }
+
+    /// Samples `read` every interval until the returned future is dropped.
+    pub async fn record(
+        &mut self,
+        read: impl Fn() -> Result<IntervalStats>,
+        job: Option<String>,
+        tags: HashMap<String, String>,
+    ) {
+        let mut all_tags = tags;
+        all_tags.extend(job.map(|job| ("job".to_string(), job)));
+        let tag_str = build_tag_str(&all_tags);
+
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last: Option<(Instant, IntervalStats)> = None;
+        loop {
+            ticker.tick().await;
+            let stats = match read() {
+                Ok(stats) => stats,
+                Err(e) => {
+                    warn!("Skipping interval: {}", e);
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            if let Some((at, previous)) = &last {
+                let timestamp_ns = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                let lines = stats
+                    .since(previous)
+                    .line_protocol(&tag_str, now - *at, timestamp_ns);
+                match Point::parse_all(&lines.join("\n")) {
+                    Ok(points) => self.points.extend(points),
+                    Err(e) => error!("Malformed interval metrics: {}", e),
+                }
+            }
+            last = Some((now, stats));
+        }
+    }
+
+    /// The points of all intervals recorded so far.
+    pub fn into_points(self) -> Vec<Point> {
+        self.points
+    }
+}
+
+/// Parses an interval like `100ms`, `2s` or `1m`.
+pub fn parse_interval(s: &str) -> Result<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let Ok(value) = value.parse::<u64>() else {
+        bail!("Interval {} does not start with a number", s);
+    };
+    let interval = match unit {
+        "us" => Duration::from_micros(value),
+        "ms" => Duration::from_millis(value),
+        "s" => Duration::from_secs(value),
+        "m" => match value.checked_mul(60) {
+            Some(secs) => Duration::from_secs(secs),
+            None => bail!("Interval {} is too long", s),
+        },
+        _ => bail!("Unknown unit in {}, use us, ms, s or m", s),
+    };
+    if interval.is_zero() {
+        bail!("Interval must not be zero");
+    }
+    Ok(interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("100ms", Some(Duration::from_millis(100)))]
+    #[test_case("2s", Some(Duration::from_secs(2)))]
+    #[test_case("1m", Some(Duration::from_secs(60)))]
+    #[test_case("250us", Some(Duration::from_micros(250)))]
+    #[test_case("0s", None)]
+    #[test_case("100", None)]
+    #[test_case("ms", None)]
+    #[test_case("18446744073709551615m", None)]
+    fn intervals(s: &str, expected: Option<Duration>) {
+        assert_eq!(parse_interval(s).ok(), expected);
+    }
+
+    #[test]
+    fn deltas() {
+        let counts = |calls, bytes| CallCounts { calls, bytes };
+        let start = IntervalStats {
+            syscalls: BTreeMap::from([("sendmsg", counts(10, 12000)), ("recvmsg", counts(4, 600))]),
+            cpu_ns: 5_000_000,
+        };
+        let end = IntervalStats {
+            syscalls: BTreeMap::from([
+                ("sendmsg", counts(30, 37000)),
+                ("recvmsg", counts(8, 1100)),
+                ("epoll_wait", counts(2, 0)),
+            ]),
+            cpu_ns: 55_000_000,
+        };
+
+        let delta = end.since(&start);
+        assert_eq!(
+            delta.syscalls,
+            BTreeMap::from([
+                ("epoll_wait", counts(2, 0)),
+                ("recvmsg", counts(4, 500)),
+                ("sendmsg", counts(20, 25000))
+            ])
+        );
+        assert_eq!(
+            delta.line_protocol(",job=quinn", Duration::from_millis(100), 7),
+            vec![
+                "nesquic_interval,job=quinn interval_ns=100000000i,syscalls=26i,sent_bytes=25000i,received_bytes=500i,cpu_ns=50000000i,cpu_utilization=0.5,send_mbps=2,recv_mbps=0.04 7",
+                "nesquic_interval_io,job=quinn,syscall=epoll_wait calls=2i,bytes=0i 7",
+                "nesquic_interval_io,job=quinn,syscall=recvmsg calls=4i,bytes=500i 7",
+                "nesquic_interval_io,job=quinn,syscall=sendmsg calls=20i,bytes=25000i 7",
+            ]
+        );
+    }
+}
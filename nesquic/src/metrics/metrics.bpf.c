@@ -3,11 +3,16 @@
 #include <bpf/bpf_helpers.h>
 #include <bpf/bpf_tracing.h>
 #include <bpf/bpf_endian.h>
+#include <bpf/bpf_core_read.h>
 
 char LICENSE[] SEC("license") = "GPL";
 
 volatile const u32 MONITORED_PID;
-#define pid_guard(...) if ((bpf_get_current_pid_tgid() >> 32) != MONITORED_PID) return 0
+// thread of a collector embedded in the monitored process, whose own work is not measured
+volatile const u32 COLLECTOR_TID;
+#define pid_guard(...)                                                  \
+    if ((bpf_get_current_pid_tgid() >> 32) != MONITORED_PID ||          \
+        (u32)bpf_get_current_pid_tgid() == COLLECTOR_TID) return 0
 
 const u16 EVENT_IO_SYSCALL_WRITE = 0;
 const u16 EVENT_IO_SYSCALL_WRITEV = 1;
@@ -23,93 +28,278 @@ const u16 EVENT_IO_SYSCALL_RECVFROM = 9;
 const u16 EVENT_IO_SYSCALL_RECVMSG = 10;
 const u16 EVENT_IO_SYSCALL_RECVMMSG = 11;
 
+// Syscalls an event loop waits in, or arms its timers with
+const u16 EVENT_WAIT_SYSCALL_EPOLL_WAIT = 12;
+const u16 EVENT_WAIT_SYSCALL_EPOLL_PWAIT = 13;
+const u16 EVENT_WAIT_SYSCALL_EPOLL_PWAIT2 = 14;
+const u16 EVENT_WAIT_SYSCALL_POLL = 15;
+const u16 EVENT_WAIT_SYSCALL_PPOLL = 16;
+const u16 EVENT_WAIT_SYSCALL_IO_URING_ENTER = 17;
+const u16 EVENT_WAIT_SYSCALL_FUTEX = 18;
+const u16 EVENT_WAIT_SYSCALL_CLOCK_NANOSLEEP = 19;
+const u16 EVENT_WAIT_SYSCALL_TIMERFD_SETTIME = 20;
+
+#define FUTEX_WAIT 0
+#define FUTEX_WAIT_BITSET 9
+#define FUTEX_CMD_MASK 0x7f
+
+#define HIST_SLOTS 32
+
+volatile const bool STREAM_EVENTS;
+
 struct ringbuf {
     __uint(type, BPF_MAP_TYPE_RINGBUF);
-    __uint(max_entries, 5000);
+    __uint(max_entries, 4 * 1024 * 1024);
 } events SEC(".maps");
 
+// The first byte of every event in the ring buffer
+#define EVENT_KIND_IO 0
+#define EVENT_KIND_SCHED 1
+#define EVENT_KIND_UPROBE 2
+
 struct event_io {
+    u8 kind;
     u16 syscall;
     u32 len;
+    u32 tid;
+    u64 start_ns;
+    u64 duration_ns;
+    char comm[16];
 };
 
-// so that libbpf exports it
+// A thread ran on a CPU (runq == 0) or waited in the run queue (runq == 1)
+struct event_sched {
+    u8 kind;
+    u8 runq;
+    u32 tid;
+    u32 cpu;
+    u64 start_ns;
+    u64 duration_ns;
+    char comm[16];
+};
+
+#define UPROBE_CRYPTO 0
+#define UPROBE_COMPONENT 1
+
+// A returned call of a crypto probe or a component
+struct event_uprobe {
+    u8 kind;
+    u8 source;
+    u32 id;
+    u32 tid;
+    u64 start_ns;
+    u64 duration_ns;
+    u64 bytes;
+};
+
+// so that libbpf exports them
 struct event_io noevent = {};
+struct event_sched noschedevent = {};
+struct event_uprobe nouprobeevent = {};
+
+struct syscall_stats {
+    u64 count;
+    u64 bytes;
+    // calls that returned 0, e.g. a wakeup without any ready events
+    u64 empty;
+    // sum of positive return values of the wait syscalls, e.g. the ready events
+    u64 events;
+    u64 size_hist[HIST_SLOTS];
+    u64 latency_hist[HIST_SLOTS];
+};
+
+// so that libbpf exports it, and used to initialize new map entries since
+// the struct does not fit on the BPF stack
+struct syscall_stats zero_syscall_stats = {};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __uint(max_entries, 64);
+    __type(key, u32);
+    __type(value, struct syscall_stats);
+} syscalls SEC(".maps");
+
+struct inflight_io {
+    u64 start_ns;
+    u32 syscall;
+    // messages passed to sendmmsg or recvmmsg, whose `msg_len` is read at exit
+    u32 vlen;
+    struct mmsghdr *mmsg;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 4096);
+    __type(key, u32);
+    __type(value, struct inflight_io);
+} inflight SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, u64);
+} dropped SEC(".maps");
+
+__always_inline void _count_dropped() {
+    u32 key = 0;
+    u64 *count = bpf_map_lookup_elem(&dropped, &key);
+    if (count) {
+        *count += 1;
+    }
+}
+
+__always_inline u32 log2_u32(u32 v) {
+    u32 r, shift;
+    r = (v > 0xFFFF) << 4; v >>= r;
+    shift = (v > 0xFF) << 3; v >>= shift; r |= shift;
+    shift = (v > 0xF) << 2; v >>= shift; r |= shift;
+    shift = (v > 0x3) << 1; v >>= shift; r |= shift;
+    r |= (v >> 1);
+    return r;
+}
+
+__always_inline u32 log2_u64(u64 v) {
+    u32 hi = v >> 32;
+    if (hi) {
+        return log2_u32(hi) + 32;
+    }
+    return log2_u32(v);
+}
+
+// Slot 0 counts zeros, slot i > 0 counts values in [2^(i-1), 2^i).
+__always_inline u32 hist_slot(u64 v) {
+    if (v == 0) {
+        return 0;
+    }
+    u32 slot = log2_u64(v) + 1;
+    return slot < HIST_SLOTS ? slot : HIST_SLOTS - 1;
+}
 
 __always_inline void _submit_event(void *event, u32 len) {
     struct bpf_dynptr ptr;
     if (bpf_ringbuf_reserve_dynptr(&events, len, 0, &ptr) != 0) {
         bpf_error("_submit_event failed to reserve %u bytes", len);
         bpf_ringbuf_discard_dynptr(&ptr, 0);
+        _count_dropped();
         return;
     }
 
     if (bpf_dynptr_write(&ptr, 0, event, len, 0) != 0) {
         bpf_error("_submit_event failed to write dynptr");
         bpf_ringbuf_discard_dynptr(&ptr, 0);
+        _count_dropped();
         return;
     }
 
     bpf_ringbuf_submit_dynptr(&ptr, 0);
 }
 
-__always_inline void _submit_event_io(u16 syscall, u32 len) {
+__always_inline void _submit_event_io(u16 syscall, u32 len, u64 start_ns, u64 duration_ns) {
     struct event_io ev = {
+        .kind = EVENT_KIND_IO,
         .syscall = syscall,
         .len = len,
+        .tid = (u32)bpf_get_current_pid_tgid(),
+        .start_ns = start_ns,
+        .duration_ns = duration_ns,
     };
+    bpf_get_current_comm(ev.comm, sizeof(ev.comm));
 
     _submit_event(&ev, sizeof(struct event_io));
 }
 
-__always_inline u32 count_iovec_len(struct iovec *vec, u32 vlen) {
-    u32 i = 0, k = 0;
-    bpf_for(i, 0, vlen) {
-        struct iovec iov;
-        if (bpf_probe_read_user(&iov, sizeof(struct iovec), vec + i) < 0) {
-            bpf_error("count_iovec_len failed to read iov[%u]", i);
-            return 0;
-        }
-
-        k += iov.iov_len;
-    }
+__always_inline void _submit_event_sched(struct task_struct *task, u8 runq, u64 start_ns, u64 duration_ns) {
+    struct event_sched ev = {
+        .kind = EVENT_KIND_SCHED,
+        .runq = runq,
+        .tid = task->pid,
+        .cpu = bpf_get_smp_processor_id(),
+        .start_ns = start_ns,
+        .duration_ns = duration_ns,
+    };
+    bpf_probe_read_kernel_str(ev.comm, sizeof(ev.comm), task->comm);
 
-    return k;
+    _submit_event(&ev, sizeof(struct event_sched));
 }
 
-__always_inline void _submit_event_iovec(u16 syscall, struct iovec *vec, u32 vlen) {
-    u32 k = count_iovec_len(vec, vlen);
-    _submit_event_io(syscall, k);
+__always_inline void _submit_event_uprobe(u8 source, u32 id, u64 start_ns, u64 duration_ns, u64 bytes) {
+    struct event_uprobe ev = {
+        .kind = EVENT_KIND_UPROBE,
+        .source = source,
+        .id = id,
+        .tid = (u32)bpf_get_current_pid_tgid(),
+        .start_ns = start_ns,
+        .duration_ns = duration_ns,
+        .bytes = bytes,
+    };
+
+    _submit_event(&ev, sizeof(struct event_uprobe));
 }
 
-__always_inline void _submit_event_user_msghdr(u16 syscall, struct user_msghdr *msg_ptr) {
-    u32 i = 0, k = 0;
-    struct user_msghdr msg;
-    if (bpf_probe_read_user(&msg, sizeof(struct user_msghdr), msg_ptr) < 0) {
-        bpf_error("_submit_event_user_msghdr failed to read msg");
-        return;
+__always_inline void _record_io(u32 syscall, u32 bytes, u64 latency_ns, long ret) {
+    struct syscall_stats *stats = bpf_map_lookup_elem(&syscalls, &syscall);
+    if (!stats) {
+        bpf_map_update_elem(&syscalls, &syscall, &zero_syscall_stats, BPF_NOEXIST);
+        stats = bpf_map_lookup_elem(&syscalls, &syscall);
+        if (!stats) {
+            bpf_error("_record_io failed to allocate stats for syscall %u", syscall);
+            _count_dropped();
+            return;
+        }
     }
 
-    bpf_for(i, 0, msg.msg_iovlen) {
-        k += count_iovec_len(msg.msg_iov, msg.msg_iovlen);
+    stats->count += 1;
+    stats->bytes += bytes;
+    // a read of 0 bytes is EOF rather than an empty poll, so only the waits count
+    if (syscall >= EVENT_WAIT_SYSCALL_EPOLL_WAIT) {
+        if (ret == 0) {
+            stats->empty += 1;
+        } else if (ret > 0) {
+            stats->events += ret;
+        }
     }
+    // masking keeps the verifier convinced that the index is in bounds
+    stats->size_hist[hist_slot(bytes) & (HIST_SLOTS - 1)] += 1;
+    stats->latency_hist[hist_slot(latency_ns) & (HIST_SLOTS - 1)] += 1;
+}
 
-    _submit_event_io(syscall, k);
+// Remembers the syscall until `sys_exit` fires for the same thread, which
+// is where the latency and the bytes it moved are known and the event gets
+// aggregated.
+__always_inline void _track_io_mmsg(u16 syscall, struct mmsghdr *mmsg, u32 vlen) {
+    u32 tid = (u32)bpf_get_current_pid_tgid();
+    struct inflight_io io = {
+        .start_ns = bpf_ktime_get_ns(),
+        .syscall = syscall,
+        .vlen = vlen,
+        .mmsg = mmsg,
+    };
+
+    if (bpf_map_update_elem(&inflight, &tid, &io, BPF_ANY) != 0) {
+        bpf_error("_track_io failed to track syscall %u", syscall);
+        _count_dropped();
+    }
 }
 
-__always_inline void _submit_event_msghdr(u16 syscall, struct mmsghdr *mmsg, u32 vlen) {
-    u32 i = 0, j = 0, k = 0;
-    bpf_for(i, 0, vlen) {
-        struct mmsghdr msg;
-        if (bpf_probe_read_user(&msg, sizeof(struct mmsghdr), mmsg + i) < 0) {
-            bpf_error("_submit_event_msghdr failed to read mmsg[%u]", i);
-            return;
+__always_inline void _track_io(u16 syscall) {
+    _track_io_mmsg(syscall, NULL, 0);
+}
+
+// Sums the `msg_len` the kernel filled in for the first `count` messages.
+__always_inline u32 count_mmsg_len(struct mmsghdr *mmsg, u32 count) {
+    u32 i = 0, k = 0;
+    bpf_for(i, 0, count) {
+        u32 len;
+        if (bpf_probe_read_user(&len, sizeof(len), &mmsg[i].msg_len) < 0) {
+            bpf_error("count_mmsg_len failed to read mmsg[%u]", i);
+            return k;
         }
 
-        k += count_iovec_len(msg.msg_hdr.msg_iov, msg.msg_hdr.msg_iovlen);
+        k += len;
     }
 
-    _submit_event_io(syscall, k);
+    return k;
 }
 
 // struct trace_sys_enter_writev_args {
@@ -123,17 +313,153 @@ __always_inline void _submit_event_msghdr(u16 syscall, struct mmsghdr *mmsg, u32
 //    unsigned long vlen;
 // };
 
-SEC("tracepoint/syscalls/sys_enter_writev")
-int writev(struct trace_event_raw_sys_enter *ctx) {
-    pid_guard();
+// Reads the arguments of each traced syscall, shared by its tracepoint and fentry programs.
 
-    unsigned long fd = ctx->args[0];
-    struct iovec *vec = (struct iovec *)ctx->args[1];
-    unsigned long vlen = ctx->args[2];
+__always_inline void _enter_writev(unsigned long *args) {
+    unsigned long fd = args[0];
+    struct iovec *vec = (struct iovec *)args[1];
+    unsigned long vlen = args[2];
 
     bpf_trace("writev(%lu, %p, %lu)", fd, vec, vlen);
-    _submit_event_iovec(EVENT_IO_SYSCALL_WRITEV, vec, vlen);
+    _track_io(EVENT_IO_SYSCALL_WRITEV);
+}
+
+__always_inline void _enter_readv(unsigned long *args) {
+    unsigned long fd = args[0];
+    struct iovec *vec = (struct iovec *)args[1];
+    unsigned long vlen = args[2];
+
+    bpf_trace("readv(%lu, %p, %lu)", fd, vec, vlen);
+    _track_io(EVENT_IO_SYSCALL_READV);
+}
 
+__always_inline void _enter_write(unsigned long *args) {
+    unsigned int fd = args[0];
+    const char *buf = (const char *)args[1];
+    size_t count = args[2];
+
+    bpf_trace("write(%u, %p, %lu)", fd, buf, count);
+    _track_io(EVENT_IO_SYSCALL_WRITE);
+}
+
+__always_inline void _enter_read(unsigned long *args) {
+    unsigned int fd = args[0];
+    char *buf = (char *)args[1];
+    size_t count = args[2];
+
+    bpf_trace("read(%u, %p, %lu)", fd, buf, count);
+    _track_io(EVENT_IO_SYSCALL_READ);
+}
+
+__always_inline void _enter_recvmsg(unsigned long *args) {
+    int fd = args[0];
+    struct user_msghdr *msg = (struct user_msghdr *)args[1];
+    unsigned int flags = args[2];
+
+    bpf_trace("recvmsg(%u, %p, %u)", fd, msg, flags);
+    _track_io(EVENT_IO_SYSCALL_RECVMSG);
+}
+
+__always_inline void _enter_recvmmsg(unsigned long *args) {
+    int fd = args[0];
+    struct mmsghdr *mmsg = (struct mmsghdr *)args[1];
+    unsigned int vlen = args[2];
+    unsigned int flags = args[3];
+
+    bpf_trace("recvmmsg(%u, %p, %u, %u)", fd, mmsg, vlen, flags);
+    _track_io_mmsg(EVENT_IO_SYSCALL_RECVMMSG, mmsg, vlen);
+}
+
+__always_inline void _enter_recvfrom(unsigned long *args) {
+    int fd = args[0];
+    void *buf = (void *)args[1];
+    size_t size = args[2];
+    unsigned int flags = args[3];
+    struct sockaddr *addr = (struct sockaddr *)args[4];
+    int *addr_len = (int *)args[5];
+
+    bpf_trace("recvfrom(%u, %p, %lu, %u, %p, %p)", fd, buf, size, flags, addr, addr_len);
+    _track_io(EVENT_IO_SYSCALL_RECVFROM);
+}
+
+__always_inline void _enter_sendto(unsigned long *args) {
+    int fd = args[0];
+    void *buf = (void *)args[1];
+    size_t len = args[2];
+    unsigned int flags = args[3];
+    struct sockaddr *addr = (struct sockaddr *)args[4];
+    int addr_len = args[5];
+
+    bpf_trace("sendto(%u, %p, %lu, %u, %p, %d)", fd, buf, len, flags, addr, addr_len);
+    _track_io(EVENT_IO_SYSCALL_SENDTO);
+}
+
+__always_inline void _enter_sendmsg(unsigned long *args) {
+    int fd = args[0];
+    struct user_msghdr *msg = (struct user_msghdr *)args[1];
+    unsigned int flags = args[2];
+
+    bpf_trace("sendmsg(%u, %p, %u)", fd, msg, flags);
+    _track_io(EVENT_IO_SYSCALL_SENDMSG);
+}
+
+__always_inline void _enter_sendmmsg(unsigned long *args) {
+    int fd = args[0];
+    struct mmsghdr *mmsg = (struct mmsghdr *)args[1];
+    unsigned int vlen = args[2];
+    unsigned int flags = args[3];
+
+    bpf_trace("sendmmsg(%u, %p, %u, %u)", fd, mmsg, vlen, flags);
+    _track_io_mmsg(EVENT_IO_SYSCALL_SENDMMSG, mmsg, vlen);
+}
+
+__always_inline void _enter_epoll_wait(unsigned long *args) {
+    _track_io(EVENT_WAIT_SYSCALL_EPOLL_WAIT);
+}
+
+__always_inline void _enter_epoll_pwait(unsigned long *args) {
+    _track_io(EVENT_WAIT_SYSCALL_EPOLL_PWAIT);
+}
+
+__always_inline void _enter_epoll_pwait2(unsigned long *args) {
+    _track_io(EVENT_WAIT_SYSCALL_EPOLL_PWAIT2);
+}
+
+__always_inline void _enter_poll(unsigned long *args) {
+    _track_io(EVENT_WAIT_SYSCALL_POLL);
+}
+
+__always_inline void _enter_ppoll(unsigned long *args) {
+    _track_io(EVENT_WAIT_SYSCALL_PPOLL);
+}
+
+__always_inline void _enter_io_uring_enter(unsigned long *args) {
+    _track_io(EVENT_WAIT_SYSCALL_IO_URING_ENTER);
+}
+
+__always_inline void _enter_futex(unsigned long *args) {
+    // only waits are wakeups, FUTEX_WAKE and friends return immediately
+    int cmd = args[1] & FUTEX_CMD_MASK;
+    if (cmd != FUTEX_WAIT && cmd != FUTEX_WAIT_BITSET) {
+        return;
+    }
+
+    _track_io(EVENT_WAIT_SYSCALL_FUTEX);
+}
+
+__always_inline void _enter_clock_nanosleep(unsigned long *args) {
+    _track_io(EVENT_WAIT_SYSCALL_CLOCK_NANOSLEEP);
+}
+
+__always_inline void _enter_timerfd_settime(unsigned long *args) {
+    _track_io(EVENT_WAIT_SYSCALL_TIMERFD_SETTIME);
+}
+
+SEC("tracepoint/syscalls/sys_enter_writev")
+int writev(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_writev(ctx->args);
     return 0;
 }
 
@@ -141,13 +467,7 @@ SEC("tracepoint/syscalls/sys_enter_readv")
 int readv(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    unsigned long fd = ctx->args[0];
-    struct iovec *vec = (struct iovec *)ctx->args[1];
-    unsigned long vlen = ctx->args[2];
-
-    bpf_trace("readv(%lu, %p, %lu)", fd, vec, vlen);
-    _submit_event_iovec(EVENT_IO_SYSCALL_READV, vec, vlen);
-
+    _enter_readv(ctx->args);
     return 0;
 }
 
@@ -155,13 +475,7 @@ SEC("tracepoint/syscalls/sys_enter_write")
 int write(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    unsigned int fd = ctx->args[0];
-    const char *buf = (const char *)ctx->args[1];
-    size_t count = ctx->args[2];
-
-    bpf_trace("write(%u, %p, %lu)", fd, buf, count);
-    _submit_event_io(EVENT_IO_SYSCALL_WRITE, count);
-
+    _enter_write(ctx->args);
     return 0;
 }
 
@@ -169,13 +483,7 @@ SEC("tracepoint/syscalls/sys_enter_read")
 int read(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    unsigned int fd = ctx->args[0];
-    char *buf = (char *)ctx->args[1];
-    size_t count = ctx->args[2];
-
-    bpf_trace("read(%u, %p, %lu)", fd, buf, count);
-    _submit_event_io(EVENT_IO_SYSCALL_READ, count);
-
+    _enter_read(ctx->args);
     return 0;
 }
 
@@ -183,13 +491,7 @@ SEC("tracepoint/syscalls/sys_enter_recvmsg")
 int recvmsg(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    int fd = ctx->args[0];
-    struct user_msghdr *msg = (struct user_msghdr *)ctx->args[1];
-    unsigned int flags = ctx->args[2];
-
-    bpf_trace("recvmsg(%u, %p, %u)", fd, msg, flags);
-    _submit_event_user_msghdr(EVENT_IO_SYSCALL_RECVMSG, msg);
-
+    _enter_recvmsg(ctx->args);
     return 0;
 }
 
@@ -197,14 +499,7 @@ SEC("tracepoint/syscalls/sys_enter_recvmmsg")
 int recvmmsg(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    int fd = ctx->args[0];
-    struct mmsghdr *mmsg = (struct mmsghdr *)ctx->args[1];
-    unsigned int vlen = ctx->args[2];
-    unsigned int flags = ctx->args[3];
-
-    bpf_trace("recvmmsg(%u, %p, %u, %u)", fd, mmsg, vlen, flags);
-    _submit_event_msghdr(EVENT_IO_SYSCALL_RECVMMSG, mmsg, vlen);
-
+    _enter_recvmmsg(ctx->args);
     return 0;
 }
 
@@ -212,16 +507,7 @@ SEC("tracepoint/syscalls/sys_enter_recvfrom")
 int recvfrom(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    int fd = ctx->args[0];
-    void *buf = (void *)ctx->args[1];
-    size_t size = ctx->args[2];
-    unsigned int flags = ctx->args[3];
-    struct sockaddr *addr = (struct sockaddr *)ctx->args[4];
-    int *addr_len = (int *)ctx->args[5];
-
-    bpf_trace("recvfrom(%u, %p, %lu, %u, %p, %p)", fd, buf, size, flags, addr, addr_len);
-    _submit_event_io(EVENT_IO_SYSCALL_RECVFROM, size);
-
+    _enter_recvfrom(ctx->args);
     return 0;
 }
 
@@ -229,16 +515,7 @@ SEC("tracepoint/syscalls/sys_enter_sendto")
 int sendto(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    int fd = ctx->args[0];
-    void *buf = (void *)ctx->args[1];
-    size_t len = ctx->args[2];
-    unsigned int flags = ctx->args[3];
-    struct sockaddr *addr = (struct sockaddr *)ctx->args[4];
-    int addr_len = ctx->args[5];
-
-    bpf_trace("sendto(%u, %p, %lu, %u, %p, %d)", fd, buf, len, flags, addr, addr_len);
-    _submit_event_io(EVENT_IO_SYSCALL_SENDTO, len);
-
+    _enter_sendto(ctx->args);
     return 0;
 }
 
@@ -246,13 +523,7 @@ SEC("tracepoint/syscalls/sys_enter_sendmsg")
 int sendmsg(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    int fd = ctx->args[0];
-    struct user_msghdr *msg = (struct user_msghdr *)ctx->args[1];
-    unsigned int flags = ctx->args[2];
-
-    bpf_trace("sendmsg(%u, %p, %u)", fd, msg, flags);
-    _submit_event_user_msghdr(EVENT_IO_SYSCALL_SENDMSG, msg);
-
+    _enter_sendmsg(ctx->args);
     return 0;
 }
 
@@ -260,13 +531,1081 @@ SEC("tracepoint/syscalls/sys_enter_sendmmsg")
 int sendmmsg(struct trace_event_raw_sys_enter *ctx) {
     pid_guard();
 
-    int fd = ctx->args[0];
-    struct mmsghdr *mmsg = (struct mmsghdr *)ctx->args[1];
-    unsigned int vlen = ctx->args[2];
-    unsigned int flags = ctx->args[3];
+    _enter_sendmmsg(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_epoll_wait")
+int epoll_wait(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
 
-    bpf_trace("sendmmsg(%u, %p, %u, %u)", fd, mmsg, vlen, flags);
-    _submit_event_msghdr(EVENT_IO_SYSCALL_SENDMMSG, mmsg, vlen);
+    _enter_epoll_wait(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_epoll_pwait")
+int epoll_pwait(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_epoll_pwait(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_epoll_pwait2")
+int epoll_pwait2(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_epoll_pwait2(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_poll")
+int poll(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_poll(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_ppoll")
+int ppoll(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_ppoll(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_io_uring_enter")
+int io_uring_enter(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_io_uring_enter(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_futex")
+int futex(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_futex(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_clock_nanosleep")
+int clock_nanosleep(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_clock_nanosleep(ctx->args);
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_timerfd_settime")
+int timerfd_settime(struct trace_event_raw_sys_enter *ctx) {
+    pid_guard();
+
+    _enter_timerfd_settime(ctx->args);
+    return 0;
+}
+
+struct thread_stats {
+    char comm[16];
+    u64 oncpu_ns;
+    u64 runq_ns;
+    u64 voluntary_switches;
+    u64 involuntary_switches;
+    u64 migrations;
+    u64 wakeups;
+    u64 runq_hist[HIST_SLOTS];
+    // traced I/O syscalls, recorded in `sys_exit`
+    u64 io_calls;
+    u64 io_bytes;
+};
+
+// so that libbpf exports it, and used to initialize new map entries
+struct thread_stats zero_thread_stats = {};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, u32);
+    __type(value, struct thread_stats);
+} threads SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, u32);
+    __type(value, u64);
+} oncpu_start SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 1024);
+    __type(key, u32);
+    __type(value, u64);
+} runq_start SEC(".maps");
+
+__always_inline bool _is_monitored(struct task_struct *task) {
+    return task->tgid == MONITORED_PID && task->pid != COLLECTOR_TID;
+}
+
+__always_inline struct thread_stats *_thread_stats(struct task_struct *task) {
+    u32 tid = task->pid;
+    struct thread_stats *stats = bpf_map_lookup_elem(&threads, &tid);
+    if (stats) {
+        return stats;
+    }
+
+    bpf_map_update_elem(&threads, &tid, &zero_thread_stats, BPF_NOEXIST);
+    stats = bpf_map_lookup_elem(&threads, &tid);
+    if (!stats) {
+        bpf_error("_thread_stats failed to allocate stats for thread %u", tid);
+        _count_dropped();
+        return NULL;
+    }
+
+    bpf_probe_read_kernel_str(stats->comm, sizeof(stats->comm), task->comm);
+    return stats;
+}
+
+__always_inline void _enqueued(struct task_struct *task) {
+    u32 tid = task->pid;
+    u64 now = bpf_ktime_get_ns();
+    bpf_map_update_elem(&runq_start, &tid, &now, BPF_ANY);
+
+    struct thread_stats *stats = _thread_stats(task);
+    if (stats) {
+        __sync_fetch_and_add(&stats->wakeups, 1);
+    }
+}
+
+SEC("tp_btf/sched_wakeup")
+int BPF_PROG(sched_wakeup, struct task_struct *p) {
+    if (!_is_monitored(p)) {
+        return 0;
+    }
+
+    _enqueued(p);
+    return 0;
+}
+
+SEC("tp_btf/sched_wakeup_new")
+int BPF_PROG(sched_wakeup_new, struct task_struct *p) {
+    if (!_is_monitored(p)) {
+        return 0;
+    }
+
+    _enqueued(p);
+    return 0;
+}
+
+SEC("tp_btf/sched_switch")
+int BPF_PROG(sched_switch, bool preempt, struct task_struct *prev, struct task_struct *next) {
+    u64 now = bpf_ktime_get_ns();
+
+    if (_is_monitored(prev)) {
+        u32 tid = prev->pid;
+        struct thread_stats *stats = _thread_stats(prev);
+        u64 *start = bpf_map_lookup_elem(&oncpu_start, &tid);
+        if (stats && start) {
+            stats->oncpu_ns += now - *start;
+        }
+        if (STREAM_EVENTS && start) {
+            _submit_event_sched(prev, 0, *start, now - *start);
+        }
+        bpf_map_delete_elem(&oncpu_start, &tid);
+
+        if (stats) {
+            // threads may be renamed after they started, e.g. tokio workers
+            bpf_probe_read_kernel_str(stats->comm, sizeof(stats->comm), prev->comm);
+
+            if (preempt) {
+                // still runnable, so it goes right back to the run queue
+                stats->involuntary_switches += 1;
+                bpf_map_update_elem(&runq_start, &tid, &now, BPF_ANY);
+            } else {
+                stats->voluntary_switches += 1;
+            }
+        }
+    }
+
+    if (_is_monitored(next)) {
+        u32 tid = next->pid;
+        bpf_map_update_elem(&oncpu_start, &tid, &now, BPF_ANY);
+
+        u64 *enqueued = bpf_map_lookup_elem(&runq_start, &tid);
+        if (enqueued) {
+            u64 latency_ns = now - *enqueued;
+            struct thread_stats *stats = _thread_stats(next);
+            if (stats) {
+                stats->runq_ns += latency_ns;
+                stats->runq_hist[hist_slot(latency_ns) & (HIST_SLOTS - 1)] += 1;
+            }
+            if (STREAM_EVENTS) {
+                _submit_event_sched(next, 1, *enqueued, latency_ns);
+            }
+            bpf_map_delete_elem(&runq_start, &tid);
+        }
+    }
+
+    return 0;
+}
+
+SEC("tp_btf/sched_migrate_task")
+int BPF_PROG(sched_migrate_task, struct task_struct *p, int dest_cpu) {
+    if (!_is_monitored(p)) {
+        return 0;
+    }
+
+    struct thread_stats *stats = _thread_stats(p);
+    if (stats) {
+        __sync_fetch_and_add(&stats->migrations, 1);
+    }
+
+    return 0;
+}
+
+// Aggregates the syscall tracked by `_track_io` once it returned `ret`.
+__always_inline void _sys_exit(long ret) {
+    u32 tid = (u32)bpf_get_current_pid_tgid();
+    struct inflight_io *io = bpf_map_lookup_elem(&inflight, &tid);
+    if (!io) {
+        return;
+    }
+
+    u64 start_ns = io->start_ns;
+    u64 latency_ns = bpf_ktime_get_ns() - start_ns;
+    u16 syscall = io->syscall;
+    u32 vlen = io->vlen;
+    struct mmsghdr *mmsg = io->mmsg;
+    bpf_map_delete_elem(&inflight, &tid);
+
+    // the bytes the syscall moved rather than the size of its buffers, e.g. none
+    // on EAGAIN; the *mmsg syscalls return a message count, each its `msg_len`
+    u32 len = 0;
+    if (syscall == EVENT_IO_SYSCALL_SENDMMSG || syscall == EVENT_IO_SYSCALL_RECVMMSG) {
+        len = ret > 0 ? count_mmsg_len(mmsg, ret < vlen ? ret : vlen) : 0;
+    } else if (syscall < EVENT_WAIT_SYSCALL_EPOLL_WAIT && ret > 0) {
+        len = ret;
+    }
+
+    bpf_trace("sys_exit(%u) after %llu ns", syscall, latency_ns);
+    _record_io(syscall, len, latency_ns, ret);
+
+    if (syscall < EVENT_WAIT_SYSCALL_EPOLL_WAIT) {
+        struct thread_stats *stats = _thread_stats(bpf_get_current_task_btf());
+        if (stats) {
+            __sync_fetch_and_add(&stats->io_calls, 1);
+            __sync_fetch_and_add(&stats->io_bytes, len);
+        }
+    }
+
+    if (STREAM_EVENTS) {
+        _submit_event_io(syscall, len, start_ns, latency_ns);
+    }
+}
+
+SEC("tracepoint/raw_syscalls/sys_exit")
+int sys_exit(struct trace_event_raw_sys_exit *ctx) {
+    pid_guard();
+
+    _sys_exit(ctx->ret);
+    return 0;
+}
+
+// fentry/fexit programs on the syscall handlers, e.g. `__x64_sys_write(const struct pt_regs *regs)`.
+// Unlike the syscall tracepoints, they do not force every syscall of every process onto the slow
+// path. The collector sets their attach target before loading, or skips them if the kernel lacks
+// BTF or trampolines.
+#define SYSCALL_FENTRY(name)                                          \
+    SEC("fentry")                                                     \
+    int BPF_PROG(name##_fentry, struct pt_regs *regs) {               \
+        pid_guard();                                                  \
+                                                                      \
+        unsigned long args[6] = {                                     \
+            PT_REGS_PARM1_CORE_SYSCALL(regs),                         \
+            PT_REGS_PARM2_CORE_SYSCALL(regs),                         \
+            PT_REGS_PARM3_CORE_SYSCALL(regs),                         \
+            PT_REGS_PARM4_CORE_SYSCALL(regs),                         \
+            PT_REGS_PARM5_CORE_SYSCALL(regs),                         \
+            PT_REGS_PARM6_CORE_SYSCALL(regs),                         \
+        };                                                            \
+        _enter_##name(args);                                          \
+        return 0;                                                     \
+    }                                                                 \
+                                                                      \
+    SEC("fexit")                                                      \
+    int BPF_PROG(name##_fexit, struct pt_regs *regs, long ret) {      \
+        pid_guard();                                                  \
+                                                                      \
+        _sys_exit(ret);                                               \
+        return 0;                                                     \
+    }
+
+SYSCALL_FENTRY(write)
+SYSCALL_FENTRY(writev)
+SYSCALL_FENTRY(sendto)
+SYSCALL_FENTRY(sendmsg)
+SYSCALL_FENTRY(sendmmsg)
+SYSCALL_FENTRY(read)
+SYSCALL_FENTRY(readv)
+SYSCALL_FENTRY(recvfrom)
+SYSCALL_FENTRY(recvmsg)
+SYSCALL_FENTRY(recvmmsg)
+SYSCALL_FENTRY(epoll_wait)
+SYSCALL_FENTRY(epoll_pwait)
+SYSCALL_FENTRY(epoll_pwait2)
+SYSCALL_FENTRY(poll)
+SYSCALL_FENTRY(ppoll)
+SYSCALL_FENTRY(io_uring_enter)
+SYSCALL_FENTRY(futex)
+SYSCALL_FENTRY(clock_nanosleep)
+SYSCALL_FENTRY(timerfd_settime)
+
+// Returns the n-th (1-based) argument at the entry of a uprobed function.
+__always_inline u64 _uprobe_arg(struct pt_regs *ctx, u32 n) {
+    switch (n) {
+    case 1: return PT_REGS_PARM1(ctx);
+    case 2: return PT_REGS_PARM2(ctx);
+    case 3: return PT_REGS_PARM3(ctx);
+    case 4: return PT_REGS_PARM4(ctx);
+    case 5: return PT_REGS_PARM5(ctx);
+    case 6: return PT_REGS_PARM6(ctx);
+    }
+
+#if defined(__TARGET_ARCH_x86)
+    // further arguments are on the stack, right above the return address
+    u64 val = 0;
+    if (bpf_probe_read_user(&val, sizeof(val), (void *)(PT_REGS_SP(ctx) + 8 * (n - 6))) < 0) {
+        bpf_error("_uprobe_arg failed to read argument %u", n);
+    }
+    return val;
+#else
+    return 0;
+#endif
+}
+
+// The attach cookie of the crypto probes encodes
+//  - bits  0..16: the probe id
+//  - bits 16..24: the argument holding the number of processed bytes, 0 if none
+//  - bits 32..48: a fixed number of processed bytes, used if there is no argument
+#define CRYPTO_COOKIE_PROBE(c) ((u32)((c) & 0xFFFF))
+#define CRYPTO_COOKIE_ARG(c) ((u32)(((c) >> 16) & 0xFF))
+#define CRYPTO_COOKIE_FIXED(c) ((u64)(((c) >> 32) & 0xFFFF))
+
+struct crypto_call {
+    u64 start_ns;
+    u64 bytes;
+};
+
+struct crypto_stats {
+    u64 calls;
+    u64 bytes;
+    u64 time_ns;
+};
+
+// so that libbpf exports it
+struct crypto_stats nocryptostats = {};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 4096);
+    // tid << 32 | probe id
+    __type(key, u64);
+    __type(value, struct crypto_call);
+} crypto_inflight SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __uint(max_entries, 256);
+    __type(key, u32);
+    __type(value, struct crypto_stats);
+} crypto SEC(".maps");
+
+SEC("uprobe")
+int BPF_KPROBE(crypto_enter) {
+    pid_guard();
+
+    u64 cookie = bpf_get_attach_cookie(ctx);
+    u32 arg = CRYPTO_COOKIE_ARG(cookie);
+    u64 key = (bpf_get_current_pid_tgid() << 32) | CRYPTO_COOKIE_PROBE(cookie);
+    struct crypto_call call = {
+        .start_ns = bpf_ktime_get_ns(),
+        .bytes = arg ? _uprobe_arg(ctx, arg) : CRYPTO_COOKIE_FIXED(cookie),
+    };
+
+    if (bpf_map_update_elem(&crypto_inflight, &key, &call, BPF_ANY) != 0) {
+        _count_dropped();
+    }
+
+    return 0;
+}
+
+SEC("uretprobe")
+int BPF_KRETPROBE(crypto_exit) {
+    pid_guard();
+
+    u64 cookie = bpf_get_attach_cookie(ctx);
+    u32 probe = CRYPTO_COOKIE_PROBE(cookie);
+    u64 key = (bpf_get_current_pid_tgid() << 32) | probe;
+    struct crypto_call *call = bpf_map_lookup_elem(&crypto_inflight, &key);
+    if (!call) {
+        return 0;
+    }
+
+    u64 start_ns = call->start_ns;
+    u64 time_ns = bpf_ktime_get_ns() - start_ns;
+    u64 bytes = call->bytes;
+    bpf_map_delete_elem(&crypto_inflight, &key);
+
+    if (STREAM_EVENTS) {
+        _submit_event_uprobe(UPROBE_CRYPTO, probe, start_ns, time_ns, bytes);
+    }
+
+    struct crypto_stats *stats = bpf_map_lookup_elem(&crypto, &probe);
+    if (!stats) {
+        struct crypto_stats zero = {};
+        bpf_map_update_elem(&crypto, &probe, &zero, BPF_NOEXIST);
+        stats = bpf_map_lookup_elem(&crypto, &probe);
+        if (!stats) {
+            _count_dropped();
+            return 0;
+        }
+    }
+
+    stats->calls += 1;
+    stats->bytes += bytes;
+    stats->time_ns += time_ns;
+
+    return 0;
+}
+
+struct component_call {
+    u64 start_ns;
+    // calls within the same component only count towards the outermost one
+    u64 depth;
+};
+
+struct component_stats {
+    u64 calls;
+    u64 time_ns;
+    u64 latency_hist[HIST_SLOTS];
+};
+
+// so that libbpf exports it, and used to initialize new map entries
+struct component_stats zero_component_stats = {};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 4096);
+    // tid << 32 | component id
+    __type(key, u64);
+    __type(value, struct component_call);
+} component_inflight SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __uint(max_entries, 256);
+    __type(key, u32);
+    __type(value, struct component_stats);
+} components SEC(".maps");
+
+// The attach cookie of the component probes is the component id.
+SEC("uprobe")
+int BPF_KPROBE(component_enter) {
+    pid_guard();
+
+    u64 key = (bpf_get_current_pid_tgid() << 32) | (u32)bpf_get_attach_cookie(ctx);
+    struct component_call *call = bpf_map_lookup_elem(&component_inflight, &key);
+    if (call) {
+        call->depth += 1;
+        return 0;
+    }
+
+    struct component_call new_call = {
+        .start_ns = bpf_ktime_get_ns(),
+        .depth = 1,
+    };
+    if (bpf_map_update_elem(&component_inflight, &key, &new_call, BPF_ANY) != 0) {
+        _count_dropped();
+    }
+
+    return 0;
+}
+
+SEC("uretprobe")
+int BPF_KRETPROBE(component_exit) {
+    pid_guard();
+
+    u32 component = (u32)bpf_get_attach_cookie(ctx);
+    u64 key = (bpf_get_current_pid_tgid() << 32) | component;
+    struct component_call *call = bpf_map_lookup_elem(&component_inflight, &key);
+    if (!call) {
+        return 0;
+    }
+
+    if (call->depth > 1) {
+        call->depth -= 1;
+        return 0;
+    }
+
+    u64 start_ns = call->start_ns;
+    u64 latency_ns = bpf_ktime_get_ns() - start_ns;
+    bpf_map_delete_elem(&component_inflight, &key);
+
+    if (STREAM_EVENTS) {
+        _submit_event_uprobe(UPROBE_COMPONENT, component, start_ns, latency_ns, 0);
+    }
+
+    struct component_stats *stats = bpf_map_lookup_elem(&components, &component);
+    if (!stats) {
+        bpf_map_update_elem(&components, &component, &zero_component_stats, BPF_NOEXIST);
+        stats = bpf_map_lookup_elem(&components, &component);
+        if (!stats) {
+            _count_dropped();
+            return 0;
+        }
+    }
+
+    stats->calls += 1;
+    stats->time_ns += latency_ns;
+    stats->latency_hist[hist_slot(latency_ns) & (HIST_SLOTS - 1)] += 1;
+
+    return 0;
+}
+
+#define MAX_STACK_DEPTH 127
+
+struct {
+    __uint(type, BPF_MAP_TYPE_STACK_TRACE);
+    __uint(max_entries, 16384);
+    __uint(key_size, sizeof(u32));
+    __uint(value_size, MAX_STACK_DEPTH * sizeof(u64));
+} stacks SEC(".maps");
+
+struct sample_key {
+    char comm[16];
+    // negative if the stack could not be walked
+    s32 user_stack;
+    s32 kernel_stack;
+};
+
+// so that libbpf exports it
+struct sample_key nosamplekey = {};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 16384);
+    __type(key, struct sample_key);
+    __type(value, u64);
+} samples SEC(".maps");
+
+// Attached to a CPU clock perf event on every CPU.
+SEC("perf_event")
+int profile(struct bpf_perf_event_data *ctx) {
+    pid_guard();
+
+    struct sample_key key = {};
+    bpf_get_current_comm(&key.comm, sizeof(key.comm));
+    key.user_stack = bpf_get_stackid(ctx, &stacks, BPF_F_USER_STACK);
+    key.kernel_stack = bpf_get_stackid(ctx, &stacks, 0);
+
+    u64 *count = bpf_map_lookup_elem(&samples, &key);
+    if (count) {
+        __sync_fetch_and_add(count, 1);
+        return 0;
+    }
+
+    u64 one = 1;
+    if (bpf_map_update_elem(&samples, &key, &one, BPF_NOEXIST) != 0) {
+        // a concurrent sample may have created the entry in the meantime
+        count = bpf_map_lookup_elem(&samples, &key);
+        if (count) {
+            __sync_fetch_and_add(count, 1);
+        } else {
+            _count_dropped();
+        }
+    }
+
+    return 0;
+}
+
+#define EAGAIN 11
+#define ENOMEM 12
+
+struct udp_stats {
+    u64 send_calls;
+    u64 send_bytes;
+    u64 send_ns;
+    u64 send_errors;
+    u64 recv_calls;
+    u64 recv_bytes;
+    // the IUTs use non-blocking sockets, so this does not include waiting for packets
+    u64 recv_ns;
+    u64 recv_errors;
+    // packets that did not fit into the receive buffer of a monitored socket
+    u64 rcvbuf_errors;
+    // packets that could not be queued to a monitored socket for other reasons
+    u64 queue_errors;
+};
+
+// so that libbpf exports it
+struct udp_stats noudpstats = {};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, struct udp_stats);
+} udp SEC(".maps");
+
+// The sockets the monitored process sent or received on. Packets are dropped
+// in softirq context, where the PID is meaningless.
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 1024);
+    __type(key, u64);
+    __type(value, u8);
+} udp_socks SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 1024);
+    // tid
+    __type(key, u32);
+    __type(value, u64);
+} udp_inflight SEC(".maps");
+
+// Packets freed by kfree_skb per drop reason
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __uint(max_entries, 512);
+    __type(key, u32);
+    __type(value, u64);
+} skb_drops SEC(".maps");
+
+__always_inline struct udp_stats *_udp_stats() {
+    u32 key = 0;
+    return bpf_map_lookup_elem(&udp, &key);
+}
+
+__always_inline bool _is_monitored_sock(struct sock *sk) {
+    u64 key = (u64)sk;
+    return sk && bpf_map_lookup_elem(&udp_socks, &key);
+}
+
+__always_inline int _udp_enter(struct sock *sk) {
+    pid_guard();
+
+    u64 key = (u64)sk;
+    u8 one = 1;
+    bpf_map_update_elem(&udp_socks, &key, &one, BPF_ANY);
+
+    u32 tid = bpf_get_current_pid_tgid();
+    u64 now = bpf_ktime_get_ns();
+    if (bpf_map_update_elem(&udp_inflight, &tid, &now, BPF_ANY) != 0) {
+        _count_dropped();
+    }
+
+    return 0;
+}
+
+__always_inline int _udp_exit(bool send, int ret) {
+    pid_guard();
+
+    u32 tid = bpf_get_current_pid_tgid();
+    u64 *start = bpf_map_lookup_elem(&udp_inflight, &tid);
+    if (!start) {
+        return 0;
+    }
+    u64 duration_ns = bpf_ktime_get_ns() - *start;
+    bpf_map_delete_elem(&udp_inflight, &tid);
+
+    struct udp_stats *stats = _udp_stats();
+    if (!stats) {
+        return 0;
+    }
+
+    if (send) {
+        stats->send_calls += 1;
+        stats->send_ns += duration_ns;
+        if (ret >= 0) {
+            stats->send_bytes += ret;
+        } else if (ret != -EAGAIN) {
+            stats->send_errors += 1;
+        }
+    } else {
+        stats->recv_calls += 1;
+        stats->recv_ns += duration_ns;
+        if (ret >= 0) {
+            stats->recv_bytes += ret;
+        } else if (ret != -EAGAIN) {
+            stats->recv_errors += 1;
+        }
+    }
+
+    return 0;
+}
+
+SEC("kprobe/udp_sendmsg")
+int BPF_KPROBE(udp_sendmsg_enter, struct sock *sk) {
+    return _udp_enter(sk);
+}
+
+SEC("kretprobe/udp_sendmsg")
+int BPF_KRETPROBE(udp_sendmsg_exit, int ret) {
+    return _udp_exit(true, ret);
+}
+
+SEC("kprobe/udpv6_sendmsg")
+int BPF_KPROBE(udpv6_sendmsg_enter, struct sock *sk) {
+    return _udp_enter(sk);
+}
+
+SEC("kretprobe/udpv6_sendmsg")
+int BPF_KRETPROBE(udpv6_sendmsg_exit, int ret) {
+    return _udp_exit(true, ret);
+}
+
+SEC("kprobe/udp_recvmsg")
+int BPF_KPROBE(udp_recvmsg_enter, struct sock *sk) {
+    return _udp_enter(sk);
+}
+
+SEC("kretprobe/udp_recvmsg")
+int BPF_KRETPROBE(udp_recvmsg_exit, int ret) {
+    return _udp_exit(false, ret);
+}
+
+SEC("kprobe/udpv6_recvmsg")
+int BPF_KPROBE(udpv6_recvmsg_enter, struct sock *sk) {
+    return _udp_enter(sk);
+}
+
+SEC("kretprobe/udpv6_recvmsg")
+int BPF_KRETPROBE(udpv6_recvmsg_exit, int ret) {
+    return _udp_exit(false, ret);
+}
+
+SEC("tracepoint/skb/kfree_skb")
+int kfree_skb(struct trace_event_raw_kfree_skb *ctx) {
+    struct sk_buff *skb = ctx->skbaddr;
+    struct sock *sk = BPF_CORE_READ(skb, sk);
+    // received packets are only owned by a socket once they are queued
+    if (!sk && bpf_core_field_exists(ctx->rx_sk)) {
+        sk = ctx->rx_sk;
+    }
+    if (!_is_monitored_sock(sk)) {
+        return 0;
+    }
+
+    // drop reasons exist since 5.17
+    u32 reason = 0;
+    if (bpf_core_field_exists(ctx->reason)) {
+        reason = ctx->reason;
+    }
+
+    u64 *count = bpf_map_lookup_elem(&skb_drops, &reason);
+    if (count) {
+        *count += 1;
+        return 0;
+    }
+
+    u64 one = 1;
+    if (bpf_map_update_elem(&skb_drops, &reason, &one, BPF_NOEXIST) != 0) {
+        _count_dropped();
+    }
+
+    return 0;
+}
+
+SEC("tp_btf/udp_fail_queue_rcv_skb")
+int BPF_PROG(udp_fail_queue_rcv_skb, int rc, struct sock *sk) {
+    if (!_is_monitored_sock(sk)) {
+        return 0;
+    }
+
+    struct udp_stats *stats = _udp_stats();
+    if (!stats) {
+        return 0;
+    }
+
+    if (rc == -ENOMEM) {
+        stats->rcvbuf_errors += 1;
+    } else {
+        stats->queue_errors += 1;
+    }
+
+    return 0;
+}
+
+// the classifiers only count, so that the packets continue with the next filter
+#define TC_ACT_UNSPEC -1
+#define ETH_P_IP 0x0800
+#define ETH_P_IPV6 0x86DD
+
+#define QUIC_PACKET_INITIAL 0
+#define QUIC_PACKET_0RTT 1
+#define QUIC_PACKET_HANDSHAKE 2
+#define QUIC_PACKET_RETRY 3
+#define QUIC_PACKET_VERSION_NEGOTIATION 4
+#define QUIC_PACKET_SHORT 5
+
+#define QUIC_VERSION_2 0x6b3343cf
+
+#define DIRECTION_INGRESS 0
+#define DIRECTION_EGRESS 1
+
+// Set once the interface is known, i.e. after loading
+u16 QUIC_PORT = 4433;
+// 14 for Ethernet (and loopback) interfaces, 0 for TUN devices like the ones of mahimahi
+u32 L2_HEADER_LEN = 14;
+
+struct packet_stats {
+    u64 packets;
+    // UDP payload bytes
+    u64 bytes;
+    u64 size_hist[HIST_SLOTS];
+};
+
+// so that libbpf exports it, and used to initialize new map entries
+struct packet_stats zero_packet_stats = {};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __uint(max_entries, 16);
+    // direction << 8 | packet type
+    __type(key, u32);
+    __type(value, struct packet_stats);
+} packets SEC(".maps");
+
+// Returns the type of the first QUIC packet in the UDP payload at `offset`.
+// Coalesced packets, e.g. an Initial followed by a Handshake packet, count as the first one.
+__always_inline int _quic_packet_type(struct __sk_buff *skb, u32 offset) {
+    u8 first;
+    if (bpf_skb_load_bytes(skb, offset, &first, sizeof(first)) != 0) {
+        return -1;
+    }
+
+    if (!(first & 0x80)) {
+        return QUIC_PACKET_SHORT;
+    }
+
+    u32 version;
+    if (bpf_skb_load_bytes(skb, offset + 1, &version, sizeof(version)) != 0) {
+        return -1;
+    }
+    version = bpf_ntohl(version);
+    if (version == 0) {
+        return QUIC_PACKET_VERSION_NEGOTIATION;
+    }
+
+    u8 type = (first >> 4) & 0x3;
+    if (version == QUIC_VERSION_2) {
+        // RFC 9369 rotates the long header packet types by one
+        type = (type + 3) & 0x3;
+    }
+    return type;
+}
+
+__always_inline int _classify(struct __sk_buff *skb, u32 direction) {
+    u32 offset = L2_HEADER_LEN;
+    u8 protocol;
+
+    if (skb->protocol == bpf_htons(ETH_P_IP)) {
+        struct iphdr ip;
+        if (bpf_skb_load_bytes(skb, offset, &ip, sizeof(ip)) != 0) {
+            return TC_ACT_UNSPEC;
+        }
+        protocol = ip.protocol;
+        offset += ip.ihl * 4;
+    } else if (skb->protocol == bpf_htons(ETH_P_IPV6)) {
+        struct ipv6hdr ip;
+        if (bpf_skb_load_bytes(skb, offset, &ip, sizeof(ip)) != 0) {
+            return TC_ACT_UNSPEC;
+        }
+        // extension headers are not expected on the benchmark path
+        protocol = ip.nexthdr;
+        offset += sizeof(ip);
+    } else {
+        return TC_ACT_UNSPEC;
+    }
+
+    if (protocol != IPPROTO_UDP) {
+        return TC_ACT_UNSPEC;
+    }
+
+    struct udphdr udp;
+    if (bpf_skb_load_bytes(skb, offset, &udp, sizeof(udp)) != 0) {
+        return TC_ACT_UNSPEC;
+    }
+    if (udp.source != bpf_htons(QUIC_PORT) && udp.dest != bpf_htons(QUIC_PORT)) {
+        return TC_ACT_UNSPEC;
+    }
+    u16 udp_len = bpf_ntohs(udp.len);
+    if (udp_len < sizeof(udp)) {
+        return TC_ACT_UNSPEC;
+    }
+    offset += sizeof(udp);
+
+    int type = _quic_packet_type(skb, offset);
+    if (type < 0) {
+        return TC_ACT_UNSPEC;
+    }
+
+    u32 key = (direction << 8) | type;
+    struct packet_stats *stats = bpf_map_lookup_elem(&packets, &key);
+    if (!stats) {
+        bpf_map_update_elem(&packets, &key, &zero_packet_stats, BPF_NOEXIST);
+        stats = bpf_map_lookup_elem(&packets, &key);
+        if (!stats) {
+            _count_dropped();
+            return TC_ACT_UNSPEC;
+        }
+    }
+
+    u32 len = udp_len - sizeof(udp);
+    stats->packets += 1;
+    stats->bytes += len;
+    stats->size_hist[hist_slot(len) & (HIST_SLOTS - 1)] += 1;
+
+    return TC_ACT_UNSPEC;
+}
+
+SEC("tc")
+int quic_ingress(struct __sk_buff *skb) {
+    return _classify(skb, DIRECTION_INGRESS);
+}
+
+SEC("tc")
+int quic_egress(struct __sk_buff *skb) {
+    return _classify(skb, DIRECTION_EGRESS);
+}
+
+// Datagrams sent within this gap belong to the same burst
+#define BURST_GAP_NS 1000
+// Width of the windows the pacing rate is measured in
+#define PACING_WINDOW_NS (10 * 1000 * 1000)
+
+struct pacing_stats {
+    u64 datagrams;
+    u64 bytes;
+    u64 gap_hist[HIST_SLOTS];
+    // datagrams per burst
+    u64 burst_hist[HIST_SLOTS];
+};
+
+// so that libbpf exports it, and used to initialize new map entries
+struct pacing_stats zero_pacing_stats = {};
+
+struct pacing_window {
+    u64 datagrams;
+    u64 bytes;
+};
+
+// so that libbpf exports it
+struct pacing_window nopacingwindow = {};
+
+struct pacing_socket {
+    u64 last_ns;
+    // datagrams of the burst in progress, only added to `burst_hist` once the next
+    // burst starts; `read_pacing` adds the last one of every socket
+    u64 burst;
+};
+
+// so that libbpf exports it
+struct pacing_socket nopacingsocket = {};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, struct pacing_stats);
+} pacing SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 1024);
+    // struct sock *
+    __type(key, u64);
+    __type(value, struct pacing_socket);
+} pacing_sockets SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 65536);
+    // CLOCK_MONOTONIC / PACING_WINDOW_NS
+    __type(key, u64);
+    __type(value, struct pacing_window);
+} pacing_windows SEC(".maps");
+
+// Sends of the monitored sockets reach the device queue in the context of the sending thread.
+// A GSO packet counts as a burst of all its segments.
+SEC("tp_btf/net_dev_queue")
+int BPF_PROG(net_dev_queue, struct sk_buff *skb) {
+    struct sock *sk = skb->sk;
+    if (!_is_monitored_sock(sk)) {
+        return 0;
+    }
+
+    u64 now = bpf_ktime_get_ns();
+    u32 len = skb->len;
+    // read as plain values, as the verifier rejects arithmetic on BTF pointers
+    unsigned char *head = BPF_CORE_READ(skb, head);
+    u32 end = BPF_CORE_READ(skb, end);
+    struct skb_shared_info *shinfo = (struct skb_shared_info *)(head + end);
+    u64 segs = BPF_CORE_READ(shinfo, gso_segs);
+    if (segs == 0) {
+        segs = 1;
+    }
+
+    u32 zero = 0;
+    struct pacing_stats *stats = bpf_map_lookup_elem(&pacing, &zero);
+    if (!stats) {
+        return 0;
+    }
+    stats->datagrams += segs;
+    stats->bytes += len;
+    // the segments of a GSO packet leave back-to-back
+    stats->gap_hist[0] += segs - 1;
+
+    u64 key = (u64)sk;
+    struct pacing_socket *socket = bpf_map_lookup_elem(&pacing_sockets, &key);
+    if (socket) {
+        u64 gap_ns = now - socket->last_ns;
+        stats->gap_hist[hist_slot(gap_ns) & (HIST_SLOTS - 1)] += 1;
+
+        if (gap_ns < BURST_GAP_NS) {
+            socket->burst += segs;
+        } else {
+            stats->burst_hist[hist_slot(socket->burst) & (HIST_SLOTS - 1)] += 1;
+            socket->burst = segs;
+        }
+        socket->last_ns = now;
+    } else {
+        struct pacing_socket new_socket = {
+            .last_ns = now,
+            .burst = segs,
+        };
+        if (bpf_map_update_elem(&pacing_sockets, &key, &new_socket, BPF_ANY) != 0) {
+            _count_dropped();
+        }
+    }
+
+    u64 window_key = now / PACING_WINDOW_NS;
+    struct pacing_window *window = bpf_map_lookup_elem(&pacing_windows, &window_key);
+    if (window) {
+        __sync_fetch_and_add(&window->datagrams, segs);
+        __sync_fetch_and_add(&window->bytes, len);
+    } else {
+        struct pacing_window new_window = {
+            .datagrams = segs,
+            .bytes = len,
+        };
+        if (bpf_map_update_elem(&pacing_windows, &window_key, &new_window, BPF_NOEXIST) != 0) {
+            _count_dropped();
+        }
+    }
 
     return 0;
 }
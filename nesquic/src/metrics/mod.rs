@@ -1,36 +1,271 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use builder::{MetricsCollectorBuilder, ProbeSet, Probes};
 use byte_unit::{Byte, Unit};
+use components::{ComponentConfig, ComponentStats};
+use crypto::{CryptoOp, CryptoStats, CRYPTO_PROBES};
+use eventloop::EventLoopStats;
+use hist::Log2Histogram;
+use interval::{CallCounts, IntervalStats};
 use lazy_static::lazy_static;
 use libbpf_rs::{
-    set_print,
+    num_possible_cpus, set_print,
     skel::{OpenSkel, SkelBuilder},
-    Link, PrintLevel,
+    Link, MapCore, MapFlags, PrintLevel, RingBuffer, TcHook, TcHookBuilder, UprobeOpts, TC_EGRESS,
+    TC_INGRESS,
 };
-use reqwest::Client;
+use overhead::Overhead;
+use pacing::PacingStats;
+use packets::{Direction, PacketStats, PacketType};
+use perf::{perf_event_open, PerfCounters, PerfEventAttr, PerfStats};
+use probes::SyscallProbes;
+use procfs::ProcStats;
+use profile::{Profile, Symbolizer};
+use sched::{cpu_seconds_per_gb, top_thread_io_share, ThreadStats};
+use sink::{MetricsSink, Point};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+    fs,
     mem::MaybeUninit,
-    sync::Mutex,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    os::fd::{AsFd, AsRawFd, IntoRawFd, RawFd},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use symbols::ElfSymbols;
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    sync::oneshot,
+    task::JoinHandle,
+};
+use trace::Trace;
 use tracing::{debug, error, info, trace, warn};
+use udp::UdpStats;
+
+pub mod builder;
+pub mod components;
+pub mod crypto;
+pub mod eventloop;
+pub mod hist;
+pub mod influx;
+pub mod interval;
+pub mod overhead;
+pub mod pacing;
+pub mod packets;
+pub mod perf;
+pub mod probes;
+pub mod procfs;
+pub mod profile;
+pub mod sched;
+pub mod scrape;
+pub mod sink;
+pub mod symbols;
+pub mod trace;
+pub mod udp;
 
 include!(concat!(env!("OUT_DIR"), "/metrics.skel.rs"));
 
-unsafe impl plain::Plain for types::event_io {}
+/// `(syscall, tracepoint, fentry, fexit)` programs of every traced syscall
+macro_rules! syscall_progs {
+    ($progs:expr) => {{
+        let progs = $progs;
+        [
+            (
+                "write",
+                &mut progs.write,
+                &mut progs.write_fentry,
+                &mut progs.write_fexit,
+            ),
+            (
+                "writev",
+                &mut progs.writev,
+                &mut progs.writev_fentry,
+                &mut progs.writev_fexit,
+            ),
+            (
+                "sendto",
+                &mut progs.sendto,
+                &mut progs.sendto_fentry,
+                &mut progs.sendto_fexit,
+            ),
+            (
+                "sendmsg",
+                &mut progs.sendmsg,
+                &mut progs.sendmsg_fentry,
+                &mut progs.sendmsg_fexit,
+            ),
+            (
+                "sendmmsg",
+                &mut progs.sendmmsg,
+                &mut progs.sendmmsg_fentry,
+                &mut progs.sendmmsg_fexit,
+            ),
+            (
+                "read",
+                &mut progs.read,
+                &mut progs.read_fentry,
+                &mut progs.read_fexit,
+            ),
+            (
+                "readv",
+                &mut progs.readv,
+                &mut progs.readv_fentry,
+                &mut progs.readv_fexit,
+            ),
+            (
+                "recvfrom",
+                &mut progs.recvfrom,
+                &mut progs.recvfrom_fentry,
+                &mut progs.recvfrom_fexit,
+            ),
+            (
+                "recvmsg",
+                &mut progs.recvmsg,
+                &mut progs.recvmsg_fentry,
+                &mut progs.recvmsg_fexit,
+            ),
+            (
+                "recvmmsg",
+                &mut progs.recvmmsg,
+                &mut progs.recvmmsg_fentry,
+                &mut progs.recvmmsg_fexit,
+            ),
+            (
+                "epoll_wait",
+                &mut progs.epoll_wait,
+                &mut progs.epoll_wait_fentry,
+                &mut progs.epoll_wait_fexit,
+            ),
+            (
+                "epoll_pwait",
+                &mut progs.epoll_pwait,
+                &mut progs.epoll_pwait_fentry,
+                &mut progs.epoll_pwait_fexit,
+            ),
+            (
+                "epoll_pwait2",
+                &mut progs.epoll_pwait2,
+                &mut progs.epoll_pwait2_fentry,
+                &mut progs.epoll_pwait2_fexit,
+            ),
+            (
+                "poll",
+                &mut progs.poll,
+                &mut progs.poll_fentry,
+                &mut progs.poll_fexit,
+            ),
+            (
+                "ppoll",
+                &mut progs.ppoll,
+                &mut progs.ppoll_fentry,
+                &mut progs.ppoll_fexit,
+            ),
+            (
+                "io_uring_enter",
+                &mut progs.io_uring_enter,
+                &mut progs.io_uring_enter_fentry,
+                &mut progs.io_uring_enter_fexit,
+            ),
+            (
+                "futex",
+                &mut progs.futex,
+                &mut progs.futex_fentry,
+                &mut progs.futex_fexit,
+            ),
+            (
+                "clock_nanosleep",
+                &mut progs.clock_nanosleep,
+                &mut progs.clock_nanosleep_fentry,
+                &mut progs.clock_nanosleep_fexit,
+            ),
+            (
+                "timerfd_settime",
+                &mut progs.timerfd_settime,
+                &mut progs.timerfd_settime_fentry,
+                &mut progs.timerfd_settime_fexit,
+            ),
+        ]
+    }};
+}
+
+unsafe impl plain::Plain for types::syscall_stats {}
+
+/// Index of the first syscall in [`SYSCALLS`] that receives, the ones before it send.
+pub const FIRST_RECV_SYSCALL: usize = 6;
+
+/// Index of the first syscall in [`SYSCALLS`] that is not I/O, but part of the event loop.
+pub const FIRST_WAIT_SYSCALL: usize = 12;
+
+/// Handle and priority of the tc classifiers, unlikely to be taken by the filters of others,
+/// which they must not replace.
+const TC_HANDLE: u32 = 0x6e71;
+const TC_PRIORITY: u32 = 0x6e71;
 
 lazy_static! {
+    /// Indexed by the `EVENT_*_SYSCALL_*` constants in `metrics.bpf.c`
     pub static ref SYSCALLS: Vec<&'static str> = vec![
         "write", "writev", "send", "sendto", "sendmsg", "sendmmsg", "read", "readv", "recv",
-        "recvfrom", "recvmsg", "recvmmsg",
+        "recvfrom", "recvmsg", "recvmmsg", "epoll_wait", "epoll_pwait", "epoll_pwait2", "poll",
+        "ppoll", "io_uring_enter", "futex", "clock_nanosleep", "timerfd_settime",
     ];
-    /// Bytes transferred per syscall invocation, keyed by syscall name.
-    pub static ref SYSCALL_VOLUMES: Mutex<HashMap<String, Vec<Byte>>> =
-        Mutex::new(HashMap::new());
     /// Throughput samples (Mbps) recorded by the client after each run.
     pub static ref THROUGHPUT_SAMPLES: Mutex<Vec<f64>> = Mutex::new(Vec::new());
 }
 
+/// Per-syscall statistics, aggregated in-kernel and merged across CPUs.
+#[derive(Clone, Debug, Default)]
+pub struct SyscallStats {
+    pub count: u64,
+    pub bytes: u64,
+    /// Event loop syscalls that returned 0, e.g. polls without any ready event
+    pub empty: u64,
+    /// Sum of the positive return values of the event loop syscalls, e.g. ready events
+    pub events: u64,
+    /// log2 histogram of the bytes passed per invocation
+    pub size: Log2Histogram,
+    /// log2 histogram of the syscall latency in nanoseconds
+    pub latency: Log2Histogram,
+}
+
+impl SyscallStats {
+    fn add(&mut self, stats: &types::syscall_stats) {
+        self.count += stats.count;
+        self.bytes += stats.bytes;
+        self.empty += stats.empty;
+        self.events += stats.events;
+        self.size.merge(&Log2Histogram::from_slots(stats.size_hist));
+        self.latency
+            .merge(&Log2Histogram::from_slots(stats.latency_hist));
+    }
+
+    fn volume_kb(&self) -> f64 {
+        Byte::from_u64(self.bytes)
+            .get_adjusted_unit(Unit::KB)
+            .get_value()
+    }
+}
+
+/// A point-in-time view of everything the eBPF programs aggregated so far.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub syscalls: HashMap<&'static str, SyscallStats>,
+    pub threads: Vec<ThreadStats>,
+    pub crypto: BTreeMap<(&'static str, CryptoOp), CryptoStats>,
+    pub components: Vec<(String, ComponentStats)>,
+    pub profile: Option<Profile>,
+    pub udp: Option<UdpStats>,
+    pub overhead: Option<Overhead>,
+    pub packets: BTreeMap<(Direction, PacketType), PacketStats>,
+    pub pacing: Option<PacingStats>,
+    pub event_loop: Option<EventLoopStats>,
+    pub perf: Option<PerfStats>,
+    /// Time since the collector started, or until it stopped
+    pub duration: Duration,
+    /// Events the eBPF programs could not record, e.g. because a map or
+    /// the ring buffer was full.
+    pub dropped_events: u64,
+}
+
 fn print(level: PrintLevel, msg: String) {
     let msg = msg.trim_start_matches("libbpf:").trim();
     match level {
@@ -40,203 +275,1183 @@ fn print(level: PrintLevel, msg: String) {
     }
 }
 
-fn process(ev: &[u8]) -> i32 {
-    let Ok(ev) = plain::from_bytes::<types::event_io>(ev) else {
+fn process(ev: &[u8], recording: Option<&Mutex<Trace>>) -> i32 {
+    let Some(event) = trace::parse(ev) else {
         return -1;
     };
 
-    trace!("Processing event: {:?}", ev);
+    trace!("Processing event: {:?}", event);
+    if let Some(recording) = recording {
+        recording.lock().unwrap().record(event);
+    }
 
-    let syscall = SYSCALLS[ev.syscall as usize];
-    let volume = Byte::from_u64(ev.len as u64);
+    0
+}
 
-    SYSCALL_VOLUMES
-        .lock()
-        .unwrap()
-        .entry(syscall.to_string())
-        .or_default()
-        .push(volume);
+impl Snapshot {
+    /// Bytes the traced I/O syscalls sent
+    pub fn sent_bytes(&self) -> u64 {
+        self.bytes_of(&SYSCALLS[..FIRST_RECV_SYSCALL])
+    }
 
-    0
+    /// Bytes the traced I/O syscalls received
+    pub fn received_bytes(&self) -> u64 {
+        self.bytes_of(&SYSCALLS[FIRST_RECV_SYSCALL..FIRST_WAIT_SYSCALL])
+    }
+
+    /// Bytes the run transferred: the blob size if known, otherwise the direction that
+    /// moved more, as adding both would count the blob once on each side of the endpoint
+    pub fn transferred_bytes(&self) -> u64 {
+        match &self.overhead {
+            Some(overhead) => overhead.goodput_bytes,
+            None => self.sent_bytes().max(self.received_bytes()),
+        }
+    }
+
+    fn bytes_of(&self, syscalls: &[&'static str]) -> u64 {
+        syscalls
+            .iter()
+            .filter_map(|s| self.syscalls.get(s))
+            .map(|s| s.bytes)
+            .sum()
+    }
+
+    /// Time all threads of the monitored process spent on a CPU
+    pub fn cpu_seconds(&self) -> f64 {
+        self.threads.iter().map(|t| t.oncpu_ns).sum::<u64>() as f64 / 1e9
+    }
 }
 
-/// Drain global metric state into InfluxDB line-protocol strings.
-/// All mutex locks are acquired and released inside this sync function,
-/// so no MutexGuard crosses an await boundary in the async push_all.
-fn collect_line_protocol(tag_str: &str, timestamp_ns: u128) -> Result<String> {
-    let mut lines: Vec<String> = Vec::new();
+/// Escape a tag key or value for the InfluxDB line protocol.
+pub(crate) fn escape_tag(s: &str) -> String {
+    s.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// The epoll fd of a ring buffer, which becomes readable whenever
+/// any of its maps has pending data.
+struct EpollFd(RawFd);
+
+impl AsRawFd for EpollFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Drain the ring buffer whenever its epoll fd signals new data, until `stop` fires.
+/// Everything submitted before the stop signal is consumed before returning.
+async fn consume_events(ringbuf: RingBuffer<'static>, mut stop: oneshot::Receiver<()>) {
+    let fd = match AsyncFd::with_interest(EpollFd(ringbuf.epoll_fd()), Interest::READABLE) {
+        Ok(fd) => fd,
+        Err(e) => {
+            error!("Failed to register ring buffer with the runtime: {}", e);
+            return;
+        }
+    };
+
+    trace!("Consuming events...");
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            guard = fd.readable() => {
+                let mut guard = match guard {
+                    Ok(guard) => guard,
+                    Err(e) => {
+                        error!("Failed to wait for ring buffer: {}", e);
+                        break;
+                    }
+                };
 
+                if let Err(e) = ringbuf.consume() {
+                    error!("Failed to consume ring buffer: {}", e);
+                }
+                guard.clear_ready();
+            }
+        }
+    }
+
+    if let Err(e) = ringbuf.consume() {
+        error!("Failed to drain ring buffer: {}", e);
+    }
+    trace!("Stopped consuming events");
+}
+
+/// Handle to the task running [`consume_events`].
+struct EventConsumer {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+/// The mean of the throughput samples the client recorded, if any.
+pub(crate) fn throughput_line(tag_str: &str, timestamp_ns: u128) -> Option<String> {
     let throughput_samples = THROUGHPUT_SAMPLES.lock().unwrap();
-    if !throughput_samples.is_empty() {
-        let mean = throughput_samples.iter().sum::<f64>() / throughput_samples.len() as f64;
-        lines.push(format!(
-            "nesquic{} throughput={} {}",
-            tag_str, mean, timestamp_ns
-        ));
+    if throughput_samples.is_empty() {
+        return None;
     }
+    let mean = throughput_samples.iter().sum::<f64>() / throughput_samples.len() as f64;
+    Some(format!(
+        "nesquic{} throughput={} {}",
+        tag_str, mean, timestamp_ns
+    ))
+}
 
-    let mut volumes = SYSCALL_VOLUMES.lock().unwrap();
-    for (syscall, data) in volumes.drain() {
-        let count = data.len();
-        let sum = data
-            .iter()
-            .fold(Byte::from_u64(0), |acc, v| acc.add(*v).unwrap())
-            .get_adjusted_unit(Unit::KB)
-            .get_value();
+pub(crate) fn build_tag_str(tags: &HashMap<String, String>) -> String {
+    let mut parts: Vec<String> = tags
+        .iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| format!("{}={}", escape_tag(k), escape_tag(v)))
+        .collect();
+    parts.sort();
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!(",{}", parts.join(","))
+    }
+}
+
+/// Turn a snapshot and the global throughput samples into InfluxDB line-protocol strings.
+/// The mutex lock is acquired and released inside this sync function,
+/// so no MutexGuard crosses an await boundary in the async points.
+fn collect_line_protocol(snapshot: &Snapshot, tag_str: &str, timestamp_ns: u128) -> Result<String> {
+    let mut lines: Vec<String> = Vec::new();
+    lines.extend(throughput_line(tag_str, timestamp_ns));
 
-        let syscall_tag = format!("{},syscall={}", tag_str, &syscall);
+    for (syscall, stats) in snapshot.syscalls.iter() {
+        let syscall_tag = format!("{},syscall={}", tag_str, syscall);
         lines.push(format!(
-            "nesquic_io{} volume_kb_sum={},count={}i {}",
-            syscall_tag, sum, count, timestamp_ns
+            "nesquic_io{} volume_kb_sum={},count={}i,size_p50={}i,size_p99={}i,latency_p50_ns={}i,latency_p99_ns={}i {}",
+            syscall_tag,
+            stats.volume_kb(),
+            stats.count,
+            stats.size.percentile(0.5),
+            stats.size.percentile(0.99),
+            stats.latency.percentile(0.5),
+            stats.latency.percentile(0.99),
+            timestamp_ns
         ));
     }
 
+    if let Some(perf) = &snapshot.perf {
+        lines.push(perf.line_protocol(tag_str, snapshot.transferred_bytes(), timestamp_ns));
+    }
+
+    for thread in snapshot.threads.iter() {
+        lines.push(thread.line_protocol(tag_str, timestamp_ns));
+    }
+
+    for ((backend, op), stats) in snapshot.crypto.iter() {
+        lines.push(stats.line_protocol(backend, *op, tag_str, timestamp_ns));
+    }
+
+    for (component, stats) in snapshot.components.iter() {
+        lines.push(stats.line_protocol(component, tag_str, timestamp_ns));
+    }
+
+    if let Some(profile) = &snapshot.profile {
+        lines.extend(profile.line_protocol(tag_str, timestamp_ns));
+    }
+
+    if let Some(udp) = &snapshot.udp {
+        lines.extend(udp.line_protocol(tag_str, timestamp_ns));
+    }
+
+    if let Some(overhead) = &snapshot.overhead {
+        lines.push(overhead.line_protocol(tag_str, timestamp_ns));
+    }
+
+    for ((direction, packet_type), stats) in snapshot.packets.iter() {
+        lines.push(stats.line_protocol(*direction, *packet_type, tag_str, timestamp_ns));
+    }
+
+    if let Some(pacing) = &snapshot.pacing {
+        lines.extend(pacing.line_protocol(tag_str, timestamp_ns));
+    }
+
+    if let Some(event_loop) = &snapshot.event_loop {
+        lines.push(event_loop.line_protocol(tag_str, timestamp_ns));
+    }
+
     if lines.is_empty() {
         bail!("No metrics to push");
     }
+
+    if !snapshot.threads.is_empty() {
+        let cpu_seconds = snapshot.cpu_seconds();
+        let per_gb = cpu_seconds_per_gb(cpu_seconds, snapshot.transferred_bytes())
+            .map(|v| format!(",cpu_seconds_per_gb={}", v))
+            .unwrap_or_default();
+        let io_share = top_thread_io_share(&snapshot.threads)
+            .map(|v| format!(",top_thread_io_share={}", v))
+            .unwrap_or_default();
+        lines.push(format!(
+            "nesquic_cpu{} cpu_seconds={}{}{} {}",
+            tag_str, cpu_seconds, per_gb, io_share, timestamp_ns
+        ));
+    }
+
+    lines.push(format!(
+        "nesquic_collector{} dropped_events={}i {}",
+        tag_str, snapshot.dropped_events, timestamp_ns
+    ));
+
     Ok(lines.join("\n"))
 }
 
 pub struct MetricsCollector<'obj> {
     skel: MetricsSkel<'obj>,
-    links: Option<Vec<Link>>,
+    pid: u32,
+    syscall_probes: SyscallProbes,
+    links: Vec<Link>,
+    /// Names of the programs that attached, recorded in the run metadata
+    probes: BTreeSet<String>,
+    consumer: Option<EventConsumer>,
+    components: Option<ComponentConfig>,
+    profiler: Option<Profiler>,
+    tracer: Option<Tracer>,
+    perf: Option<PerfCounters>,
+    /// Names of the kernel's drop reasons, set once the UDP stack is monitored
+    drop_reasons: Option<HashMap<u32, String>>,
+    tc_hooks: Vec<TcHook>,
+    /// The clsact qdisc, if the collector created it
+    tc_qdisc: Option<TcHook>,
+    pacing: bool,
+    /// Application bytes of the run, to reconcile the UDP bytes with
+    goodput_bytes: Option<u64>,
+    stream_events: bool,
+    /// The probes to attach once the collector starts
+    pending: Option<Probes>,
+    /// Shared objects to probe besides the mapped ones
+    objects: Vec<PathBuf>,
+    sinks: Vec<Box<dyn MetricsSink>>,
+    job: Option<String>,
+    labels: HashMap<String, String>,
+    started: Instant,
+    stopped: Option<Instant>,
+}
+
+struct Tracer {
+    trace: Arc<Mutex<Trace>>,
+    /// Where to write the trace once the collector stops
+    path: PathBuf,
+    /// Spans of the IUT to merge into the trace
+    spans: Option<PathBuf>,
+}
+
+struct Profiler {
+    symbolizer: Symbolizer,
+    /// Where to write the folded stacks once the collector stops
+    folded: PathBuf,
 }
 
 impl<'obj> MetricsCollector<'obj> {
-    pub fn new(open_obj: &'obj mut MaybeUninit<libbpf_rs::OpenObject>) -> Result<Self> {
+    pub fn builder() -> MetricsCollectorBuilder {
+        MetricsCollectorBuilder::default()
+    }
+
+    /// Loads the eBPF programs to monitor the process `pid`, except its thread
+    /// `collector_tid`. With `stream_events`, every traced syscall is additionally
+    /// sent through the ring buffer, on top of the in-kernel aggregation.
+    fn new(
+        open_obj: &'obj mut MaybeUninit<libbpf_rs::OpenObject>,
+        pid: u32,
+        collector_tid: Option<u32>,
+        stream_events: bool,
+    ) -> Result<Self> {
         set_print(Some((PrintLevel::Debug, print)));
 
+        let mut syscall_probes = SyscallProbes::detect(&SYSCALLS);
+        let obj: *mut MaybeUninit<libbpf_rs::OpenObject> = open_obj;
+        // SAFETY: `obj` comes from `open_obj`, which nothing else borrows
+        let skel = match Self::load(
+            unsafe { &mut *obj },
+            pid,
+            collector_tid,
+            stream_events,
+            &syscall_probes,
+        ) {
+            Ok(skel) => skel,
+            Err(e) if matches!(syscall_probes, SyscallProbes::Fentry(_)) => {
+                warn!(
+                    "Tracing syscalls with tracepoints: the fentry programs failed to load: {:#}",
+                    e
+                );
+                syscall_probes = SyscallProbes::Tracepoint;
+                // SAFETY: the skeleton that failed to load was dropped, so it no longer
+                // borrows `obj`, which can be opened again
+                Self::load(
+                    unsafe { &mut *obj },
+                    pid,
+                    collector_tid,
+                    stream_events,
+                    &syscall_probes,
+                )?
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            skel,
+            pid,
+            syscall_probes,
+            links: Vec::new(),
+            probes: BTreeSet::new(),
+            consumer: None,
+            components: None,
+            profiler: None,
+            tracer: None,
+            perf: None,
+            drop_reasons: None,
+            tc_hooks: Vec::new(),
+            tc_qdisc: None,
+            pacing: false,
+            goodput_bytes: None,
+            stream_events,
+            pending: None,
+            objects: Vec::new(),
+            sinks: Vec::new(),
+            job: None,
+            labels: HashMap::new(),
+            started: Instant::now(),
+            stopped: None,
+        })
+    }
+
+    /// Opens the eBPF object in `open_obj`, with `syscall_probes` picking the syscall
+    /// programs, and loads it.
+    fn load(
+        open_obj: &'obj mut MaybeUninit<libbpf_rs::OpenObject>,
+        pid: u32,
+        collector_tid: Option<u32>,
+        stream_events: bool,
+        syscall_probes: &SyscallProbes,
+    ) -> Result<MetricsSkel<'obj>> {
         let skel_builder = MetricsSkelBuilder::default();
         let mut open_skel = skel_builder.open(open_obj)?;
 
         let Some(rodata) = open_skel.maps.rodata_data.as_mut() else {
             bail!("Failed to load rodata");
         };
-        rodata.MONITORED_PID = std::process::id();
-        let skel = open_skel.load()?;
+        rodata.MONITORED_PID = pid;
+        rodata.COLLECTOR_TID = collector_tid.unwrap_or_default();
+        rodata.STREAM_EVENTS = stream_events;
 
-        Ok(Self { skel, links: None })
+        for (name, tracepoint, fentry, fexit) in syscall_progs!(&mut open_skel.progs) {
+            match syscall_probes {
+                SyscallProbes::Fentry(handlers) => {
+                    tracepoint.set_autoload(false);
+                    match handlers.get(name) {
+                        Some(handler) => {
+                            fentry.set_attach_target(0, Some(handler.clone()))?;
+                            fexit.set_attach_target(0, Some(handler.clone()))?;
+                        }
+                        None => {
+                            fentry.set_autoload(false);
+                            fexit.set_autoload(false);
+                        }
+                    }
+                }
+                SyscallProbes::Tracepoint => {
+                    fentry.set_autoload(false);
+                    fexit.set_autoload(false);
+                }
+            }
+        }
+        if let SyscallProbes::Fentry(_) = syscall_probes {
+            open_skel.progs.sys_exit.set_autoload(false);
+        }
+        Ok(open_skel.load()?)
+    }
+
+    /// Run metadata about the collector itself, e.g. how it traces syscalls and
+    /// which probes are active.
+    pub fn metadata(&self) -> HashMap<String, String> {
+        HashMap::from([
+            (String::from("collector"), String::from("ebpf")),
+            (
+                String::from("syscall_probes"),
+                self.syscall_probes.to_string(),
+            ),
+            (
+                String::from("probes"),
+                self.probes.iter().cloned().collect::<Vec<_>>().join(","),
+            ),
+        ])
+    }
+
+    /// The labels of the builder and the metadata of the collector, which tag every point.
+    pub fn tags(&self) -> HashMap<String, String> {
+        let mut tags = self.labels.clone();
+        tags.extend(self.metadata());
+        tags
+    }
+
+    pub fn job(&self) -> Option<String> {
+        self.job.clone()
+    }
+
+    /// Attaches the probes the builder configured. Probes that fail to attach are
+    /// skipped with a warning.
+    pub fn start(&mut self) -> Result<()> {
+        let Some(probes) = self.pending.take() else {
+            bail!("The collector already started");
+        };
+        self.started = Instant::now();
+        if probes.set == ProbeSet::Detached {
+            return Ok(());
+        }
+
+        if let Some((path, spans)) = probes.trace {
+            if let Err(e) = self.monitor_trace(path, spans) {
+                warn!("Not tracing: {}", e);
+            }
+        }
+        if let Err(e) = self.monitor_io() {
+            warn!("Not monitoring IO: {}", e);
+        }
+        if let Err(e) = self.monitor_event_loop() {
+            warn!("Not monitoring the event loop: {}", e);
+        }
+        if probes.set != ProbeSet::All {
+            return Ok(());
+        }
+
+        if let Err(e) = self.monitor_sched() {
+            warn!("Not monitoring scheduling: {}", e);
+        }
+        if let Err(e) = self.monitor_perf() {
+            warn!("Not counting perf events: {}", e);
+        }
+        if let Err(e) = self.monitor_crypto() {
+            warn!("Not monitoring crypto: {}", e);
+        }
+        if let Err(e) = self.monitor_udp() {
+            warn!("Not monitoring UDP: {}", e);
+        }
+        if let Err(e) = self.monitor_pacing() {
+            warn!("Not monitoring pacing: {}", e);
+        }
+        if let Some(goodput_bytes) = probes.goodput_bytes {
+            if let Err(e) = self.reconcile_overhead(goodput_bytes) {
+                warn!("Not reporting the protocol overhead: {}", e);
+            }
+        }
+        if let Some(config) = probes.components {
+            if let Err(e) = self.monitor_components(config) {
+                warn!("Not monitoring components: {}", e);
+            }
+        }
+        if let Some((iface, port)) = probes.packets {
+            if let Err(e) = self.monitor_packets(&iface, port) {
+                warn!("Not classifying packets: {}", e);
+            }
+        }
+        if let Some((hz, folded)) = probes.profile {
+            if let Err(e) = self.monitor_profile(hz, folded) {
+                warn!("Not profiling: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records the probe `name` as active and keeps its links until the collector stops.
+    fn activate(&mut self, name: &str, links: Vec<Link>) {
+        self.links.extend(links);
+        self.probes.insert(name.to_string());
+    }
+
+    /// Activates the probe `name`, or skips it with a warning if it failed to attach.
+    fn keep<E: Display>(&mut self, name: &str, attached: Result<Vec<Link>, E>) -> bool {
+        match attached {
+            Ok(links) => {
+                self.activate(name, links);
+                true
+            }
+            Err(e) => {
+                warn!("Skipping {}: {}", name, e);
+                false
+            }
+        }
+    }
+
+    fn keep_link<E: Display>(&mut self, name: &str, attached: Result<Link, E>) -> bool {
+        self.keep(name, attached.map(|link| vec![link]))
+    }
+
+    /// Attach the programs of the I/O syscalls, or of the event loop syscalls if `io` is false.
+    fn attach_syscalls(&mut self, io: bool) -> Vec<(&'static str, Result<Vec<Link>>)> {
+        let mut attached = Vec::new();
+        for (name, tracepoint, fentry, fexit) in syscall_progs!(&mut self.skel.progs) {
+            let idx = SYSCALLS.iter().position(|s| *s == name).unwrap_or_default();
+            if (idx < FIRST_WAIT_SYSCALL) != io {
+                continue;
+            }
+
+            let links = match &self.syscall_probes {
+                SyscallProbes::Fentry(handlers) if !handlers.contains_key(name) => {
+                    Err(anyhow!("No syscall handler"))
+                }
+                SyscallProbes::Fentry(_) => fentry
+                    .attach()
+                    .and_then(|entry| Ok(vec![entry, fexit.attach()?]))
+                    .map_err(Into::into),
+                SyscallProbes::Tracepoint => tracepoint
+                    .attach()
+                    .map(|link| vec![link])
+                    .map_err(Into::into),
+            };
+            attached.push((name, links));
+        }
+        attached
     }
 
     pub fn monitor_io(&mut self) -> Result<()> {
-        info!("Monitoring IO");
-        let read = self.skel.progs.read.attach()?;
-        let write = self.skel.progs.write.attach()?;
-        let writev = self.skel.progs.writev.attach()?;
-        let readv = self.skel.progs.readv.attach()?;
-
-        let recvfrom = self.skel.progs.recvfrom.attach()?;
-        let recvmsg = self.skel.progs.recvmsg.attach()?;
-        let recvmmsg = self.skel.progs.recvmmsg.attach()?;
-        let sendto = self.skel.progs.sendto.attach()?;
-        let sendmsg = self.skel.progs.sendmsg.attach()?;
-        let sendmmsg = self.skel.progs.sendmmsg.attach()?;
-
-        self.links = Some(vec![
-            read, write, writev, readv, recvfrom, recvmsg, recvmmsg, sendto, sendmsg, sendmmsg,
-        ]);
+        info!("Monitoring IO with {} probes", self.syscall_probes);
+        for (name, attached) in self.attach_syscalls(true) {
+            self.keep(&format!("{}:{}", self.syscall_probes, name), attached);
+        }
+        if let SyscallProbes::Tracepoint = self.syscall_probes {
+            let sys_exit = self.skel.progs.sys_exit.attach();
+            if !self.keep_link("tracepoint:sys_exit", sys_exit) {
+                warn!("Syscalls will not be aggregated without sys_exit");
+            }
+        }
 
         let mut builder = libbpf_rs::RingBufferBuilder::new();
-        builder.add(&self.skel.maps.events, |ev| process(ev))?;
+        let trace = self.tracer.as_ref().map(|tracer| tracer.trace.clone());
+        builder.add(&self.skel.maps.events, move |ev| {
+            process(ev, trace.as_deref())
+        })?;
         let ringbuf = builder.build()?;
 
-        tokio::spawn(async move {
-            trace!("Polling...");
-            loop {
-                if let Err(e) = ringbuf.poll(Duration::from_millis(100)) {
-                    error!("Failed to poll ring buffer: {}", e);
+        let (stop, stopped) = oneshot::channel();
+        let handle = tokio::spawn(consume_events(ringbuf, stopped));
+        self.consumer = Some(EventConsumer { stop, handle });
+
+        Ok(())
+    }
+
+    /// Keep the streamed events, and write them as a trace to `path` once the collector
+    /// stops, together with the IUT's `spans`. Must be called before [`Self::monitor_io`],
+    /// which starts to consume the events.
+    pub fn monitor_trace(&mut self, path: PathBuf, spans: Option<PathBuf>) -> Result<()> {
+        if !self.stream_events {
+            bail!("Tracing needs the collector to stream events");
+        }
+        if self.consumer.is_some() {
+            bail!("Tracing must start before the events are consumed");
+        }
+        info!("Tracing events to {}", path.display());
+        self.tracer = Some(Tracer {
+            trace: Arc::new(Mutex::new(Trace::default())),
+            path,
+            spans,
+        });
+
+        Ok(())
+    }
+
+    pub fn monitor_sched(&mut self) -> Result<()> {
+        info!("Monitoring scheduling");
+        let progs = &self.skel.progs;
+        let attached = [
+            ("sched_wakeup", progs.sched_wakeup.attach()),
+            ("sched_wakeup_new", progs.sched_wakeup_new.attach()),
+            ("sched_switch", progs.sched_switch.attach()),
+            ("sched_migrate_task", progs.sched_migrate_task.attach()),
+        ];
+        for (name, link) in attached {
+            self.keep_link(name, link);
+        }
+
+        Ok(())
+    }
+
+    /// The objects to attach uprobes to, with the pid to attach them for. The objects
+    /// the process has not mapped yet are probed in every process, the programs'
+    /// pid guard drops the hits of others.
+    fn probed_objects(&self) -> Result<Vec<(PathBuf, i32)>> {
+        let mapped = symbols::mapped_objects(self.pid)?;
+        let canonical: BTreeSet<PathBuf> = mapped
+            .iter()
+            .filter_map(|p| fs::canonicalize(p).ok())
+            .collect();
+        let unmapped = self
+            .objects
+            .iter()
+            .filter(|p| fs::canonicalize(p).is_ok_and(|p| !canonical.contains(&p)))
+            .map(|p| (p.clone(), -1));
+        Ok(mapped
+            .into_iter()
+            .map(|p| (p, self.pid as i32))
+            .chain(unmapped)
+            .collect())
+    }
+
+    /// Attach uprobe/uretprobe pairs to all functions of [`CRYPTO_PROBES`] found in
+    /// the binary or the shared objects of the monitored process.
+    pub fn monitor_crypto(&mut self) -> Result<()> {
+        info!("Monitoring crypto");
+        let mut links = Vec::new();
+        for (path, pid) in self.probed_objects()? {
+            let symbols = match ElfSymbols::load(&path) {
+                Ok(symbols) => symbols,
+                Err(e) => {
+                    debug!("Skipping {}: {}", path.display(), e);
+                    continue;
                 }
+            };
 
-                tokio::time::sleep(Duration::from_millis(100)).await;
+            for (id, probe) in CRYPTO_PROBES.iter().enumerate() {
+                for symbol in symbols.matching(probe.symbol) {
+                    debug!(
+                        "Attaching {} {} probe to {} in {}",
+                        probe.backend,
+                        probe.op,
+                        symbol.name,
+                        symbols.path.display()
+                    );
+
+                    let progs = [
+                        (&self.skel.progs.crypto_enter, false),
+                        (&self.skel.progs.crypto_exit, true),
+                    ];
+                    for (prog, retprobe) in progs {
+                        let opts = UprobeOpts {
+                            cookie: probe.cookie(id),
+                            retprobe,
+                            ..Default::default()
+                        };
+                        match prog.attach_uprobe_with_opts(
+                            pid,
+                            &symbols.path,
+                            symbol.offset as usize,
+                            opts,
+                        ) {
+                            Ok(link) => links.push(link),
+                            Err(e) => warn!("Skipping crypto probe on {}: {}", symbol.name, e),
+                        }
+                    }
+                }
             }
+        }
+
+        if links.is_empty() {
+            warn!("No crypto functions found in process {}", self.pid);
+        } else {
+            self.activate("crypto", links);
+        }
+
+        Ok(())
+    }
+
+    /// Attach uprobe/uretprobe pairs to every function of the monitored process that
+    /// one of the components in `config` claims, up to `config.max_probes` functions.
+    pub fn monitor_components(&mut self, config: ComponentConfig) -> Result<()> {
+        info!("Monitoring {} components", config.components.len());
+        let mut links = Vec::new();
+        let mut attached = 0;
+        'objects: for (path, pid) in self.probed_objects()? {
+            let symbols = match ElfSymbols::load(&path) {
+                Ok(symbols) => symbols,
+                Err(e) => {
+                    debug!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            for symbol in symbols.iter() {
+                let Some(id) = config.component_of(&symbol.name, &symbol.mangled) else {
+                    continue;
+                };
+                if attached >= config.max_probes {
+                    warn!(
+                        "Reached the limit of {} component probes, ignoring the remaining functions",
+                        config.max_probes
+                    );
+                    break 'objects;
+                }
+
+                trace!(
+                    "Attaching {} probe to {} in {}",
+                    config.components[id].name,
+                    symbol.name,
+                    symbols.path.display()
+                );
+
+                let progs = [
+                    (&self.skel.progs.component_enter, false),
+                    (&self.skel.progs.component_exit, true),
+                ];
+                let probes = progs.map(|(prog, retprobe)| {
+                    let opts = UprobeOpts {
+                        cookie: id as u64,
+                        retprobe,
+                        ..Default::default()
+                    };
+                    prog.attach_uprobe_with_opts(pid, &symbols.path, symbol.offset as usize, opts)
+                });
+                match probes.into_iter().collect::<Result<Vec<_>, _>>() {
+                    Ok(probes) => {
+                        links.extend(probes);
+                        attached += 1;
+                    }
+                    Err(e) => warn!("Skipping component probe on {}: {}", symbol.name, e),
+                }
+            }
+        }
+
+        if attached == 0 {
+            warn!("No component functions found in process {}", self.pid);
+        } else {
+            info!("Attached to {} component functions", attached);
+            self.activate("components", links);
+        }
+        self.components = Some(config);
+
+        Ok(())
+    }
+
+    /// Trace the time the kernel spends in `udp_sendmsg`/`udp_recvmsg` for the monitored process,
+    /// and the packets it drops on the process' sockets.
+    pub fn monitor_udp(&mut self) -> Result<()> {
+        info!("Monitoring the UDP stack");
+        let progs = &self.skel.progs;
+        let pairs = [
+            (
+                "udp_sendmsg",
+                &progs.udp_sendmsg_enter,
+                &progs.udp_sendmsg_exit,
+            ),
+            (
+                "udp_recvmsg",
+                &progs.udp_recvmsg_enter,
+                &progs.udp_recvmsg_exit,
+            ),
+            (
+                "udpv6_sendmsg",
+                &progs.udpv6_sendmsg_enter,
+                &progs.udpv6_sendmsg_exit,
+            ),
+            (
+                "udpv6_recvmsg",
+                &progs.udpv6_recvmsg_enter,
+                &progs.udpv6_recvmsg_exit,
+            ),
+        ]
+        .map(|(name, enter, exit)| {
+            let links = enter
+                .attach()
+                .and_then(|enter| Ok(vec![enter, exit.attach()?]));
+            (name, links)
         });
+        let drops = [
+            ("kfree_skb", progs.kfree_skb.attach()),
+            (
+                "udp_fail_queue_rcv_skb",
+                progs.udp_fail_queue_rcv_skb.attach(),
+            ),
+        ];
+
+        for (name, links) in pairs {
+            match links {
+                // IPv6 may be built as a module that is not loaded
+                Err(e) if name.starts_with("udpv6") => debug!("Not tracing IPv6: {}", e),
+                links => {
+                    self.keep(name, links);
+                }
+            }
+        }
+        for (name, link) in drops {
+            self.keep_link(name, link);
+        }
+
+        let reasons = udp::drop_reasons();
+        if reasons.is_empty() {
+            warn!("Drop reasons will be reported as numbers, tracefs is not available");
+        }
+        self.drop_reasons = Some(reasons);
 
         Ok(())
     }
 
-    /// Push all collected metrics to InfluxDB as a single point-in-time measurement.
-    ///
-    /// Writes two measurement types:
-    /// - `nesquic`: throughput (one point per run)
-    /// - `nesquic_io`: per-syscall stats (one point per syscall type per run)
-    pub async fn push_all(
-        &mut self,
-        url: String,
-        token: String,
-        org: String,
-        bucket: String,
-        job: String,
-        tags: HashMap<String, String>,
-    ) -> Result<()> {
-        // Dropping links flushes the ring buffer
-        self.links.take();
+    /// Trace the syscalls the event loop of the monitored process waits in.
+    /// Relies on [`MetricsCollector::monitor_io`] for `sys_exit` with tracepoints.
+    pub fn monitor_event_loop(&mut self) -> Result<()> {
+        if self.consumer.is_none() {
+            bail!("Event loop metrics require monitoring IO");
+        }
 
-        let timestamp_ns = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        info!("Monitoring the event loop");
+        for (name, attached) in self.attach_syscalls(false) {
+            match attached {
+                // newer than the others, or not available on every architecture or kernel config
+                Err(e) if ["epoll_pwait2", "poll", "io_uring_enter"].contains(&name) => {
+                    debug!("Not tracing {}: {}", name, e)
+                }
+                attached => {
+                    self.keep(&format!("{}:{}", self.syscall_probes, name), attached);
+                }
+            }
+        }
 
-        let mut all_tags = tags;
-        all_tags.insert("job".to_string(), job);
+        Ok(())
+    }
+
+    /// Timestamp every datagram the monitored process sends to analyze its pacing.
+    /// Relies on [`MetricsCollector::monitor_udp`] to learn the process' sockets.
+    pub fn monitor_pacing(&mut self) -> Result<()> {
+        if self.drop_reasons.is_none() {
+            bail!("Pacing analysis requires monitoring the UDP stack");
+        }
+
+        info!("Monitoring pacing");
+        let link = self.skel.progs.net_dev_queue.attach();
+        self.pacing = self.keep_link("net_dev_queue", link);
+
+        Ok(())
+    }
 
-        fn build_tag_str(tags: &HashMap<String, String>) -> String {
-            fn escape_tag(s: &str) -> String {
-                s.replace(',', "\\,")
-                    .replace('=', "\\=")
-                    .replace(' ', "\\ ")
+    /// Compare the UDP bytes of the run with the `goodput_bytes` it transferred, see [`Overhead`].
+    /// Relies on [`MetricsCollector::monitor_udp`] to count the UDP bytes.
+    pub fn reconcile_overhead(&mut self, goodput_bytes: u64) -> Result<()> {
+        if self.drop_reasons.is_none() {
+            bail!("The overhead needs the UDP stack to be monitored");
+        }
+        self.goodput_bytes = Some(goodput_bytes);
+
+        Ok(())
+    }
+
+    /// Classify the QUIC packets to and from `port` on the interface `iface`
+    /// with a tc classifier in both directions.
+    pub fn monitor_packets(&mut self, iface: &str, port: u16) -> Result<()> {
+        info!("Classifying QUIC packets on {} port {}", iface, port);
+        let (ifindex, l2_header_len) = packets::interface(iface)?;
+
+        let Some(data) = self.skel.maps.data_data.as_mut() else {
+            bail!("Failed to access data");
+        };
+        data.QUIC_PORT = port;
+        data.L2_HEADER_LEN = l2_header_len;
+
+        match packets::create_clsact(ifindex) {
+            Ok(true) => {
+                let qdisc = TcHookBuilder::new(self.skel.progs.quic_ingress.as_fd())
+                    .ifindex(ifindex)
+                    .hook(TC_INGRESS | TC_EGRESS);
+                self.tc_qdisc = Some(qdisc);
             }
+            Ok(false) => debug!("The clsact qdisc on {} already exists", iface),
+            Err(e) => warn!("Creating the clsact qdisc on {}: {}", iface, e),
+        }
+
+        let progs = [
+            ("quic_ingress", &self.skel.progs.quic_ingress, TC_INGRESS),
+            ("quic_egress", &self.skel.progs.quic_egress, TC_EGRESS),
+        ];
+        for (name, prog, attach_point) in progs {
+            let mut hook = TcHookBuilder::new(prog.as_fd())
+                .ifindex(ifindex)
+                .handle(TC_HANDLE)
+                .priority(TC_PRIORITY)
+                .hook(attach_point);
+            match hook.attach() {
+                Ok(_) => {
+                    self.tc_hooks.push(hook);
+                    self.probes.insert(name.to_string());
+                }
+                Err(e) => warn!("Skipping {}: {}", name, e),
+            }
+        }
 
-            let mut parts: Vec<String> = tags
+        Ok(())
+    }
+
+    /// Count cycles, instructions, cache and branch misses, page faults and context switches
+    /// of the monitored process, or only the software counters if the hardware ones are missing.
+    pub fn monitor_perf(&mut self) -> Result<()> {
+        info!("Counting perf events");
+        self.perf = Some(PerfCounters::open(self.pid)?);
+
+        Ok(())
+    }
+
+    /// Sample the user and kernel stacks of the monitored process `hz` times per second
+    /// on every CPU. The stacks are written to `folded` once the collector stops.
+    pub fn monitor_profile(&mut self, hz: u64, folded: PathBuf) -> Result<()> {
+        info!("Profiling at {} Hz", hz);
+        let symbolizer = Symbolizer::new(self.pid)?;
+
+        let attr = PerfEventAttr::sampling(hz);
+        let mut links = Vec::new();
+        for cpu in 0..num_possible_cpus()? {
+            let fd = match perf_event_open(&attr, -1, cpu as i32) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    // possible, but offline CPUs
+                    debug!("Not sampling CPU {}: {}", cpu, e);
+                    continue;
+                }
+            };
+            match self.skel.progs.profile.attach_perf_event(fd.as_raw_fd()) {
+                Ok(link) => {
+                    links.push(link);
+                    // the link closes the perf event once it is dropped
+                    _ = fd.into_raw_fd();
+                }
+                Err(e) => warn!("Not sampling CPU {}: {}", cpu, e),
+            }
+        }
+
+        if links.is_empty() {
+            bail!("Failed to sample any CPU");
+        }
+        self.activate("profile", links);
+        self.profiler = Some(Profiler { symbolizer, folded });
+
+        Ok(())
+    }
+
+    /// Detach all programs and wait until the ring buffer is drained.
+    /// Afterwards, the collected metrics no longer change. Calling this
+    /// more than once is a no-op.
+    pub async fn stop(&mut self) {
+        if self.stopped.is_some() {
+            return;
+        }
+        self.stopped = Some(Instant::now());
+
+        self.links.clear();
+        self.detach_tc_hooks();
+
+        if let Some(perf) = &self.perf {
+            if let Err(e) = perf.disable() {
+                error!("Failed to stop perf counters: {}", e);
+            }
+        }
+
+        if let Some(consumer) = self.consumer.take() {
+            _ = consumer.stop.send(());
+            if let Err(e) = consumer.handle.await {
+                error!("Event consumer failed: {}", e);
+            }
+        }
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.symbolizer.refresh();
+            let folded = profile::read_profile(
+                &self.skel.maps.samples,
+                &self.skel.maps.stacks,
+                &profiler.symbolizer,
+            )
+            .and_then(|profile| profile.write_folded(&profiler.folded));
+            match folded {
+                Ok(()) => info!("Folded stacks written to {}", profiler.folded.display()),
+                Err(e) => error!("Failed to write folded stacks: {}", e),
+            }
+        }
+
+        if let Some(tracer) = &self.tracer {
+            let components: Vec<String> = self
+                .components
                 .iter()
-                .filter(|(_, v)| !v.is_empty())
-                .map(|(k, v)| format!("{}={}", escape_tag(k), escape_tag(v)))
+                .flat_map(|config| config.components.iter().map(|c| c.name.clone()))
                 .collect();
-            parts.sort();
-            if parts.is_empty() {
-                String::new()
-            } else {
-                format!(",{}", parts.join(","))
+            let trace = tracer.trace.lock().unwrap();
+            if let Err(e) =
+                trace.write(&tracer.path, self.pid, &components, tracer.spans.as_deref())
+            {
+                error!("Failed to write trace: {:#}", e);
             }
         }
+    }
 
-        let tag_str = build_tag_str(&all_tags);
+    /// Unlike links, tc hooks stay attached to the interface until detached explicitly.
+    fn detach_tc_hooks(&mut self) {
+        for mut hook in self.tc_hooks.drain(..) {
+            if let Err(e) = hook.detach() {
+                error!("Failed to detach tc classifier: {}", e);
+            }
+        }
+        if let Some(mut qdisc) = self.tc_qdisc.take() {
+            if let Err(e) = qdisc.destroy() {
+                error!("Failed to destroy the clsact qdisc: {}", e);
+            }
+        }
+    }
+
+    /// Read the per-CPU maps and merge them into a [`Snapshot`].
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let mut snapshot = Snapshot {
+            syscalls: self.read_syscalls()?,
+            ..Default::default()
+        };
 
-        // Collect data into plain strings before any await (MutexGuard is not Send)
-        let body = collect_line_protocol(&tag_str, timestamp_ns)?;
-        let line_count = body.lines().count();
+        snapshot.duration = self
+            .stopped
+            .unwrap_or_else(Instant::now)
+            .duration_since(self.started);
+        snapshot.event_loop = EventLoopStats::from_syscalls(&snapshot.syscalls, snapshot.duration);
+
+        snapshot.threads = sched::read_threads(&self.skel.maps.threads)?;
+        snapshot.crypto = crypto::read_crypto(&self.skel.maps.crypto)?;
+        if let Some(config) = &self.components {
+            snapshot.components = components::read_components(&self.skel.maps.components, config)?;
+        }
+        snapshot.packets = packets::read_packets(&self.skel.maps.packets)?;
+        if let Some(reasons) = &self.drop_reasons {
+            snapshot.udp = Some(udp::read_udp(
+                &self.skel.maps.udp,
+                &self.skel.maps.skb_drops,
+                reasons,
+            )?);
+        }
+        if let (Some(goodput_bytes), Some(udp)) = (self.goodput_bytes, &snapshot.udp) {
+            snapshot.overhead = Some(Overhead::new(goodput_bytes, udp, &snapshot.crypto));
+        }
+        if self.pacing {
+            snapshot.pacing = Some(pacing::read_pacing(
+                &self.skel.maps.pacing,
+                &self.skel.maps.pacing_sockets,
+                &self.skel.maps.pacing_windows,
+            )?);
+        }
+        if let Some(perf) = &self.perf {
+            snapshot.perf = Some(perf.read()?);
+        }
+        if let Some(profiler) = &self.profiler {
+            snapshot.profile = Some(profile::read_profile(
+                &self.skel.maps.samples,
+                &self.skel.maps.stacks,
+                &profiler.symbolizer,
+            )?);
+        }
 
-        let write_url = format!(
-            "{}/api/v2/write?org={}&bucket={}&precision=ns",
-            url, org, bucket
-        );
+        let key = 0u32.to_ne_bytes();
+        if let Some(per_cpu) = self.skel.maps.dropped.lookup_percpu(&key, MapFlags::ANY)? {
+            snapshot.dropped_events = per_cpu
+                .iter()
+                .filter_map(|v| v[..8].try_into().ok().map(u64::from_ne_bytes))
+                .sum();
+        }
 
-        info!("Pushing {} line(s) to {}", line_count, write_url);
-        for line in body.lines() {
-            debug!("  > {}", line);
+        if snapshot.dropped_events > 0 {
+            warn!("{} events were dropped in-kernel", snapshot.dropped_events);
         }
 
-        let client = Client::new();
-        let resp = client
-            .post(&write_url)
-            .header("Authorization", format!("Token {}", token))
-            .header("Content-Type", "text/plain; charset=utf-8")
-            .body(body)
-            .send()
-            .await
-            .map_err(|e| anyhow::anyhow!("HTTP request failed: {} (kind: {:?})", e, e.url()))?;
+        Ok(snapshot)
+    }
 
-        let status = resp.status();
-        info!("InfluxDB responded with HTTP {}", status);
+    /// Merge the per-CPU syscall statistics.
+    fn read_syscalls(&self) -> Result<HashMap<&'static str, SyscallStats>> {
+        let mut merged: HashMap<&'static str, SyscallStats> = HashMap::new();
+        let syscalls = &self.skel.maps.syscalls;
+        for key in syscalls.keys() {
+            let Some(per_cpu) = syscalls.lookup_percpu(&key, MapFlags::ANY)? else {
+                continue;
+            };
 
-        if !status.is_success() {
-            let body = resp.text().await.unwrap_or_default();
-            bail!("InfluxDB write failed {}: {}", status, body);
+            let idx = u32::from_ne_bytes(key[..4].try_into()?);
+            let Some(syscall) = SYSCALLS.get(idx as usize) else {
+                warn!("Unknown syscall index {} in syscalls map", idx);
+                continue;
+            };
+
+            let stats = merged.entry(*syscall).or_default();
+            for value in per_cpu {
+                let mut raw = types::syscall_stats::default();
+                if plain::copy_from_bytes(&mut raw, &value).is_err() {
+                    bail!("Malformed syscall_stats value for {}", syscall);
+                }
+                stats.add(&raw);
+            }
         }
+        Ok(merged)
+    }
+
+    /// The counters [`interval::IntervalRecorder`] samples: the syscall map, which is
+    /// cheap to read, and the CPU time from `/proc`.
+    pub fn interval_stats(&self) -> Result<IntervalStats> {
+        let syscalls = self
+            .read_syscalls()?
+            .into_iter()
+            .map(|(name, stats)| {
+                let counts = CallCounts {
+                    calls: stats.count,
+                    bytes: stats.bytes,
+                };
+                (name, counts)
+            })
+            .collect();
+        let proc = ProcStats::read(self.pid)?;
+        Ok(IntervalStats {
+            syscalls,
+            cpu_ns: proc.user_ns + proc.system_ns,
+        })
+    }
+
+    /// All collected metrics as a single point-in-time measurement, for the [`sink::MetricsSink`]s.
+    ///
+    /// Contains these measurement types:
+    /// - `nesquic`: throughput (one point per run)
+    /// - `nesquic_io`: per-syscall stats (one point per syscall type per run)
+    /// - `nesquic_perf`: hardware and software perf counters of the process, instructions per byte
+    /// - `nesquic_sched`, `nesquic_cpu`: scheduling and I/O per thread, CPU time of the process
+    /// - `nesquic_crypto`: calls, bytes and time per crypto backend and operation
+    /// - `nesquic_component`: calls and latency per configured component
+    /// - `nesquic_profile`: sampled stacks per crate of the innermost frame
+    /// - `nesquic_udp`, `nesquic_udp_drop`: time in the kernel's UDP stack and packets dropped per reason
+    /// - `nesquic_overhead`: UDP bytes beyond the blob, split into headers and frames, and the reverse path
+    /// - `nesquic_packets`: QUIC packets per direction and header type on the benchmark port
+    /// - `nesquic_pacing`, `nesquic_pacing_rate`: gaps and bursts between sent datagrams, send rate per window
+    /// - `nesquic_eventloop`: wakeups of the event loop and the work it finds per wakeup
+    /// - `nesquic_collector`: health of the collector itself, like dropped events
+    pub async fn points(
+        &mut self,
+        job: Option<String>,
+        tags: HashMap<String, String>,
+    ) -> Result<Vec<Point>> {
+        // Detach the programs so that the snapshot is final
+        self.stop().await;
+        self.live_points(job, tags)
+    }
+
+    /// The metrics collected so far, while the programs may still be attached.
+    pub fn live_points(
+        &self,
+        job: Option<String>,
+        tags: HashMap<String, String>,
+    ) -> Result<Vec<Point>> {
+        let timestamp_ns = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+
+        let mut all_tags = tags;
+        all_tags.extend(job.map(|job| ("job".to_string(), job)));
+        let tag_str = build_tag_str(&all_tags);
 
-        info!("Metrics written to InfluxDB (bucket: {}/{})", org, bucket);
+        let snapshot = self.snapshot()?;
+        Point::parse_all(&collect_line_protocol(&snapshot, &tag_str, timestamp_ns)?)
+    }
+
+    /// Stops the collector and writes its metrics, tagged with [`Self::tags`], to the
+    /// sinks of the builder together with the `extra` points, e.g. of an
+    /// [`interval::IntervalRecorder`]. Without any sink, the metrics are printed.
+    pub async fn finish(&mut self, extra: Vec<Point>) -> Result<()> {
+        if self.sinks.is_empty() {
+            return self.report().await;
+        }
+        let (job, tags) = (self.job(), self.tags());
+        let mut points = self.points(job, tags).await?;
+        points.extend(extra);
+        sink::write_all(&self.sinks, &points).await;
         Ok(())
     }
 
-    pub fn report(&mut self) -> Result<()> {
-        self.links.take();
+    pub async fn report(&mut self) -> Result<()> {
+        self.stop().await;
 
+        println!("syscall probes: {}", self.syscall_probes);
         let throughput_samples = THROUGHPUT_SAMPLES.lock().unwrap();
         if !throughput_samples.is_empty() {
             let mean = throughput_samples.iter().sum::<f64>() / throughput_samples.len() as f64;
@@ -244,17 +1459,173 @@ impl<'obj> MetricsCollector<'obj> {
         }
         drop(throughput_samples);
 
-        let volumes = SYSCALL_VOLUMES.lock().unwrap();
-        for (syscall, data) in volumes.iter() {
-            let count = data.len();
-            let sum = data
+        let snapshot = self.snapshot()?;
+        for (syscall, stats) in snapshot.syscalls.iter() {
+            println!(
+                "{}: count={}, volume_sum={:.3}, size_p50={}, size_p99={}, latency_p50={}ns, latency_p99={}ns",
+                syscall,
+                stats.count,
+                stats.volume_kb(),
+                stats.size.percentile(0.5),
+                stats.size.percentile(0.99),
+                stats.latency.percentile(0.5),
+                stats.latency.percentile(0.99),
+            );
+        }
+        for thread in snapshot.threads.iter() {
+            println!(
+                "{} ({}): oncpu={:.3}ms, runq={:.3}ms, runq_p99={}ns, switches={} ({} involuntary), migrations={}, wakeups={}, io={} calls/{:.3}KB",
+                thread.comm,
+                thread.tid,
+                thread.oncpu_ns as f64 / 1e6,
+                thread.runq_ns as f64 / 1e6,
+                thread.runq_latency.percentile(0.99),
+                thread.context_switches(),
+                thread.involuntary_switches,
+                thread.migrations,
+                thread.wakeups,
+                thread.io_calls,
+                thread.io_bytes as f64 / 1e3,
+            );
+        }
+        if !snapshot.threads.is_empty() {
+            let cpu_seconds = snapshot.cpu_seconds();
+            match cpu_seconds_per_gb(cpu_seconds, snapshot.transferred_bytes()) {
+                Some(per_gb) => println!(
+                    "cpu: {:.3}s ({:.3}s per transferred GB)",
+                    cpu_seconds, per_gb
+                ),
+                None => println!("cpu: {:.3}s", cpu_seconds),
+            }
+            if let Some(share) = top_thread_io_share(&snapshot.threads) {
+                println!(
+                    "io: {:.1}% of the bytes issued by the busiest thread",
+                    share * 100.0
+                );
+            }
+        }
+        for ((backend, op), stats) in snapshot.crypto.iter() {
+            println!(
+                "crypto {} {}: calls={}, bytes={}, time={:.3}ms",
+                backend,
+                op,
+                stats.calls,
+                stats.bytes,
+                stats.time_ns as f64 / 1e6
+            );
+        }
+        for (component, stats) in snapshot.components.iter() {
+            println!(
+                "component {}: calls={}, time={:.3}ms, latency_p50={}ns, latency_p99={}ns",
+                component,
+                stats.calls,
+                stats.time_ns as f64 / 1e6,
+                stats.latency.percentile(0.5),
+                stats.latency.percentile(0.99),
+            );
+        }
+        if let Some(profile) = &snapshot.profile {
+            let mut crates: Vec<(&String, &u64)> = profile.crates.iter().collect();
+            crates.sort_by(|a, b| b.1.cmp(a.1));
+            for (krate, samples) in crates {
+                println!(
+                    "profile {}: samples={}, share={:.1}%",
+                    krate,
+                    samples,
+                    profile.share(krate) * 100.0
+                );
+            }
+        }
+        if let Some(udp) = &snapshot.udp {
+            println!(
+                "udp send: calls={}, bytes={}, time={:.3}ms, errors={}",
+                udp.send_calls,
+                udp.send_bytes,
+                udp.send_ns as f64 / 1e6,
+                udp.send_errors
+            );
+            println!(
+                "udp recv: calls={}, bytes={}, time={:.3}ms, errors={}, rcvbuf_errors={}, queue_errors={}",
+                udp.recv_calls,
+                udp.recv_bytes,
+                udp.recv_ns as f64 / 1e6,
+                udp.recv_errors,
+                udp.rcvbuf_errors,
+                udp.queue_errors
+            );
+            for (reason, count) in udp.drops.iter() {
+                println!("udp drop {}: {}", reason, count);
+            }
+        }
+        if let Some(overhead) = &snapshot.overhead {
+            println!(
+                "overhead {}: goodput={}, udp={} ({:.2}%), headers={} ({:.2}%, {}), frames={} ({:.2}%), reverse={} ({:.2}%)",
+                overhead.direction,
+                overhead.goodput_bytes,
+                overhead.udp_bytes,
+                overhead.percent(overhead.overhead_bytes()),
+                overhead.header_bytes(),
+                overhead.percent(overhead.header_bytes()),
+                overhead.headers(),
+                overhead.frame_bytes(),
+                overhead.percent(overhead.frame_bytes()),
+                overhead.reverse_bytes,
+                overhead.percent(overhead.reverse_bytes),
+            );
+        }
+        for ((direction, packet_type), stats) in snapshot.packets.iter() {
+            println!(
+                "packets {} {}: packets={}, bytes={}, size_p50={}, size_p99={}",
+                direction,
+                packet_type,
+                stats.packets,
+                stats.bytes,
+                stats.size.percentile(0.5),
+                stats.size.percentile(0.99),
+            );
+        }
+        if let Some(pacing) = &snapshot.pacing {
+            println!(
+                "pacing: datagrams={}, gap_p50={}ns, gap_p99={}ns, burst_mean={:.1}, burst_p99={}, rate_p50={:.1}Mbps, rate_p99={:.1}Mbps",
+                pacing.datagrams,
+                pacing.gaps.percentile(0.5),
+                pacing.gaps.percentile(0.99),
+                pacing.mean_burst(),
+                pacing.bursts.percentile(0.99),
+                pacing.rate_percentile_mbps(0.5),
+                pacing.rate_percentile_mbps(0.99),
+            );
+        }
+        if let Some(event_loop) = &snapshot.event_loop {
+            println!(
+                "event loop: wakeups={} ({:.1}/s), empty={}, events_per_wakeup={:.2}, io_batch={:.2}, timer_arms={}",
+                event_loop.wakeups,
+                event_loop.wakeups_per_second,
+                event_loop.empty_wakeups,
+                event_loop.events_per_wakeup,
+                event_loop.io_batch,
+                event_loop.timer_arms,
+            );
+        }
+        if let Some(perf) = &snapshot.perf {
+            let counters: Vec<String> = perf
+                .counters
                 .iter()
-                .fold(Byte::from_u64(0), |acc, v| acc.add(*v).unwrap())
-                .get_adjusted_unit(Unit::KB)
-                .get_value();
-            println!("{}: count={}, volume_sum={:.3}", syscall, count, sum);
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            println!("perf: {}", counters.join(", "));
+            if let Some(per_byte) = perf.instructions_per_byte(snapshot.transferred_bytes()) {
+                println!("instructions per byte: {:.2}", per_byte);
+            }
         }
+        println!("dropped events: {}", snapshot.dropped_events);
 
         Ok(())
     }
 }
+
+impl Drop for MetricsCollector<'_> {
+    fn drop(&mut self) {
+        self.detach_tc_hooks();
+    }
+}
@@ -0,0 +1,202 @@
+use super::{
+    crypto::{CryptoOp, CryptoStats},
+    packets::Direction,
+    udp::UdpStats,
+};
+use std::collections::BTreeMap;
+
+/// Header and AEAD tag of a 1-RTT packet with an 8 byte connection ID and a 2 byte
+/// packet number, for when the crypto probes did not count the payloads
+const PACKET_OVERHEAD_ESTIMATE: u64 = 1 + 8 + 2 + 16;
+
+/// How the UDP bytes of a run add up, compared to the application bytes it transferred.
+/// Encrypted packets do not reveal their frames, so ACK, retransmitted and padding
+/// bytes are only known together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Overhead {
+    /// Application bytes the benchmark requested, i.e. the size of the blob
+    pub goodput_bytes: u64,
+    /// Direction of the blob, the one that carried more UDP bytes
+    pub direction: Direction,
+    /// UDP payload bytes and datagrams in the direction of the blob
+    pub udp_bytes: u64,
+    pub datagrams: u64,
+    /// Bytes the AEAD sealed or opened in the direction of the blob, if the crypto probes counted them
+    pub payload_bytes: Option<u64>,
+    /// UDP payload bytes and datagrams in the reverse direction, mostly ACKs
+    pub reverse_bytes: u64,
+    pub reverse_datagrams: u64,
+}
+
+impl Overhead {
+    pub fn new(
+        goodput_bytes: u64,
+        udp: &UdpStats,
+        crypto: &BTreeMap<(&'static str, CryptoOp), CryptoStats>,
+    ) -> Self {
+        let (direction, op) = if udp.send_bytes >= udp.recv_bytes {
+            (Direction::Egress, CryptoOp::Seal)
+        } else {
+            (Direction::Ingress, CryptoOp::Open)
+        };
+        let payload_bytes: u64 = crypto
+            .iter()
+            .filter(|((_, o), _)| *o == op)
+            .map(|(_, stats)| stats.bytes)
+            .sum();
+        let (udp_bytes, datagrams, reverse_bytes, reverse_datagrams) = match direction {
+            Direction::Egress => (
+                udp.send_bytes,
+                udp.send_calls,
+                udp.recv_bytes,
+                udp.recv_calls,
+            ),
+            Direction::Ingress => (
+                udp.recv_bytes,
+                udp.recv_calls,
+                udp.send_bytes,
+                udp.send_calls,
+            ),
+        };
+        Overhead {
+            goodput_bytes,
+            direction,
+            udp_bytes,
+            datagrams,
+            payload_bytes: (payload_bytes > 0).then_some(payload_bytes),
+            reverse_bytes,
+            reverse_datagrams,
+        }
+    }
+
+    /// UDP bytes in the direction of the blob beyond the blob itself
+    pub fn overhead_bytes(&self) -> u64 {
+        self.udp_bytes.saturating_sub(self.goodput_bytes)
+    }
+
+    /// Packet headers and, depending on the library, AEAD tags, i.e. the UDP bytes the AEAD
+    /// did not process. Estimated per datagram without the crypto probes, which undercounts
+    /// datagrams sent with GSO.
+    pub fn header_bytes(&self) -> u64 {
+        let header_bytes = match self.payload_bytes {
+            Some(payload_bytes) => self.udp_bytes.saturating_sub(payload_bytes),
+            None => self.datagrams * PACKET_OVERHEAD_ESTIMATE,
+        };
+        header_bytes.min(self.overhead_bytes())
+    }
+
+    /// ACK, retransmitted, padding and other frame bytes, i.e. the rest of the overhead
+    pub fn frame_bytes(&self) -> u64 {
+        self.overhead_bytes() - self.header_bytes()
+    }
+
+    /// `bytes` as a percentage of the goodput
+    pub fn percent(&self, bytes: u64) -> f64 {
+        match self.goodput_bytes {
+            0 => 0.0,
+            goodput => bytes as f64 * 100.0 / goodput as f64,
+        }
+    }
+
+    /// Whether the header bytes are measured or estimated
+    pub fn headers(&self) -> &'static str {
+        match self.payload_bytes {
+            Some(_) => "measured",
+            None => "estimated",
+        }
+    }
+
+    pub fn line_protocol(&self, tag_str: &str, timestamp_ns: u128) -> String {
+        format!(
+            "nesquic_overhead{},direction={},headers={} goodput_bytes={}i,udp_bytes={}i,datagrams={}i,overhead_bytes={}i,header_bytes={}i,frame_bytes={}i,reverse_bytes={}i,reverse_datagrams={}i,overhead_pct={},header_pct={},frame_pct={},reverse_pct={} {}",
+            tag_str,
+            self.direction,
+            self.headers(),
+            self.goodput_bytes,
+            self.udp_bytes,
+            self.datagrams,
+            self.overhead_bytes(),
+            self.header_bytes(),
+            self.frame_bytes(),
+            self.reverse_bytes,
+            self.reverse_datagrams,
+            self.percent(self.overhead_bytes()),
+            self.percent(self.header_bytes()),
+            self.percent(self.frame_bytes()),
+            self.percent(self.reverse_bytes),
+            timestamp_ns
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp(send_calls: u64, send_bytes: u64, recv_calls: u64, recv_bytes: u64) -> UdpStats {
+        UdpStats {
+            send_calls,
+            send_bytes,
+            recv_calls,
+            recv_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn measured() {
+        let crypto = BTreeMap::from([
+            (
+                ("ring", CryptoOp::Seal),
+                CryptoStats {
+                    calls: 900,
+                    bytes: 1_080_000,
+                    time_ns: 0,
+                },
+            ),
+            (
+                ("ring", CryptoOp::Open),
+                CryptoStats {
+                    calls: 100,
+                    bytes: 3_000,
+                    time_ns: 0,
+                },
+            ),
+        ]);
+        let overhead = Overhead::new(1_000_000, &udp(900, 1_100_000, 100, 5_000), &crypto);
+
+        assert_eq!(overhead.direction, Direction::Egress);
+        assert_eq!(overhead.payload_bytes, Some(1_080_000));
+        assert_eq!(overhead.overhead_bytes(), 100_000);
+        assert_eq!(overhead.header_bytes(), 20_000);
+        assert_eq!(overhead.frame_bytes(), 80_000);
+        assert_eq!(
+            overhead.line_protocol(",job=quinn", 7),
+            "nesquic_overhead,job=quinn,direction=egress,headers=measured goodput_bytes=1000000i,udp_bytes=1100000i,datagrams=900i,overhead_bytes=100000i,header_bytes=20000i,frame_bytes=80000i,reverse_bytes=5000i,reverse_datagrams=100i,overhead_pct=10,header_pct=2,frame_pct=8,reverse_pct=0.5 7"
+        );
+    }
+
+    #[test]
+    fn estimated() {
+        let overhead = Overhead::new(
+            1_000_000,
+            &udp(100, 5_000, 900, 1_050_000),
+            &BTreeMap::new(),
+        );
+
+        assert_eq!(overhead.direction, Direction::Ingress);
+        assert_eq!(overhead.payload_bytes, None);
+        assert_eq!(overhead.header_bytes(), 900 * PACKET_OVERHEAD_ESTIMATE);
+        assert_eq!(
+            overhead.frame_bytes(),
+            50_000 - 900 * PACKET_OVERHEAD_ESTIMATE
+        );
+        assert_eq!(overhead.reverse_bytes, 5_000);
+
+        // fewer UDP bytes than requested, e.g. an aborted transfer
+        let overhead = Overhead::new(1_000_000, &udp(10, 500, 90, 90_000), &BTreeMap::new());
+        assert_eq!(overhead.overhead_bytes(), 0);
+        assert_eq!(overhead.header_bytes(), 0);
+        assert_eq!(overhead.frame_bytes(), 0);
+    }
+}
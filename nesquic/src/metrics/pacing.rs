@@ -0,0 +1,204 @@
+use super::{hist::Log2Histogram, types};
+use anyhow::{bail, Result};
+use libbpf_rs::{MapCore, MapFlags};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+unsafe impl plain::Plain for types::pacing_stats {}
+unsafe impl plain::Plain for types::pacing_window {}
+unsafe impl plain::Plain for types::pacing_socket {}
+
+/// Must match `PACING_WINDOW_NS` in `metrics.bpf.c`.
+pub const PACING_WINDOW_NS: u64 = 10_000_000;
+
+/// Datagrams and bytes sent within one window of [`PACING_WINDOW_NS`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PacingWindow {
+    /// Start of the window since the Unix epoch
+    pub start_ns: u128,
+    pub datagrams: u64,
+    /// Bytes handed to the device, including headers
+    pub bytes: u64,
+}
+
+impl PacingWindow {
+    pub fn rate_mbps(&self) -> f64 {
+        (self.bytes * 8) as f64 * 1e3 / PACING_WINDOW_NS as f64
+    }
+}
+
+/// How smoothly the monitored process sends its datagrams.
+#[derive(Clone, Debug, Default)]
+pub struct PacingStats {
+    pub datagrams: u64,
+    pub bytes: u64,
+    /// log2 histogram of the gaps between consecutive datagrams of a socket in nanoseconds
+    pub gaps: Log2Histogram,
+    /// log2 histogram of the datagrams per burst
+    pub bursts: Log2Histogram,
+    /// Windows in which anything was sent, in chronological order
+    pub windows: Vec<PacingWindow>,
+}
+
+impl PacingStats {
+    /// Mean number of datagrams sent back-to-back
+    pub fn mean_burst(&self) -> f64 {
+        let bursts = self.bursts.count();
+        if bursts == 0 {
+            return 0.0;
+        }
+        self.datagrams as f64 / bursts as f64
+    }
+
+    /// The `p`-th percentile (0.0..=1.0) of the rate over all windows that were busy.
+    pub fn rate_percentile_mbps(&self, p: f64) -> f64 {
+        let mut rates: Vec<f64> = self.windows.iter().map(|w| w.rate_mbps()).collect();
+        if rates.is_empty() {
+            return 0.0;
+        }
+        rates.sort_by(|a, b| a.total_cmp(b));
+        let rank = ((rates.len() as f64) * p.clamp(0.0, 1.0)).ceil().max(1.0) as usize;
+        rates[rank - 1]
+    }
+
+    pub fn line_protocol(&self, tag_str: &str, timestamp_ns: u128) -> Vec<String> {
+        let mut lines = vec![format!(
+            "nesquic_pacing{} datagrams={}i,bytes={}i,gap_p50_ns={}i,gap_p99_ns={}i,burst_mean={},burst_p99={}i,rate_p50_mbps={},rate_p99_mbps={} {}",
+            tag_str,
+            self.datagrams,
+            self.bytes,
+            self.gaps.percentile(0.5),
+            self.gaps.percentile(0.99),
+            self.mean_burst(),
+            self.bursts.percentile(0.99),
+            self.rate_percentile_mbps(0.5),
+            self.rate_percentile_mbps(0.99),
+            timestamp_ns
+        )];
+
+        for window in self.windows.iter() {
+            lines.push(format!(
+                "nesquic_pacing_rate{} rate_mbps={},datagrams={}i {}",
+                tag_str,
+                window.rate_mbps(),
+                window.datagrams,
+                window.start_ns
+            ));
+        }
+
+        lines
+    }
+}
+
+/// Nanoseconds to add to `CLOCK_MONOTONIC`, i.e. `bpf_ktime_get_ns`, to get the time since the Unix epoch.
+fn monotonic_to_unix_ns() -> Result<u128> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: ts is a valid timespec
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } != 0 {
+        bail!("clock_gettime failed: {}", std::io::Error::last_os_error());
+    }
+    let monotonic_ns = ts.tv_sec as u128 * 1_000_000_000 + ts.tv_nsec as u128;
+    let unix_ns = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    Ok(unix_ns.saturating_sub(monotonic_ns))
+}
+
+/// Read the per-CPU pacing statistics and the per-window send rate.
+pub fn read_pacing(
+    pacing: &impl MapCore,
+    sockets: &impl MapCore,
+    windows: &impl MapCore,
+) -> Result<PacingStats> {
+    let mut stats = PacingStats::default();
+
+    let key = 0u32.to_ne_bytes();
+    if let Some(per_cpu) = pacing.lookup_percpu(&key, MapFlags::ANY)? {
+        for value in per_cpu {
+            let mut raw = types::pacing_stats::default();
+            if plain::copy_from_bytes(&mut raw, &value).is_err() {
+                bail!("Malformed pacing_stats value");
+            }
+            stats.datagrams += raw.datagrams;
+            stats.bytes += raw.bytes;
+            stats.gaps.merge(&Log2Histogram::from_slots(raw.gap_hist));
+            stats
+                .bursts
+                .merge(&Log2Histogram::from_slots(raw.burst_hist));
+        }
+    }
+
+    // the last burst of every socket is still in progress and not in `burst_hist` yet,
+    // as the eBPF program only adds a burst once the next one starts
+    for key in sockets.keys() {
+        let Some(value) = sockets.lookup(&key, MapFlags::ANY)? else {
+            continue;
+        };
+        let mut raw = types::pacing_socket::default();
+        if plain::copy_from_bytes(&mut raw, &value).is_err() {
+            bail!("Malformed pacing_socket value");
+        }
+        stats.bursts.record(raw.burst);
+    }
+
+    let offset_ns = monotonic_to_unix_ns()?;
+    for key in windows.keys() {
+        let Some(value) = windows.lookup(&key, MapFlags::ANY)? else {
+            continue;
+        };
+        let mut raw = types::pacing_window::default();
+        if plain::copy_from_bytes(&mut raw, &value).is_err() {
+            bail!("Malformed pacing_window value");
+        }
+
+        let window = u64::from_ne_bytes(key[..8].try_into()?);
+        stats.windows.push(PacingWindow {
+            start_ns: (window * PACING_WINDOW_NS) as u128 + offset_ns,
+            datagrams: raw.datagrams,
+            bytes: raw.bytes,
+        });
+    }
+    stats.windows.sort_by_key(|w| w.start_ns);
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(bytes: u64) -> PacingWindow {
+        PacingWindow {
+            start_ns: 0,
+            datagrams: 1,
+            bytes,
+        }
+    }
+
+    #[test]
+    fn rates() {
+        // 12500 bytes in 10ms are 10 Mbps
+        assert_eq!(window(12_500).rate_mbps(), 10.0);
+
+        let stats = PacingStats {
+            windows: vec![window(12_500), window(125_000), window(25_000)],
+            ..Default::default()
+        };
+        assert_eq!(stats.rate_percentile_mbps(0.5), 20.0);
+        assert_eq!(stats.rate_percentile_mbps(1.0), 100.0);
+        assert_eq!(PacingStats::default().rate_percentile_mbps(0.5), 0.0);
+    }
+
+    #[test]
+    fn bursts() {
+        let mut stats = PacingStats {
+            datagrams: 10,
+            ..Default::default()
+        };
+        assert_eq!(stats.mean_burst(), 0.0);
+
+        stats.bursts.record(8);
+        stats.bursts.record(2);
+        assert_eq!(stats.mean_burst(), 5.0);
+    }
+}
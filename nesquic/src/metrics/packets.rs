@@ -0,0 +1,189 @@
+use super::{hist::Log2Histogram, types};
+use anyhow::{bail, Context, Result};
+use libbpf_rs::{libbpf_sys, MapCore, MapFlags, TC_EGRESS, TC_INGRESS};
+use std::{collections::BTreeMap, ffi::CString, fmt, fs, io, mem};
+
+unsafe impl plain::Plain for types::packet_stats {}
+
+/// `ARPHRD_NONE`, e.g. TUN devices, whose packets start with the IP header
+const ARPHRD_NONE: u32 = 65534;
+
+/// Must match `DIRECTION_*` in `metrics.bpf.c`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Ingress => write!(f, "ingress"),
+            Direction::Egress => write!(f, "egress"),
+        }
+    }
+}
+
+/// Must match `QUIC_PACKET_*` in `metrics.bpf.c`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PacketType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+    VersionNegotiation,
+    /// Short header, i.e. 1-RTT packet
+    Short,
+}
+
+impl fmt::Display for PacketType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketType::Initial => write!(f, "initial"),
+            PacketType::ZeroRtt => write!(f, "0rtt"),
+            PacketType::Handshake => write!(f, "handshake"),
+            PacketType::Retry => write!(f, "retry"),
+            PacketType::VersionNegotiation => write!(f, "version_negotiation"),
+            PacketType::Short => write!(f, "short"),
+        }
+    }
+}
+
+impl TryFrom<u32> for PacketType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        Ok(match value {
+            0 => PacketType::Initial,
+            1 => PacketType::ZeroRtt,
+            2 => PacketType::Handshake,
+            3 => PacketType::Retry,
+            4 => PacketType::VersionNegotiation,
+            5 => PacketType::Short,
+            _ => bail!("Unknown QUIC packet type {}", value),
+        })
+    }
+}
+
+/// Splits a key of the `packets` map into direction and packet type.
+fn parse_key(key: u32) -> Result<(Direction, PacketType)> {
+    let direction = match key >> 8 {
+        0 => Direction::Ingress,
+        1 => Direction::Egress,
+        d => bail!("Unknown direction {}", d),
+    };
+    Ok((direction, PacketType::try_from(key & 0xFF)?))
+}
+
+/// UDP datagrams on the benchmark port, classified by the header of their first QUIC packet.
+#[derive(Clone, Debug, Default)]
+pub struct PacketStats {
+    pub packets: u64,
+    /// UDP payload bytes
+    pub bytes: u64,
+    /// log2 histogram of the UDP payload size
+    pub size: Log2Histogram,
+}
+
+impl PacketStats {
+    pub fn line_protocol(
+        &self,
+        direction: Direction,
+        packet_type: PacketType,
+        tag_str: &str,
+        timestamp_ns: u128,
+    ) -> String {
+        format!(
+            "nesquic_packets{},direction={},type={} packets={}i,bytes={}i,size_p50={}i,size_p99={}i {}",
+            tag_str,
+            direction,
+            packet_type,
+            self.packets,
+            self.bytes,
+            self.size.percentile(0.5),
+            self.size.percentile(0.99),
+            timestamp_ns
+        )
+    }
+}
+
+/// The interface index of `iface` and the length of its link-layer header.
+pub fn interface(iface: &str) -> Result<(i32, u32)> {
+    let name = CString::new(iface)?;
+    // SAFETY: name is a valid NUL-terminated string
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        bail!("Unknown interface {}", iface);
+    }
+
+    let path = format!("/sys/class/net/{}/type", iface);
+    let link_type: u32 = fs::read_to_string(&path)
+        .with_context(|| format!("read {}", path))?
+        .trim()
+        .parse()?;
+    let l2_header_len = if link_type == ARPHRD_NONE { 0 } else { 14 };
+
+    Ok((ifindex as i32, l2_header_len))
+}
+
+/// Creates the clsact qdisc on `ifindex`, which is false if it already existed.
+pub fn create_clsact(ifindex: i32) -> Result<bool> {
+    let mut hook = libbpf_sys::bpf_tc_hook {
+        sz: mem::size_of::<libbpf_sys::bpf_tc_hook>() as libbpf_sys::size_t,
+        ifindex,
+        attach_point: TC_INGRESS | TC_EGRESS,
+        ..Default::default()
+    };
+    // SAFETY: hook is initialized and its size is set
+    let err = unsafe { libbpf_sys::bpf_tc_hook_create(&mut hook) };
+    match -err {
+        0 => Ok(true),
+        libc::EEXIST => Ok(false),
+        errno => Err(io::Error::from_raw_os_error(errno)).context("create clsact qdisc"),
+    }
+}
+
+/// Read the per-CPU packet statistics, keyed by direction and packet type.
+pub fn read_packets(map: &impl MapCore) -> Result<BTreeMap<(Direction, PacketType), PacketStats>> {
+    let mut packets: BTreeMap<(Direction, PacketType), PacketStats> = BTreeMap::new();
+    for key in map.keys() {
+        let Some(per_cpu) = map.lookup_percpu(&key, MapFlags::ANY)? else {
+            continue;
+        };
+
+        let stats = packets
+            .entry(parse_key(u32::from_ne_bytes(key[..4].try_into()?))?)
+            .or_default();
+        for value in per_cpu {
+            let mut raw = types::packet_stats::default();
+            if plain::copy_from_bytes(&mut raw, &value).is_err() {
+                bail!("Malformed packet_stats value");
+            }
+            stats.packets += raw.packets;
+            stats.bytes += raw.bytes;
+            stats.size.merge(&Log2Histogram::from_slots(raw.size_hist));
+        }
+    }
+
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(0x000, Direction::Ingress, PacketType::Initial)]
+    #[test_case(0x002, Direction::Ingress, PacketType::Handshake)]
+    #[test_case(0x105, Direction::Egress, PacketType::Short)]
+    #[test_case(0x104, Direction::Egress, PacketType::VersionNegotiation)]
+    fn keys(key: u32, direction: Direction, packet_type: PacketType) {
+        assert_eq!(parse_key(key).unwrap(), (direction, packet_type));
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(parse_key(0x006).is_err());
+        assert!(parse_key(0x200).is_err());
+    }
+}
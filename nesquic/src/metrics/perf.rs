@@ -0,0 +1,342 @@
+use anyhow::{bail, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+use tracing::{debug, warn};
+
+pub const PERF_TYPE_HARDWARE: u32 = 0;
+pub const PERF_TYPE_SOFTWARE: u32 = 1;
+
+pub const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+pub const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+pub const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+pub const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+
+pub const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+pub const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+pub const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+pub const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+/// `_IO('$', 1)`
+const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+
+const ATTR_FLAG_INHERIT: u64 = 1 << 1;
+const ATTR_FLAG_FREQ: u64 = 1 << 10;
+
+/// Hardware counters, which are often missing in VMs
+const HARDWARE_COUNTERS: &[(&str, u64)] = &[
+    ("cycles", PERF_COUNT_HW_CPU_CYCLES),
+    ("instructions", PERF_COUNT_HW_INSTRUCTIONS),
+    ("cache_misses", PERF_COUNT_HW_CACHE_MISSES),
+    ("branch_misses", PERF_COUNT_HW_BRANCH_MISSES),
+];
+
+/// Software counters, which the kernel always provides
+const SOFTWARE_COUNTERS: &[(&str, u64)] = &[
+    ("task_clock_ns", PERF_COUNT_SW_TASK_CLOCK),
+    ("page_faults", PERF_COUNT_SW_PAGE_FAULTS),
+    ("context_switches", PERF_COUNT_SW_CONTEXT_SWITCHES),
+];
+
+/// `struct perf_event_attr` up to `sig_data` (`PERF_ATTR_SIZE_VER7`), see `perf_event_open(2)`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PerfEventAttr {
+    pub type_: u32,
+    pub size: u32,
+    pub config: u64,
+    /// `sample_period`, or `sample_freq` if the `freq` flag is set
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub flags: u64,
+    pub wakeup_events: u32,
+    pub bp_type: u32,
+    pub config1: u64,
+    pub config2: u64,
+    pub branch_sample_type: u64,
+    pub sample_regs_user: u64,
+    pub sample_stack_user: u32,
+    pub clockid: i32,
+    pub sample_regs_intr: u64,
+    pub aux_watermark: u32,
+    pub sample_max_stack: u16,
+    pub reserved_2: u16,
+    pub aux_sample_size: u32,
+    pub reserved_3: u32,
+    pub sig_data: u64,
+}
+
+impl PerfEventAttr {
+    pub fn new(type_: u32, config: u64) -> Self {
+        PerfEventAttr {
+            type_,
+            size: mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Counts `config` in a thread and in all threads it spawns from now on, corrected for
+    /// the time the kernel multiplexed the counter with others.
+    pub fn counting(type_: u32, config: u64) -> Self {
+        PerfEventAttr {
+            read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+            flags: ATTR_FLAG_INHERIT,
+            ..Self::new(type_, config)
+        }
+    }
+
+    /// A software clock that overflows `hz` times per second of CPU time,
+    /// e.g. to drive a sampling `perf_event` program.
+    pub fn sampling(hz: u64) -> Self {
+        PerfEventAttr {
+            sample_period: hz,
+            flags: ATTR_FLAG_FREQ,
+            ..Self::new(PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_CLOCK)
+        }
+    }
+}
+
+/// Opens a perf event for the thread `pid` (-1 for all) on `cpu` (-1 for any).
+pub fn perf_event_open(attr: &PerfEventAttr, pid: i32, cpu: i32) -> Result<OwnedFd> {
+    // SAFETY: attr outlives the syscall and its size field matches the struct
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            pid,
+            cpu,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        bail!(
+            "perf_event_open(type={}, config={}, pid={}, cpu={}) failed: {}",
+            attr.type_,
+            attr.config,
+            pid,
+            cpu,
+            io::Error::last_os_error()
+        );
+    }
+
+    // SAFETY: the kernel just handed us this fd
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Extrapolates a counter that was only scheduled for `running` out of `enabled` nanoseconds.
+fn scale(value: u64, enabled: u64, running: u64) -> u64 {
+    if running == 0 {
+        return 0;
+    }
+    if running >= enabled {
+        return value;
+    }
+    (value as u128 * enabled as u128 / running as u128) as u64
+}
+
+/// A counter opened for every thread of the monitored process.
+struct Counter {
+    name: &'static str,
+    fds: Vec<OwnedFd>,
+}
+
+impl Counter {
+    fn open(name: &'static str, type_: u32, config: u64, tids: &[i32]) -> Result<Self> {
+        let attr = PerfEventAttr::counting(type_, config);
+        let fds = tids
+            .iter()
+            .map(|tid| perf_event_open(&attr, *tid, -1))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Counter { name, fds })
+    }
+
+    fn read(&self) -> Result<u64> {
+        let mut total = 0;
+        for fd in self.fds.iter() {
+            // value, time_enabled, time_running
+            let mut buf = [0u8; 24];
+            fs::File::from(fd.try_clone()?).read_exact(&mut buf)?;
+            let [value, enabled, running] =
+                [0, 8, 16].map(|i| u64::from_ne_bytes(buf[i..i + 8].try_into().unwrap()));
+            total += scale(value, enabled, running);
+        }
+        Ok(total)
+    }
+}
+
+/// Hardware and software counters of the monitored process.
+pub struct PerfCounters {
+    counters: Vec<Counter>,
+    hardware: bool,
+}
+
+impl PerfCounters {
+    /// Opens the counters for all current threads of `pid`. Threads spawned later inherit the
+    /// counters, but only add to them once they exit. Falls back to the software counters
+    /// if the hardware ones are not available.
+    pub fn open(pid: u32) -> Result<Self> {
+        let mut tids = Vec::new();
+        for entry in fs::read_dir(format!("/proc/{}/task", pid))? {
+            if let Ok(tid) = entry?.file_name().to_string_lossy().parse() {
+                tids.push(tid);
+            }
+        }
+
+        let mut counters = Vec::new();
+        for (name, config) in SOFTWARE_COUNTERS {
+            counters.push(Counter::open(name, PERF_TYPE_SOFTWARE, *config, &tids)?);
+        }
+
+        let hardware = HARDWARE_COUNTERS
+            .iter()
+            .map(|(name, config)| Counter::open(name, PERF_TYPE_HARDWARE, *config, &tids))
+            .collect::<Result<Vec<_>>>();
+        let hardware = match hardware {
+            Ok(hardware) => {
+                counters.extend(hardware);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "Hardware counters not available, using software counters only: {}",
+                    e
+                );
+                false
+            }
+        };
+        debug!("Counting {} threads", tids.len());
+
+        Ok(PerfCounters { counters, hardware })
+    }
+
+    /// Stops counting, so that later reads return the same values.
+    pub fn disable(&self) -> Result<()> {
+        for fd in self.counters.iter().flat_map(|c| c.fds.iter()) {
+            // SAFETY: fd is an open perf event
+            if unsafe { libc::ioctl(fd.as_raw_fd(), PERF_EVENT_IOC_DISABLE as _, 0) } < 0 {
+                bail!(
+                    "Failed to disable perf event: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(&self) -> Result<PerfStats> {
+        let mut stats = PerfStats {
+            hardware: self.hardware,
+            ..Default::default()
+        };
+        for counter in self.counters.iter() {
+            stats.counters.insert(counter.name, counter.read()?);
+        }
+        Ok(stats)
+    }
+}
+
+/// Values of the [`PerfCounters`], keyed by counter name.
+#[derive(Clone, Debug, Default)]
+pub struct PerfStats {
+    pub counters: BTreeMap<&'static str, u64>,
+    /// Whether the hardware counters were available
+    pub hardware: bool,
+}
+
+impl PerfStats {
+    /// Instructions retired per transferred byte, see [`super::Snapshot::transferred_bytes`].
+    /// `None` without hardware counters or I/O.
+    pub fn instructions_per_byte(&self, bytes: u64) -> Option<f64> {
+        let instructions = self.counters.get("instructions")?;
+        if bytes == 0 {
+            return None;
+        }
+        Some(*instructions as f64 / bytes as f64)
+    }
+
+    /// Instructions per cycle. `None` without hardware counters.
+    pub fn ipc(&self) -> Option<f64> {
+        let cycles = *self.counters.get("cycles")?;
+        if cycles == 0 {
+            return None;
+        }
+        Some(*self.counters.get("instructions")? as f64 / cycles as f64)
+    }
+
+    pub fn line_protocol(&self, tag_str: &str, bytes: u64, timestamp_ns: u128) -> String {
+        let mut fields: Vec<String> = self
+            .counters
+            .iter()
+            .map(|(name, value)| format!("{}={}i", name, value))
+            .collect();
+        if let Some(ipc) = self.ipc() {
+            fields.push(format!("ipc={}", ipc));
+        }
+        if let Some(per_byte) = self.instructions_per_byte(bytes) {
+            fields.push(format!("instructions_per_byte={}", per_byte));
+        }
+
+        let counters = if self.hardware {
+            "hardware"
+        } else {
+            "software"
+        };
+        format!(
+            "nesquic_perf{},counters={} {} {}",
+            tag_str,
+            counters,
+            fields.join(","),
+            timestamp_ns
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attr_layout() {
+        // PERF_ATTR_SIZE_VER7
+        assert_eq!(mem::size_of::<PerfEventAttr>(), 128);
+
+        let attr = PerfEventAttr::sampling(99);
+        assert_eq!(attr.size, 128);
+        assert_eq!(attr.sample_period, 99);
+        assert_eq!(attr.flags, ATTR_FLAG_FREQ);
+    }
+
+    #[test]
+    fn multiplexed_counters() {
+        assert_eq!(scale(100, 10, 10), 100);
+        assert_eq!(scale(100, 10, 5), 200);
+        assert_eq!(scale(100, 10, 0), 0);
+    }
+
+    #[test]
+    fn efficiency() {
+        let mut stats = PerfStats::default();
+        stats.counters.insert("task_clock_ns", 1_000);
+        assert_eq!(stats.instructions_per_byte(100), None);
+        assert_eq!(stats.ipc(), None);
+
+        stats.hardware = true;
+        stats.counters.insert("instructions", 4_000);
+        stats.counters.insert("cycles", 2_000);
+        assert_eq!(stats.instructions_per_byte(100), Some(40.0));
+        assert_eq!(stats.instructions_per_byte(0), None);
+        assert_eq!(stats.ipc(), Some(2.0));
+    }
+}
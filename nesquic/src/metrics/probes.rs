@@ -0,0 +1,129 @@
+use std::{collections::HashMap, fmt, fs, path::Path};
+use tracing::{debug, info};
+
+/// Present if the kernel exposes its own BTF, which fentry/fexit programs need
+const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+
+/// The prefix of the syscall handlers taking a `struct pt_regs`, and the first kernel
+/// with BPF trampolines, on this architecture.
+#[cfg(target_arch = "x86_64")]
+const FENTRY_SUPPORT: Option<(&str, (u32, u32))> = Some(("__x64_sys_", (5, 5)));
+#[cfg(target_arch = "aarch64")]
+const FENTRY_SUPPORT: Option<(&str, (u32, u32))> = Some(("__arm64_sys_", (6, 0)));
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const FENTRY_SUPPORT: Option<(&str, (u32, u32))> = None;
+
+/// How the programs tracing syscalls are attached.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyscallProbes {
+    /// fentry/fexit programs on the syscall handlers, keyed by syscall name
+    Fentry(HashMap<&'static str, String>),
+    /// `syscalls/sys_enter_*` and `raw_syscalls/sys_exit` tracepoints
+    Tracepoint,
+}
+
+impl fmt::Display for SyscallProbes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyscallProbes::Fentry(_) => write!(f, "fentry"),
+            SyscallProbes::Tracepoint => write!(f, "tracepoint"),
+        }
+    }
+}
+
+impl SyscallProbes {
+    /// Picks fentry/fexit programs for `syscalls` if the running kernel supports them.
+    pub fn detect(syscalls: &[&'static str]) -> Self {
+        let Some((prefix, min_version)) = FENTRY_SUPPORT else {
+            info!("Tracing syscalls with tracepoints: no fentry support on this architecture");
+            return SyscallProbes::Tracepoint;
+        };
+        if !Path::new(VMLINUX_BTF).exists() {
+            info!(
+                "Tracing syscalls with tracepoints: {} is missing",
+                VMLINUX_BTF
+            );
+            return SyscallProbes::Tracepoint;
+        }
+
+        let version = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .ok()
+            .and_then(|release| kernel_version(&release));
+        if version.is_none_or(|version| version < min_version) {
+            info!(
+                "Tracing syscalls with tracepoints: kernel {:?} predates fentry",
+                version
+            );
+            return SyscallProbes::Tracepoint;
+        }
+
+        let handlers = fs::read_to_string("/proc/kallsyms")
+            .map(|kallsyms| syscall_handlers(&kallsyms, prefix, syscalls))
+            .unwrap_or_default();
+        if handlers.is_empty() {
+            info!("Tracing syscalls with tracepoints: no {}* handlers", prefix);
+            return SyscallProbes::Tracepoint;
+        }
+
+        info!("Tracing syscalls with fentry/fexit");
+        for syscall in syscalls.iter().filter(|s| !handlers.contains_key(*s)) {
+            debug!("No handler for {}", syscall);
+        }
+        SyscallProbes::Fentry(handlers)
+    }
+}
+
+/// Major and minor version of a kernel release like `6.8.0-45-generic`.
+fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.trim().split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// The handlers of `syscalls` in `/proc/kallsyms`, e.g. `__x64_sys_write` for `write`.
+fn syscall_handlers(
+    kallsyms: &str,
+    prefix: &str,
+    syscalls: &[&'static str],
+) -> HashMap<&'static str, String> {
+    let mut handlers = HashMap::new();
+    for line in kallsyms.lines() {
+        let Some(name) = line.split_whitespace().nth(2) else {
+            continue;
+        };
+        let Some(syscall) = name.strip_prefix(prefix) else {
+            continue;
+        };
+        if let Some(syscall) = syscalls.iter().find(|s| **s == syscall) {
+            handlers.insert(*syscall, name.to_string());
+        }
+    }
+    handlers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("6.8.0-45-generic", Some((6, 8)))]
+    #[test_case("5.15.167.4-microsoft-standard-WSL2", Some((5, 15)))]
+    #[test_case("6.1\n", Some((6, 1)))]
+    #[test_case("linux", None)]
+    fn versions(release: &str, version: Option<(u32, u32)>) {
+        assert_eq!(kernel_version(release), version);
+    }
+
+    #[test]
+    fn handlers() {
+        let kallsyms = "ffffffff81401d10 T __x64_sys_write\n\
+                        ffffffff81401d50 T __ia32_sys_write\n\
+                        ffffffff81401e10 T __x64_sys_writev\n\
+                        ffffffff81b31e10 T __x64_sys_sendmmsg\n";
+        let handlers = syscall_handlers(kallsyms, "__x64_sys_", &["write", "writev", "poll"]);
+        assert_eq!(handlers.len(), 2);
+        assert_eq!(handlers["write"], "__x64_sys_write");
+        assert_eq!(handlers["writev"], "__x64_sys_writev");
+    }
+}
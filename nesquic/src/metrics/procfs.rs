@@ -0,0 +1,332 @@
+use super::{
+    build_tag_str,
+    interval::{CallCounts, IntervalStats},
+    sched::cpu_seconds_per_gb,
+    sink::Point,
+    throughput_line,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+/// Coarse counters of a process that `/proc` and `getrusage` provide without any privileges.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProcStats {
+    /// Bytes passed to read-like syscalls, including files and pipes
+    pub read_bytes: u64,
+    /// Bytes passed to write-like syscalls, including files and pipes
+    pub write_bytes: u64,
+    pub read_syscalls: u64,
+    pub write_syscalls: u64,
+    pub user_ns: u64,
+    pub system_ns: u64,
+    pub minor_faults: u64,
+    pub major_faults: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    pub threads: u64,
+}
+
+impl ProcStats {
+    /// Reads the counters of `pid`, using `getrusage` for the collector's own process.
+    pub fn read(pid: u32) -> Result<Self> {
+        let mut stats = ProcStats::default();
+
+        match fs::read_to_string(format!("/proc/{}/io", pid)) {
+            Ok(io) => stats.parse_io(&io),
+            // needs the same user or CAP_SYS_PTRACE
+            Err(e) => warn!("Cannot read /proc/{}/io: {}", pid, e),
+        }
+
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid))
+            .with_context(|| format!("read /proc/{}/stat", pid))?;
+        // SAFETY: sysconf has no preconditions
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+        stats.parse_stat(&stat, ticks_per_second)?;
+
+        let status = fs::read_to_string(format!("/proc/{}/status", pid))
+            .with_context(|| format!("read /proc/{}/status", pid))?;
+        stats.parse_status(&status);
+
+        if pid == std::process::id() {
+            stats.read_rusage()?;
+        }
+
+        Ok(stats)
+    }
+
+    fn parse_io(&mut self, io: &str) {
+        for line in io.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let Ok(value) = value.trim().parse() else {
+                continue;
+            };
+            match key {
+                "rchar" => self.read_bytes = value,
+                "wchar" => self.write_bytes = value,
+                "syscr" => self.read_syscalls = value,
+                "syscw" => self.write_syscalls = value,
+                _ => {}
+            }
+        }
+    }
+
+    /// Parses `/proc/<pid>/stat`, whose second field is the command name in parentheses,
+    /// which may itself contain spaces and parentheses.
+    fn parse_stat(&mut self, stat: &str, ticks_per_second: u64) -> Result<()> {
+        let Some((_, fields)) = stat.rsplit_once(')') else {
+            bail!("Malformed stat: {}", stat);
+        };
+        // starts with the third field, the state
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        let field = |n: usize| -> Result<u64> {
+            let Some(value) = fields.get(n - 3) else {
+                bail!("stat has no field {}", n);
+            };
+            Ok(value.parse()?)
+        };
+
+        let ns_per_tick = 1_000_000_000 / ticks_per_second;
+        self.minor_faults = field(10)?;
+        self.major_faults = field(12)?;
+        self.user_ns = field(14)? * ns_per_tick;
+        self.system_ns = field(15)? * ns_per_tick;
+        self.threads = field(20)?;
+        Ok(())
+    }
+
+    fn parse_status(&mut self, status: &str) {
+        for line in status.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let Ok(value) = value.trim().parse() else {
+                continue;
+            };
+            match key {
+                "voluntary_ctxt_switches" => self.voluntary_switches = value,
+                "nonvoluntary_ctxt_switches" => self.involuntary_switches = value,
+                _ => {}
+            }
+        }
+    }
+
+    /// Replaces the CPU time and context switches with the more precise ones of `getrusage`.
+    fn read_rusage(&mut self) -> Result<()> {
+        // SAFETY: rusage is plain old data
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        // SAFETY: usage is a valid rusage
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+            bail!("getrusage failed: {}", std::io::Error::last_os_error());
+        }
+
+        let ns = |tv: libc::timeval| tv.tv_sec as u64 * 1_000_000_000 + tv.tv_usec as u64 * 1_000;
+        self.user_ns = ns(usage.ru_utime);
+        self.system_ns = ns(usage.ru_stime);
+        self.voluntary_switches = usage.ru_nvcsw as u64;
+        self.involuntary_switches = usage.ru_nivcsw as u64;
+        Ok(())
+    }
+
+    /// The counters accumulated since `start`, with the current number of threads.
+    pub fn since(&self, start: &ProcStats) -> ProcStats {
+        ProcStats {
+            read_bytes: self.read_bytes.saturating_sub(start.read_bytes),
+            write_bytes: self.write_bytes.saturating_sub(start.write_bytes),
+            read_syscalls: self.read_syscalls.saturating_sub(start.read_syscalls),
+            write_syscalls: self.write_syscalls.saturating_sub(start.write_syscalls),
+            user_ns: self.user_ns.saturating_sub(start.user_ns),
+            system_ns: self.system_ns.saturating_sub(start.system_ns),
+            minor_faults: self.minor_faults.saturating_sub(start.minor_faults),
+            major_faults: self.major_faults.saturating_sub(start.major_faults),
+            voluntary_switches: self
+                .voluntary_switches
+                .saturating_sub(start.voluntary_switches),
+            involuntary_switches: self
+                .involuntary_switches
+                .saturating_sub(start.involuntary_switches),
+            threads: self.threads,
+        }
+    }
+
+    /// The read-like and write-like syscalls and the CPU time, as far as `/proc` tells.
+    pub fn interval_stats(&self) -> IntervalStats {
+        let counts = |calls, bytes| CallCounts { calls, bytes };
+        IntervalStats {
+            syscalls: BTreeMap::from([
+                ("read", counts(self.read_syscalls, self.read_bytes)),
+                ("write", counts(self.write_syscalls, self.write_bytes)),
+            ]),
+            cpu_ns: self.user_ns + self.system_ns,
+        }
+    }
+
+    pub fn cpu_seconds(&self) -> f64 {
+        (self.user_ns + self.system_ns) as f64 / 1e9
+    }
+
+    pub fn line_protocol(&self, tag_str: &str, timestamp_ns: u128) -> String {
+        let per_gb = cpu_seconds_per_gb(self.cpu_seconds(), self.read_bytes.max(self.write_bytes))
+            .map(|v| format!(",cpu_seconds_per_gb={}", v))
+            .unwrap_or_default();
+        format!(
+            "nesquic_proc{} read_bytes={}i,write_bytes={}i,read_syscalls={}i,write_syscalls={}i,user_ns={}i,system_ns={}i,cpu_seconds={}{},minor_faults={}i,major_faults={}i,voluntary_switches={}i,involuntary_switches={}i,threads={}i {}",
+            tag_str,
+            self.read_bytes,
+            self.write_bytes,
+            self.read_syscalls,
+            self.write_syscalls,
+            self.user_ns,
+            self.system_ns,
+            self.cpu_seconds(),
+            per_gb,
+            self.minor_faults,
+            self.major_faults,
+            self.voluntary_switches,
+            self.involuntary_switches,
+            self.threads,
+            timestamp_ns
+        )
+    }
+}
+
+/// Unprivileged fallback of the [`super::MetricsCollector`] for when the eBPF programs
+/// cannot be loaded. Only sees what `/proc` and `getrusage` expose.
+pub struct ProcCollector {
+    pid: u32,
+    start: ProcStats,
+    started: Instant,
+}
+
+impl ProcCollector {
+    pub fn new(pid: u32) -> Result<Self> {
+        Ok(ProcCollector {
+            pid,
+            start: ProcStats::read(pid)?,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn metadata(&self) -> HashMap<String, String> {
+        HashMap::from([(String::from("collector"), String::from("proc"))])
+    }
+
+    /// The counters accumulated since the collector started.
+    pub fn snapshot(&self) -> Result<ProcStats> {
+        Ok(ProcStats::read(self.pid)?.since(&self.start))
+    }
+
+    pub fn interval_stats(&self) -> Result<IntervalStats> {
+        Ok(ProcStats::read(self.pid)?.interval_stats())
+    }
+
+    /// The counters accumulated so far as points for the [`super::sink::MetricsSink`]s.
+    pub fn points(&self, job: Option<String>, tags: HashMap<String, String>) -> Result<Vec<Point>> {
+        let timestamp_ns = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+
+        let mut all_tags = tags;
+        all_tags.extend(job.map(|job| ("job".to_string(), job)));
+        let tag_str = build_tag_str(&all_tags);
+
+        let mut lines = vec![self.snapshot()?.line_protocol(&tag_str, timestamp_ns)];
+        lines.extend(throughput_line(&tag_str, timestamp_ns));
+        Point::parse_all(&lines.join("\n"))
+    }
+
+    pub fn report(&self) -> Result<()> {
+        let stats = self.snapshot()?;
+        println!(
+            "process {} over {:.3}s (from /proc, eBPF unavailable)",
+            self.pid,
+            self.started.elapsed().as_secs_f64()
+        );
+        println!(
+            "io: read={:.3}KB in {} syscalls, write={:.3}KB in {} syscalls",
+            stats.read_bytes as f64 / 1e3,
+            stats.read_syscalls,
+            stats.write_bytes as f64 / 1e3,
+            stats.write_syscalls,
+        );
+        println!(
+            "cpu: {:.3}s (user={:.3}s, system={:.3}s), threads={}",
+            stats.cpu_seconds(),
+            stats.user_ns as f64 / 1e9,
+            stats.system_ns as f64 / 1e9,
+            stats.threads,
+        );
+        println!(
+            "faults: minor={}, major={}, switches: voluntary={}, involuntary={}",
+            stats.minor_faults,
+            stats.major_faults,
+            stats.voluntary_switches,
+            stats.involuntary_switches,
+        );
+        info!("Reported /proc statistics of process {}", self.pid);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_files() {
+        let mut stats = ProcStats::default();
+        stats.parse_io("rchar: 3980\nwchar: 12\nsyscr: 9\nsyscw: 1\nread_bytes: 0\n");
+        stats
+            .parse_stat(
+                "7498 (tokio (rt) x) R 7494 7498 7494 0 -1 4194304 80 0 3 0 25 7 0 0 20 0 4 0 211785",
+                100,
+            )
+            .unwrap();
+        stats.parse_status(
+            "Name:\tcat\nvoluntary_ctxt_switches:\t5\nnonvoluntary_ctxt_switches:\t2\n",
+        );
+
+        assert_eq!(
+            stats,
+            ProcStats {
+                read_bytes: 3980,
+                write_bytes: 12,
+                read_syscalls: 9,
+                write_syscalls: 1,
+                user_ns: 250_000_000,
+                system_ns: 70_000_000,
+                minor_faults: 80,
+                major_faults: 3,
+                voluntary_switches: 5,
+                involuntary_switches: 2,
+                threads: 4,
+            }
+        );
+        assert!(ProcStats::default().parse_stat("7498 cat", 100).is_err());
+    }
+
+    #[test]
+    fn deltas() {
+        let start = ProcStats {
+            read_bytes: 100,
+            user_ns: 1_000,
+            threads: 2,
+            ..Default::default()
+        };
+        let end = ProcStats {
+            read_bytes: 350,
+            user_ns: 4_000,
+            threads: 5,
+            ..Default::default()
+        };
+        let delta = end.since(&start);
+        assert_eq!(delta.read_bytes, 250);
+        assert_eq!(delta.user_ns, 3_000);
+        assert_eq!(delta.threads, 5);
+    }
+}
@@ -0,0 +1,282 @@
+use super::{
+    escape_tag,
+    sched::comm_to_string,
+    symbols::{self, ElfSymbols, KernelSymbols, Mapping},
+    types,
+};
+use anyhow::{bail, Result};
+use libbpf_rs::{MapCore, MapFlags};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, warn};
+
+unsafe impl plain::Plain for types::sample_key {}
+
+/// Resolves sampled instruction pointers of the monitored process and the kernel to function names.
+pub struct Symbolizer {
+    pid: u32,
+    mappings: Vec<Mapping>,
+    /// `None` if the object could not be loaded, so that we try only once
+    objects: HashMap<PathBuf, Option<ElfSymbols>>,
+    kernel: Option<KernelSymbols>,
+}
+
+impl Symbolizer {
+    /// Loads the symbols of everything the process `pid` has mapped so far. The objects are
+    /// loaded eagerly, as the process may be gone by the time the stacks are symbolized.
+    pub fn new(pid: u32) -> Result<Self> {
+        let kernel = match KernelSymbols::load() {
+            Ok(kernel) => Some(kernel),
+            Err(e) => {
+                warn!("Kernel stacks will not be symbolized: {}", e);
+                None
+            }
+        };
+
+        let mut symbolizer = Symbolizer {
+            pid,
+            mappings: Vec::new(),
+            objects: HashMap::new(),
+            kernel,
+        };
+        symbolizer.mappings = symbols::mappings(pid)?;
+        symbolizer.load_objects();
+
+        Ok(symbolizer)
+    }
+
+    /// Picks up objects the process mapped since the last call, if it is still alive.
+    pub fn refresh(&mut self) {
+        if let Ok(mappings) = symbols::mappings(self.pid) {
+            self.mappings = mappings;
+            self.load_objects();
+        }
+    }
+
+    fn load_objects(&mut self) {
+        for mapping in self.mappings.iter() {
+            self.objects.entry(mapping.path.clone()).or_insert_with(|| {
+                match ElfSymbols::load_with_debug_info(&mapping.path) {
+                    Ok(symbols) => Some(symbols),
+                    Err(e) => {
+                        debug!("Cannot symbolize {}: {}", mapping.path.display(), e);
+                        None
+                    }
+                }
+            });
+        }
+    }
+
+    /// The frames at user space address `ip`, innermost first, together with the name of the
+    /// object they belong to.
+    fn user_frames(&self, ip: u64) -> (Vec<String>, String) {
+        let Some((mapping, offset)) = self
+            .mappings
+            .iter()
+            .find_map(|m| m.file_offset(ip).map(|offset| (m, offset)))
+        else {
+            return (vec!["[unknown]".to_string()], "[unknown]".to_string());
+        };
+
+        let object = mapping
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let frames = self
+            .objects
+            .get(&mapping.path)
+            .and_then(|symbols| symbols.as_ref())
+            .map(|symbols| symbols.symbolize(offset))
+            .unwrap_or_default();
+        if frames.is_empty() {
+            return (vec![format!("[{}]", object)], object);
+        }
+
+        (frames, object)
+    }
+
+    fn kernel_frame(&self, ip: u64) -> String {
+        let name = self
+            .kernel
+            .as_ref()
+            .and_then(|kernel| kernel.symbolize(ip))
+            .unwrap_or("[unknown]");
+        format!("{}_[k]", name)
+    }
+}
+
+/// The crate a Rust function belongs to, e.g. `quinn_proto` for
+/// `<quinn_proto::connection::Connection as core::fmt::Debug>::fmt`. C functions are
+/// attributed to the object that contains them, except for the ones bundled by ring.
+pub fn crate_of(function: &str, object: &str) -> String {
+    let path = function.trim_start_matches(['<', '&', '*']);
+    let path = path.strip_prefix("dyn ").unwrap_or(path);
+    let path = path.strip_prefix("mut ").unwrap_or(path);
+    if let Some((krate, _)) = path.split_once("::") {
+        return krate.to_string();
+    }
+
+    if function.starts_with("ring_core_") {
+        return "ring".to_string();
+    }
+
+    object.to_string()
+}
+
+/// Sampled stacks of the monitored process.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    /// Folded stacks, i.e. `thread;outermost;...;innermost`, with their number of samples
+    pub stacks: BTreeMap<String, u64>,
+    /// Samples per crate of the innermost frame, where all kernel frames count as `kernel`
+    pub crates: BTreeMap<String, u64>,
+}
+
+impl Profile {
+    pub fn samples(&self) -> u64 {
+        self.crates.values().sum()
+    }
+
+    /// Share of the samples that ended up in `krate`, 0.0..=1.0
+    pub fn share(&self, krate: &str) -> f64 {
+        let samples = self.samples();
+        if samples == 0 {
+            return 0.0;
+        }
+        self.crates.get(krate).copied().unwrap_or_default() as f64 / samples as f64
+    }
+
+    /// Writes the stacks in the folded format understood by `flamegraph.pl` and `inferno`.
+    pub fn write_folded(&self, path: &Path) -> Result<()> {
+        let mut file = fs::File::create(path)?;
+        for (stack, samples) in self.stacks.iter() {
+            writeln!(file, "{} {}", stack, samples)?;
+        }
+        Ok(())
+    }
+
+    pub fn line_protocol(&self, tag_str: &str, timestamp_ns: u128) -> Vec<String> {
+        self.crates
+            .iter()
+            .map(|(krate, samples)| {
+                format!(
+                    "nesquic_profile{},crate={} samples={}i,share={} {}",
+                    tag_str,
+                    escape_tag(krate),
+                    samples,
+                    self.share(krate),
+                    timestamp_ns
+                )
+            })
+            .collect()
+    }
+}
+
+/// Instruction pointers of the stack `id`, innermost first. Empty for failed stack walks.
+fn read_stack(stacks: &impl MapCore, id: i32) -> Result<Vec<u64>> {
+    if id < 0 {
+        return Ok(Vec::new());
+    }
+
+    let Some(value) = stacks.lookup(&id.to_ne_bytes(), MapFlags::ANY)? else {
+        return Ok(Vec::new());
+    };
+    Ok(value
+        .chunks_exact(8)
+        .map(|ip| u64::from_ne_bytes(ip.try_into().unwrap()))
+        .take_while(|ip| *ip != 0)
+        .collect())
+}
+
+/// Read the sampled stacks and symbolize them.
+pub fn read_profile(
+    samples: &impl MapCore,
+    stacks: &impl MapCore,
+    symbolizer: &Symbolizer,
+) -> Result<Profile> {
+    let mut profile = Profile::default();
+    for key in samples.keys() {
+        let Some(value) = samples.lookup(&key, MapFlags::ANY)? else {
+            continue;
+        };
+
+        let mut raw = types::sample_key::default();
+        if plain::copy_from_bytes(&mut raw, &key).is_err() {
+            bail!("Malformed sample_key");
+        }
+        let count = u64::from_ne_bytes(value[..8].try_into()?);
+
+        let user = read_stack(stacks, raw.user_stack)?;
+        let kernel = read_stack(stacks, raw.kernel_stack)?;
+
+        let mut frames = vec![comm_to_string(&raw.comm)];
+        let mut leaf = None;
+        for (depth, ip) in user.iter().enumerate().rev() {
+            // return addresses point behind the call instruction
+            let ip = if depth == 0 { *ip } else { ip - 1 };
+            let (functions, object) = symbolizer.user_frames(ip);
+            leaf = functions.first().map(|f| crate_of(f, &object));
+            frames.extend(functions.into_iter().rev());
+        }
+        for ip in kernel.iter().rev() {
+            frames.push(symbolizer.kernel_frame(*ip));
+        }
+        if !kernel.is_empty() {
+            leaf = Some("kernel".to_string());
+        }
+
+        *profile.stacks.entry(frames.join(";")).or_default() += count;
+        *profile
+            .crates
+            .entry(leaf.unwrap_or_else(|| "[unknown]".to_string()))
+            .or_default() += count;
+    }
+
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(
+        "quinn_proto::connection::Connection::poll",
+        "nesquic-quinn",
+        "quinn_proto"
+    )]
+    #[test_case(
+        "<quinn_proto::congestion::cubic::Cubic as quinn_proto::congestion::Controller>::on_ack",
+        "nesquic-quinn",
+        "quinn_proto"
+    )]
+    #[test_case(
+        "<&mut rustls::conn::ConnectionCommon<T> as core::fmt::Debug>::fmt",
+        "nesquic-quinn",
+        "rustls"
+    )]
+    #[test_case("<dyn tokio::runtime::park::Park>::park", "nesquic-quinn", "tokio")]
+    #[test_case("ring_core_0_17_14__aesni_gcm_encrypt", "nesquic-quinn", "ring")]
+    #[test_case("__memmove_avx_unaligned_erms", "libc.so.6", "libc.so.6")]
+    fn crates(function: &str, object: &str, krate: &str) {
+        assert_eq!(crate_of(function, object), krate);
+    }
+
+    #[test]
+    fn shares() {
+        let mut profile = Profile::default();
+        profile.crates.insert("kernel".to_string(), 1);
+        profile.crates.insert("quinn_proto".to_string(), 3);
+
+        assert_eq!(profile.samples(), 4);
+        assert_eq!(profile.share("quinn_proto"), 0.75);
+        assert_eq!(profile.share("quiche"), 0.0);
+        assert_eq!(Profile::default().share("kernel"), 0.0);
+    }
+}
@@ -0,0 +1,148 @@
+use super::{escape_tag, hist::Log2Histogram, types};
+use anyhow::{bail, Result};
+use libbpf_rs::{MapCore, MapFlags};
+
+unsafe impl plain::Plain for types::thread_stats {}
+
+/// Scheduling statistics of a single thread of the monitored process.
+#[derive(Clone, Debug, Default)]
+pub struct ThreadStats {
+    pub tid: u32,
+    /// Thread name, e.g. `tokio-runtime-w`
+    pub comm: String,
+    pub oncpu_ns: u64,
+    /// Time spent runnable, but waiting for a CPU
+    pub runq_ns: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    pub migrations: u64,
+    pub wakeups: u64,
+    /// log2 histogram of the run-queue latency in nanoseconds
+    pub runq_latency: Log2Histogram,
+    /// Traced I/O syscalls issued by this thread
+    pub io_calls: u64,
+    pub io_bytes: u64,
+}
+
+/// Turns a NUL-terminated `char[]` from the eBPF programs into a string.
+pub fn comm_to_string<C: Copy + Into<i16>>(comm: &[C]) -> String {
+    let bytes: Vec<u8> = comm
+        .iter()
+        .map(|c| Into::<i16>::into(*c) as u8)
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+impl ThreadStats {
+    fn from_raw(tid: u32, raw: &types::thread_stats) -> Self {
+        ThreadStats {
+            tid,
+            comm: comm_to_string(&raw.comm),
+            oncpu_ns: raw.oncpu_ns,
+            runq_ns: raw.runq_ns,
+            voluntary_switches: raw.voluntary_switches,
+            involuntary_switches: raw.involuntary_switches,
+            migrations: raw.migrations,
+            wakeups: raw.wakeups,
+            runq_latency: Log2Histogram::from_slots(raw.runq_hist),
+            io_calls: raw.io_calls,
+            io_bytes: raw.io_bytes,
+        }
+    }
+
+    pub fn context_switches(&self) -> u64 {
+        self.voluntary_switches + self.involuntary_switches
+    }
+
+    pub fn line_protocol(&self, tag_str: &str, timestamp_ns: u128) -> String {
+        format!(
+            "nesquic_sched{},thread={},tid={} oncpu_ns={}i,runq_ns={}i,runq_p50_ns={}i,runq_p99_ns={}i,voluntary_switches={}i,involuntary_switches={}i,migrations={}i,wakeups={}i,io_calls={}i,io_bytes={}i {}",
+            tag_str,
+            escape_tag(&self.comm),
+            self.tid,
+            self.oncpu_ns,
+            self.runq_ns,
+            self.runq_latency.percentile(0.5),
+            self.runq_latency.percentile(0.99),
+            self.voluntary_switches,
+            self.involuntary_switches,
+            self.migrations,
+            self.wakeups,
+            self.io_calls,
+            self.io_bytes,
+            timestamp_ns
+        )
+    }
+}
+
+/// Read the per-thread scheduling statistics, sorted by thread id.
+pub fn read_threads(map: &impl MapCore) -> Result<Vec<ThreadStats>> {
+    let mut threads = Vec::new();
+    for key in map.keys() {
+        let Some(value) = map.lookup(&key, MapFlags::ANY)? else {
+            continue;
+        };
+
+        let tid = u32::from_ne_bytes(key[..4].try_into()?);
+        let mut raw = types::thread_stats::default();
+        if plain::copy_from_bytes(&mut raw, &value).is_err() {
+            bail!("Malformed thread_stats value for thread {}", tid);
+        }
+        threads.push(ThreadStats::from_raw(tid, &raw));
+    }
+
+    threads.sort_by_key(|t| t.tid);
+    Ok(threads)
+}
+
+/// CPU seconds the process needed per transferred gigabyte. `None` if nothing was transferred.
+pub fn cpu_seconds_per_gb(cpu_seconds: f64, bytes: u64) -> Option<f64> {
+    if bytes == 0 {
+        return None;
+    }
+    Some(cpu_seconds / (bytes as f64 / 1e9))
+}
+
+/// Share of the I/O bytes issued by the busiest thread, 0.0..=1.0. Close to 1.0 if a library
+/// funnels its I/O through a single thread. `None` if no thread did any I/O.
+pub fn top_thread_io_share(threads: &[ThreadStats]) -> Option<f64> {
+    let total: u64 = threads.iter().map(|t| t.io_bytes).sum();
+    if total == 0 {
+        return None;
+    }
+    let top = threads.iter().map(|t| t.io_bytes).max().unwrap_or_default();
+    Some(top as f64 / total as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comm_is_nul_terminated() {
+        let mut comm = [0i8; 16];
+        for (c, b) in comm.iter_mut().zip(b"main".iter()) {
+            *c = *b as i8;
+        }
+        assert_eq!(comm_to_string(&comm), "main");
+        assert_eq!(comm_to_string(&[0u8; 16]), "");
+    }
+
+    #[test]
+    fn cpu_per_gb() {
+        assert_eq!(cpu_seconds_per_gb(2.0, 500_000_000), Some(4.0));
+        assert_eq!(cpu_seconds_per_gb(2.0, 0), None);
+    }
+
+    #[test]
+    fn io_share() {
+        let thread = |io_bytes| ThreadStats {
+            io_bytes,
+            ..Default::default()
+        };
+        assert_eq!(top_thread_io_share(&[thread(300), thread(100)]), Some(0.75));
+        assert_eq!(top_thread_io_share(&[thread(0)]), None);
+        assert_eq!(top_thread_io_share(&[]), None);
+    }
+}
@@ -0,0 +1,119 @@
+use super::sink::{exposition, Point};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, error, info};
+
+const OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// Longest request head that is read, scrapers send far less
+const MAX_HEAD: usize = 8192;
+/// Time a scraper gets to send its request and read the response, so that a stalled
+/// one does not block the next
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the current `points` in the OpenMetrics format at `GET /metrics`,
+/// so that a run can be followed while it is collected.
+pub async fn serve(listener: &TcpListener, points: impl Fn() -> Result<Vec<Point>>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving live metrics at http://{}/metrics", addr);
+    }
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept scrape: {}", e);
+                continue;
+            }
+        };
+        debug!("Scrape from {}", peer);
+        match tokio::time::timeout(SCRAPE_TIMEOUT, respond(stream, &points)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("Failed to answer {}: {}", peer, e),
+            Err(_) => debug!("Gave up on {} after {:?}", peer, SCRAPE_TIMEOUT),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, points: impl Fn() -> Result<Vec<Point>>) -> Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let response = match route(&String::from_utf8_lossy(&head)) {
+        Route::Metrics => match points() {
+            Ok(points) => response(
+                "200 OK",
+                OPENMETRICS,
+                &format!("{}# EOF\n", exposition(&points, &[], false)),
+            ),
+            Err(e) => {
+                error!("Failed to collect live metrics: {}", e);
+                response("500 Internal Server Error", "text/plain", &e.to_string())
+            }
+        },
+        Route::NotFound => response("404 Not Found", "text/plain", "Not found\n"),
+        Route::MethodNotAllowed => response("405 Method Not Allowed", "text/plain", "Only GET\n"),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Metrics,
+    NotFound,
+    MethodNotAllowed,
+}
+
+/// Routes a request by its request line, e.g. `GET /metrics?x=y HTTP/1.1`.
+fn route(head: &str) -> Route {
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Route::NotFound;
+    };
+    if method != "GET" {
+        return Route::MethodNotAllowed;
+    }
+    match target.split('?').next() {
+        Some("/metrics") => Route::Metrics,
+        _ => Route::NotFound,
+    }
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("GET /metrics HTTP/1.1\r\nHost: nesquic\r\n\r\n", Route::Metrics)]
+    #[test_case(
+        "GET /metrics?name[]=nesquic_io_count HTTP/1.1\r\n\r\n",
+        Route::Metrics
+    )]
+    #[test_case("GET / HTTP/1.1\r\n\r\n", Route::NotFound)]
+    #[test_case("POST /metrics HTTP/1.1\r\n\r\n", Route::MethodNotAllowed)]
+    #[test_case("", Route::NotFound)]
+    fn routes(head: &str, expected: Route) {
+        assert_eq!(route(head), expected);
+    }
+}
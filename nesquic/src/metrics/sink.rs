@@ -0,0 +1,557 @@
+use super::{escape_tag, influx::InfluxSink};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use reqwest::Client;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::{error, info};
+
+/// The value of a field of a [`Point`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Int(i64),
+    Float(f64),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Int(v) => write!(f, "{}", v),
+            FieldValue::Float(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// One measurement with its tags and fields. The collectors assemble their
+/// metrics as line protocol, which every [`MetricsSink`] gets as points.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp_ns: u128,
+}
+
+impl Point {
+    /// Parses newline-separated line protocol.
+    pub fn parse_all(body: &str) -> Result<Vec<Point>> {
+        body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.parse())
+            .collect()
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl FromStr for Point {
+    type Err = anyhow::Error;
+
+    /// Parses one line of InfluxDB line protocol with integer and float fields.
+    fn from_str(line: &str) -> Result<Self> {
+        let sections = split_unescaped(line, ' ');
+        let [series, fields, timestamp] = sections[..] else {
+            bail!("Expected series, fields and timestamp: {}", line);
+        };
+
+        let mut series = split_unescaped(series, ',').into_iter();
+        let measurement = unescape(series.next().unwrap_or_default());
+        if measurement.is_empty() {
+            bail!("Missing measurement: {}", line);
+        }
+        let tags = series
+            .map(|tag| match split_unescaped(tag, '=')[..] {
+                [k, v] => Ok((unescape(k), unescape(v))),
+                _ => Err(anyhow!("Malformed tag {}", tag)),
+            })
+            .collect::<Result<_>>()?;
+
+        let fields = split_unescaped(fields, ',')
+            .into_iter()
+            .map(|field| {
+                let [k, v] = split_unescaped(field, '=')[..] else {
+                    bail!("Malformed field {}", field);
+                };
+                let value = match v.strip_suffix('i') {
+                    Some(int) => FieldValue::Int(int.parse()?),
+                    None => FieldValue::Float(v.parse()?),
+                };
+                Ok((unescape(k), value))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Point {
+            measurement,
+            tags,
+            fields,
+            timestamp_ns: timestamp
+                .parse()
+                .with_context(|| format!("timestamp {}", timestamp))?,
+        })
+    }
+}
+
+impl fmt::Display for Point {
+    /// Formats the point as one line of line protocol.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.measurement.replace(',', "\\,").replace(' ', "\\ ")
+        )?;
+        for (k, v) in &self.tags {
+            write!(f, ",{}={}", escape_tag(k), escape_tag(v))?;
+        }
+        for (i, (k, v)) in self.fields.iter().enumerate() {
+            let sep = if i == 0 { ' ' } else { ',' };
+            match v {
+                FieldValue::Int(v) => write!(f, "{}{}={}i", sep, escape_tag(k), v)?,
+                FieldValue::Float(v) => write!(f, "{}{}={}", sep, escape_tag(k), v)?,
+            }
+        }
+        write!(f, " {}", self.timestamp_ns)
+    }
+}
+
+/// Splits `s` at every `sep` that is not escaped with a backslash.
+fn split_unescaped(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == sep => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("\\,", ",")
+        .replace("\\=", "=")
+        .replace("\\ ", " ")
+}
+
+/// A destination for the collected metrics.
+#[async_trait]
+pub trait MetricsSink: Send + Sync {
+    /// Where the points go, for logging
+    fn describe(&self) -> String;
+
+    async fn write(&self, points: &[Point]) -> Result<()>;
+}
+
+/// Writes `points` to every sink. A failing sink does not keep the others from
+/// receiving the points.
+pub async fn write_all(sinks: &[Box<dyn MetricsSink>], points: &[Point]) {
+    for sink in sinks {
+        info!("Writing {} point(s) to {}", points.len(), sink.describe());
+        if let Err(e) = sink.write(points).await {
+            error!("Error writing metrics to {}: {:#}", sink.describe(), e);
+        }
+    }
+}
+
+/// Parses a sink given as `influx`, `jsonl:PATH`, `csv:PATH`, `openmetrics:PATH`
+/// or `pushgateway:URL`. InfluxDB batches that cannot be written go to `spool`.
+pub fn parse_sink(spec: &str, spool: &Path) -> Result<Box<dyn MetricsSink>> {
+    let (kind, target) = match spec.split_once(':') {
+        Some((kind, target)) => (kind, Some(target)),
+        None => (spec, None),
+    };
+    let sink: Box<dyn MetricsSink> = match (kind, target) {
+        ("influx", None) => match InfluxSink::from_env(spool.to_path_buf()) {
+            Some(influx) => Box::new(influx),
+            None => bail!(
+                "The influx sink needs INFLUX_URL, INFLUX_TOKEN, INFLUX_ORG and INFLUX_BUCKET"
+            ),
+        },
+        ("jsonl", Some(path)) => Box::new(JsonLinesSink(path.into())),
+        ("csv", Some(path)) => Box::new(CsvSink(path.into())),
+        ("openmetrics", Some(path)) => Box::new(OpenMetricsSink(path.into())),
+        ("pushgateway", Some(url)) => Box::new(PushgatewaySink(url.trim_end_matches('/').into())),
+        _ => bail!("Unknown sink {}", spec),
+    };
+    Ok(sink)
+}
+
+/// Parses the sinks given as `specs`, see [`parse_sink`]. Without any, a `job` goes to
+/// InfluxDB if it is configured.
+pub fn parse_sinks(specs: &[String], job: bool, spool: &Path) -> Result<Vec<Box<dyn MetricsSink>>> {
+    let mut sinks = specs
+        .iter()
+        .map(|spec| parse_sink(spec, spool))
+        .collect::<Result<Vec<_>>>()?;
+    if sinks.is_empty() && job {
+        if let Some(influx) = InfluxSink::from_env(spool.to_path_buf()) {
+            sinks.push(Box::new(influx));
+        }
+    }
+    Ok(sinks)
+}
+
+/// Appends one JSON object per point to a file.
+pub struct JsonLinesSink(PathBuf);
+
+#[async_trait]
+impl MetricsSink for JsonLinesSink {
+    fn describe(&self) -> String {
+        format!("JSON Lines file {}", self.0.display())
+    }
+
+    async fn write(&self, points: &[Point]) -> Result<()> {
+        let mut body = String::new();
+        for point in points {
+            let tags: Map<String, Value> = point
+                .tags
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::from(v.as_str())))
+                .collect();
+            let fields: Map<String, Value> = point
+                .fields
+                .iter()
+                .map(|(k, v)| {
+                    let value = match v {
+                        FieldValue::Int(v) => Value::from(*v),
+                        FieldValue::Float(v) => Value::from(*v),
+                    };
+                    (k.clone(), value)
+                })
+                .collect();
+            let line = json!({
+                "measurement": point.measurement,
+                "tags": tags,
+                "fields": fields,
+                "timestamp_ns": point.timestamp_ns as u64,
+            });
+            body.push_str(&format!("{}\n", line));
+        }
+        append(&self.0, &body)
+    }
+}
+
+/// Appends one row per field to a CSV file, so that all measurements share
+/// the same columns.
+pub struct CsvSink(PathBuf);
+
+const CSV_HEADER: &str = "timestamp_ns,measurement,tags,field,value\n";
+
+#[async_trait]
+impl MetricsSink for CsvSink {
+    fn describe(&self) -> String {
+        format!("CSV file {}", self.0.display())
+    }
+
+    async fn write(&self, points: &[Point]) -> Result<()> {
+        let is_empty = fs::metadata(&self.0).map(|m| m.len() == 0).unwrap_or(true);
+        let mut body = if is_empty {
+            String::from(CSV_HEADER)
+        } else {
+            String::new()
+        };
+        body.push_str(&csv_rows(points));
+        append(&self.0, &body)
+    }
+}
+
+fn csv_rows(points: &[Point]) -> String {
+    let mut rows = String::new();
+    for point in points {
+        let tags = point
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(";");
+        for (field, value) in &point.fields {
+            rows.push_str(&format!(
+                "{},{},{},{},{}\n",
+                point.timestamp_ns,
+                csv_escape(&point.measurement),
+                csv_escape(&tags),
+                csv_escape(field),
+                value
+            ));
+        }
+    }
+    rows
+}
+
+/// Quotes a CSV cell if it contains a separator, quote or line break.
+fn csv_escape(cell: &str) -> String {
+    if cell.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+fn append(path: &Path, body: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open {}", path.display()))?;
+    file.write_all(body.as_bytes())?;
+    Ok(())
+}
+
+/// Writes the points as an OpenMetrics text exposition, replacing the file.
+pub struct OpenMetricsSink(PathBuf);
+
+#[async_trait]
+impl MetricsSink for OpenMetricsSink {
+    fn describe(&self) -> String {
+        format!("OpenMetrics file {}", self.0.display())
+    }
+
+    async fn write(&self, points: &[Point]) -> Result<()> {
+        let body = format!("{}# EOF\n", exposition(points, &[], true));
+        fs::write(&self.0, body).with_context(|| format!("write {}", self.0.display()))
+    }
+}
+
+/// Pushes the points to a Prometheus Pushgateway, grouped by their `job` tag.
+pub struct PushgatewaySink(String);
+
+#[async_trait]
+impl MetricsSink for PushgatewaySink {
+    fn describe(&self) -> String {
+        format!("Pushgateway at {}", self.0)
+    }
+
+    async fn write(&self, points: &[Point]) -> Result<()> {
+        let job = points
+            .iter()
+            .find_map(|p| p.tag("job"))
+            .unwrap_or("nesquic");
+        let body = pushgateway_body(points);
+        let url = format!("{}{}", self.0, job_path(job));
+
+        let resp = Client::new()
+            .post(&url)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| anyhow!("HTTP request failed: {} (kind: {:?})", e, e.url()))?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            bail!("Pushgateway push failed {}: {}", status, body);
+        }
+        Ok(())
+    }
+}
+
+/// The points as the Pushgateway takes them. It rejects timestamps and sets the grouping
+/// key itself. Without timestamps, the samples of a time series, e.g. the intervals,
+/// would repeat the same series, which it rejects as well, so only the latest one is kept.
+fn pushgateway_body(points: &[Point]) -> String {
+    let mut latest: Vec<&Point> = Vec::new();
+    for point in points {
+        let same_series = latest
+            .iter_mut()
+            .find(|p| p.measurement == point.measurement && p.tags == point.tags);
+        match same_series {
+            Some(p) if p.timestamp_ns <= point.timestamp_ns => *p = point,
+            Some(_) => {}
+            None => latest.push(point),
+        }
+    }
+    let latest: Vec<Point> = latest.into_iter().cloned().collect();
+    exposition(&latest, &["job"], false)
+}
+
+/// The Pushgateway path of the grouping key `job`, base64 encoded, as the
+/// Pushgateway would split a value with a `/` even if it was percent-encoded.
+fn job_path(job: &str) -> String {
+    let encoded = match job {
+        // an empty value has no base64 encoding
+        "" => String::from("="),
+        job => URL_SAFE.encode(job),
+    };
+    format!("/metrics/job@base64/{}", encoded)
+}
+
+/// Renders the points in the Prometheus text format, one gauge per field named
+/// `<measurement>_<field>`, without the labels in `skip_labels`.
+pub fn exposition(points: &[Point], skip_labels: &[&str], timestamps: bool) -> String {
+    let mut families: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for point in points {
+        let labels = point
+            .tags
+            .iter()
+            .filter(|(k, _)| !skip_labels.contains(&k.as_str()))
+            .map(|(k, v)| format!("{}=\"{}\"", metric_name(k), escape_label(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let labels = if labels.is_empty() {
+            labels
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let timestamp = if timestamps {
+            format!(
+                " {}.{:09}",
+                point.timestamp_ns / 1_000_000_000,
+                point.timestamp_ns % 1_000_000_000
+            )
+        } else {
+            String::new()
+        };
+
+        for (field, value) in &point.fields {
+            let name = metric_name(&format!("{}_{}", point.measurement, field));
+            let value = match value {
+                FieldValue::Float(v) if v.is_nan() => String::from("NaN"),
+                FieldValue::Float(v) if v.is_infinite() => {
+                    String::from(if *v > 0.0 { "+Inf" } else { "-Inf" })
+                }
+                v => v.to_string(),
+            };
+            families
+                .entry(name.clone())
+                .or_default()
+                .push(format!("{}{} {}{}", name, labels, value, timestamp));
+        }
+    }
+
+    let mut body = String::new();
+    for (name, samples) in families {
+        body.push_str(&format!("# TYPE {} gauge\n", name));
+        for sample in samples {
+            body.push_str(&sample);
+            body.push('\n');
+        }
+    }
+    body
+}
+
+/// Replaces the characters that metric and label names cannot contain.
+fn metric_name(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const LINES: &str = "nesquic_io,job=quinn,run=a\\ b\\,c,syscall=sendmsg volume_kb_sum=1.5,count=3i,size_p50=512i 1700000000123456789\n\
+                         nesquic,job=quinn throughput=812.25 1700000000123456789\n";
+
+    #[test]
+    fn line_protocol_roundtrip() {
+        let points = Point::parse_all(LINES).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].measurement, "nesquic_io");
+        assert_eq!(points[0].tag("run"), Some("a b,c"));
+        assert_eq!(
+            points[0].fields,
+            vec![
+                (String::from("volume_kb_sum"), FieldValue::Float(1.5)),
+                (String::from("count"), FieldValue::Int(3)),
+                (String::from("size_p50"), FieldValue::Int(512)),
+            ]
+        );
+
+        let formatted: Vec<String> = points.iter().map(|p| p.to_string()).collect();
+        assert_eq!(formatted, LINES.lines().collect::<Vec<_>>());
+        assert!("nesquic_io count=3i".parse::<Point>().is_err());
+        assert!("nesquic_io count=3x 1".parse::<Point>().is_err());
+    }
+
+    #[test]
+    fn openmetrics() {
+        let points = Point::parse_all(LINES).unwrap();
+        assert_eq!(
+            exposition(&points, &["job"], true),
+            "# TYPE nesquic_io_count gauge\n\
+             nesquic_io_count{run=\"a b,c\",syscall=\"sendmsg\"} 3 1700000000.123456789\n\
+             # TYPE nesquic_io_size_p50 gauge\n\
+             nesquic_io_size_p50{run=\"a b,c\",syscall=\"sendmsg\"} 512 1700000000.123456789\n\
+             # TYPE nesquic_io_volume_kb_sum gauge\n\
+             nesquic_io_volume_kb_sum{run=\"a b,c\",syscall=\"sendmsg\"} 1.5 1700000000.123456789\n\
+             # TYPE nesquic_throughput gauge\n\
+             nesquic_throughput 812.25 1700000000.123456789\n"
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let points = Point::parse_all(LINES).unwrap();
+        let rows = csv_rows(&points[1..]);
+        assert_eq!(
+            rows,
+            "1700000000123456789,nesquic,job=quinn,throughput,812.25\n"
+        );
+        assert_eq!(
+            csv_rows(&points[..1]).lines().next(),
+            Some("1700000000123456789,nesquic_io,\"job=quinn;run=a b,c;syscall=sendmsg\",volume_kb_sum,1.5")
+        );
+    }
+
+    #[test_case("quinn", "/metrics/job@base64/cXVpbm4=")]
+    #[test_case("neqo/1.0?", "/metrics/job@base64/bmVxby8xLjA_")]
+    #[test_case("", "/metrics/job@base64/=")]
+    fn job_paths(job: &str, path: &str) {
+        assert_eq!(job_path(job), path);
+    }
+
+    #[test]
+    fn pushgateway_intervals() {
+        let lines = "nesquic_interval,job=quinn syscalls=3i,send_mbps=2 100\n\
+                     nesquic_interval_io,job=quinn,syscall=sendmsg calls=3i 100\n\
+                     nesquic_interval,job=quinn syscalls=5i,send_mbps=4 200\n\
+                     nesquic_interval_io,job=quinn,syscall=sendmsg calls=5i 200\n\
+                     nesquic,job=quinn throughput=812.25 300\n";
+        let points = Point::parse_all(lines).unwrap();
+        assert_eq!(
+            pushgateway_body(&points),
+            "# TYPE nesquic_interval_io_calls gauge\n\
+             nesquic_interval_io_calls{syscall=\"sendmsg\"} 5\n\
+             # TYPE nesquic_interval_send_mbps gauge\n\
+             nesquic_interval_send_mbps 4\n\
+             # TYPE nesquic_interval_syscalls gauge\n\
+             nesquic_interval_syscalls 5\n\
+             # TYPE nesquic_throughput gauge\n\
+             nesquic_throughput 812.25\n"
+        );
+    }
+}
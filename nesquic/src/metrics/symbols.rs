@@ -0,0 +1,437 @@
+use anyhow::{bail, Context, Result};
+use object::{Object, ObjectSection, ObjectSegment, ObjectSymbol, SymbolKind};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
+use tracing::warn;
+
+type DebugInfo = addr2line::Context<gimli::EndianArcSlice<gimli::RunTimeEndian>>;
+
+/// A function symbol of an ELF object.
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    /// The symbol as stored in the object
+    pub mangled: String,
+    /// The demangled name without the trailing hash, e.g. `quinn_proto::connection::Connection::poll`
+    pub name: String,
+    /// Virtual address as stored in the object
+    pub address: u64,
+    /// Size of the function in bytes, 0 if unknown
+    pub size: u64,
+    /// Offset of the function in the file, as expected by uprobes
+    pub offset: u64,
+}
+
+/// A loadable segment of an ELF object.
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    address: u64,
+    end: u64,
+    file_offset: u64,
+    file_size: u64,
+}
+
+/// The function symbols of an ELF object, sorted by address.
+pub struct ElfSymbols {
+    pub path: PathBuf,
+    symbols: Vec<Symbol>,
+    segments: Vec<Segment>,
+    debug_info: Option<DebugInfo>,
+}
+
+impl ElfSymbols {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(path, false)
+    }
+
+    /// Like [`ElfSymbols::load`], but also reads the DWARF debug info to resolve inlined functions.
+    pub fn load_with_debug_info(path: &Path) -> Result<Self> {
+        Self::parse(path, true)
+    }
+
+    fn parse(path: &Path, with_debug_info: bool) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        let file =
+            object::File::parse(&*data).with_context(|| format!("parse {}", path.display()))?;
+
+        let segments: Vec<Segment> = file
+            .segments()
+            .map(|s| Segment {
+                address: s.address(),
+                end: s.address() + s.size(),
+                file_offset: s.file_range().0,
+                file_size: s.file_range().1,
+            })
+            .collect();
+
+        let mut symbols: Vec<Symbol> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|s| s.kind() == SymbolKind::Text && s.address() != 0 && !s.is_undefined())
+            .filter_map(|s| {
+                let mangled = s.name().ok()?.to_string();
+                let address = s.address();
+                let segment = segments
+                    .iter()
+                    .find(|seg| (seg.address..seg.end).contains(&address))?;
+                Some(Symbol {
+                    name: demangle(&mangled),
+                    mangled,
+                    address,
+                    size: s.size(),
+                    offset: address - segment.address + segment.file_offset,
+                })
+            })
+            .collect();
+
+        symbols.sort_by_key(|s| s.address);
+        symbols.dedup_by(|a, b| a.address == b.address && a.mangled == b.mangled);
+
+        let debug_info = if with_debug_info {
+            match load_debug_info(&file) {
+                Ok(debug_info) => Some(debug_info),
+                Err(e) => {
+                    tracing::debug!("No debug info in {}: {}", path.display(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(ElfSymbols {
+            path: path.to_path_buf(),
+            symbols,
+            segments,
+            debug_info,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// The virtual address that is stored at `offset` in the file.
+    fn address_of(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|seg| (seg.file_offset..seg.file_offset + seg.file_size).contains(&offset))
+            .map(|seg| offset - seg.file_offset + seg.address)
+    }
+
+    /// The function symbol that contains the virtual address `address`.
+    fn containing(&self, address: u64) -> Option<&Symbol> {
+        let idx = self.symbols.partition_point(|s| s.address <= address);
+        let symbol = self.symbols.get(idx.checked_sub(1)?)?;
+        (symbol.size == 0 || address < symbol.address + symbol.size).then_some(symbol)
+    }
+
+    /// The demangled functions at file offset `offset`, innermost inlined function first.
+    /// Falls back to the symbol table if there is no debug info. Empty if nothing is known.
+    pub fn symbolize(&self, offset: u64) -> Vec<String> {
+        let Some(address) = self.address_of(offset) else {
+            return Vec::new();
+        };
+
+        let mut functions = Vec::new();
+        if let Some(debug_info) = &self.debug_info {
+            if let Ok(mut frames) = debug_info.find_frames(address).skip_all_loads() {
+                while let Ok(Some(frame)) = frames.next() {
+                    if let Some(name) = frame.function.as_ref().and_then(|f| f.raw_name().ok()) {
+                        functions.push(demangle(&name));
+                    }
+                }
+            }
+        }
+
+        if functions.is_empty() {
+            if let Some(symbol) = self.containing(address) {
+                functions.push(symbol.name.clone());
+            }
+        }
+
+        functions
+    }
+
+    /// All symbols whose demangled or mangled name matches `pattern`, see [`wildcard_match`].
+    pub fn matching<'a>(&'a self, pattern: &'a str) -> impl Iterator<Item = &'a Symbol> + 'a {
+        self.symbols.iter().filter(move |s| {
+            wildcard_match(pattern, &s.name) || wildcard_match(pattern, &s.mangled)
+        })
+    }
+}
+
+fn load_debug_info(file: &object::File) -> Result<DebugInfo> {
+    let endian = if file.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    };
+    let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+        let data = file
+            .section_by_name(id.name())
+            .and_then(|s| s.uncompressed_data().ok())
+            .unwrap_or_default();
+        Ok(gimli::EndianArcSlice::new(Arc::from(&*data), endian))
+    })?;
+    Ok(addr2line::Context::from_dwarf(dwarf)?)
+}
+
+/// Demangle Rust symbols and strip their hash; other symbols are returned unchanged.
+pub fn demangle(name: &str) -> String {
+    format!("{:#}", rustc_demangle::demangle(name))
+}
+
+/// Matches `name` against `pattern`, where `*` stands for any (possibly empty) sequence of characters.
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(first) = parts.next() else {
+        return name.is_empty();
+    };
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no wildcard at all
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// An executable file-backed mapping of a process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    /// File offset of `start`
+    pub offset: u64,
+    /// Points into `/proc/<pid>/root`, so it also resolves for processes in containers
+    pub path: PathBuf,
+}
+
+impl Mapping {
+    /// The file offset of the runtime address `address`, if it lies within this mapping.
+    pub fn file_offset(&self, address: u64) -> Option<u64> {
+        (self.start..self.end)
+            .contains(&address)
+            .then(|| address - self.start + self.offset)
+    }
+}
+
+fn parse_mapping(pid: u32, line: &str) -> Option<Mapping> {
+    // address perms offset dev inode path
+    let mut fields = line.split_whitespace();
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?;
+    let offset = fields.next()?;
+    let path = fields.nth(2)?;
+    if !perms.contains('x') || !path.starts_with('/') {
+        return None;
+    }
+
+    Some(Mapping {
+        start: u64::from_str_radix(start, 16).ok()?,
+        end: u64::from_str_radix(end, 16).ok()?,
+        offset: u64::from_str_radix(offset, 16).ok()?,
+        path: PathBuf::from(format!("/proc/{}/root{}", pid, path)),
+    })
+}
+
+/// Executable file-backed mappings of the process `pid`, sorted by address.
+pub fn mappings(pid: u32) -> Result<Vec<Mapping>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+        .with_context(|| format!("read memory maps of {}", pid))?;
+    Ok(maps
+        .lines()
+        .filter_map(|line| parse_mapping(pid, line))
+        .collect())
+}
+
+/// The binary and shared objects the process `pid` has mapped executable.
+pub fn mapped_objects(pid: u32) -> Result<Vec<PathBuf>> {
+    let objects: BTreeSet<PathBuf> = mappings(pid)?.into_iter().map(|m| m.path).collect();
+    Ok(objects.into_iter().collect())
+}
+
+/// The shared objects the glibc loader maps for `binary`, as `ldd` lists them,
+/// e.g. to probe them before a process held at its `execve` loaded them.
+pub fn linked_objects(binary: &Path) -> Result<Vec<PathBuf>> {
+    let data = fs::read(binary).with_context(|| format!("read {}", binary.display()))?;
+    let file =
+        object::File::parse(&*data).with_context(|| format!("parse {}", binary.display()))?;
+    // without an interpreter, the binary would run instead of listing its objects
+    let Some(interp) = file.section_by_name(".interp") else {
+        return Ok(Vec::new());
+    };
+    // and so it would with any loader that ignores LD_TRACE_LOADED_OBJECTS, e.g. musl's
+    let interp = interp.data()?;
+    if !glibc_loader(interp) {
+        warn!(
+            "Not listing the objects of {}, its loader {} is not glibc's",
+            binary.display(),
+            String::from_utf8_lossy(interp)
+        );
+        return Ok(Vec::new());
+    }
+
+    let output = Command::new(binary)
+        .env("LD_TRACE_LOADED_OBJECTS", "1")
+        .output()
+        .with_context(|| format!("list the objects of {}", binary.display()))?;
+    if !output.status.success() {
+        bail!(
+            "Cannot list the objects of {}: {}",
+            binary.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(parse_loaded_objects(&stdout))
+}
+
+/// Whether the `.interp` of a binary names the glibc loader, e.g. `/lib64/ld-linux-x86-64.so.2`.
+fn glibc_loader(interp: &[u8]) -> bool {
+    let path = interp.strip_suffix(b"\0").unwrap_or(interp);
+    let name = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+    name.starts_with(b"ld-linux")
+}
+
+fn parse_loaded_objects(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| {
+            // `libssl.so.3 => /lib/libssl.so.3 (0x...)` or `/lib64/ld-linux-x86-64.so.2 (0x...)`
+            let line = line.trim();
+            let path = line.split_once(" => ").map_or(line, |(_, path)| path);
+            let path = path.split_whitespace().next()?;
+            path.starts_with('/').then(|| PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// The kernel's function symbols from `/proc/kallsyms`, sorted by address.
+pub struct KernelSymbols {
+    symbols: Vec<(u64, String)>,
+}
+
+impl KernelSymbols {
+    pub fn load() -> Result<Self> {
+        let kallsyms = fs::read_to_string("/proc/kallsyms").context("read /proc/kallsyms")?;
+        let mut symbols: Vec<(u64, String)> = kallsyms
+            .lines()
+            .filter_map(|line| {
+                // address type name [module]
+                let mut fields = line.split_whitespace();
+                let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+                (address != 0 && matches!(kind, "t" | "T" | "w" | "W"))
+                    .then(|| (address, name.to_string()))
+            })
+            .collect();
+        symbols.sort();
+
+        Ok(KernelSymbols { symbols })
+    }
+
+    /// The kernel function that contains `address`.
+    pub fn symbolize(&self, address: u64) -> Option<&str> {
+        let idx = self.symbols.partition_point(|(a, _)| *a <= address);
+        Some(&self.symbols.get(idx.checked_sub(1)?)?.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(
+        "quinn_proto::connection::*",
+        "quinn_proto::connection::Connection::poll",
+        true
+    )]
+    #[test_case(
+        "quinn_proto::connection::*",
+        "quinn_proto::endpoint::Endpoint::handle",
+        false
+    )]
+    #[test_case(
+        "ring_core_*_aesni_gcm_encrypt",
+        "ring_core_0_17_14__aesni_gcm_encrypt",
+        true
+    )]
+    #[test_case("ring_core_*_aesni_gcm_encrypt", "aesni_gcm_encrypt", false)]
+    #[test_case("*::recovery::*", "quiche::recovery::Recovery::on_ack_received", true)]
+    #[test_case("EVP_AEAD_CTX_open", "EVP_AEAD_CTX_open", true)]
+    #[test_case("EVP_AEAD_CTX_open", "EVP_AEAD_CTX_open_gather", false)]
+    #[test_case("a*a", "a", false)]
+    fn wildcards(pattern: &str, name: &str, matches: bool) {
+        assert_eq!(wildcard_match(pattern, name), matches);
+    }
+
+    #[test]
+    fn maps_line() {
+        let mapping = parse_mapping(
+            42,
+            "5581d2a00000-5581d2c00000 r-xp 00040000 08:01 1234 /usr/local/bin/nesquic-quinn",
+        )
+        .expect("mapping");
+        assert_eq!(
+            mapping.path,
+            PathBuf::from("/proc/42/root/usr/local/bin/nesquic-quinn")
+        );
+        assert_eq!(mapping.file_offset(0x5581d2a00010), Some(0x40010));
+        assert_eq!(mapping.file_offset(0x5581d2c00000), None);
+
+        assert!(parse_mapping(42, "7ffd1000-7ffd2000 rw-p 00000000 00:00 0 [stack]").is_none());
+        assert!(parse_mapping(42, "7ffd1000-7ffd2000 r-xp 00000000 00:00 0 [vdso]").is_none());
+    }
+
+    #[test]
+    fn loaded_objects() {
+        let output = "\tlinux-vdso.so.1 (0x00007ffd6b1f2000)
+\tlibnss3.so => /usr/lib/x86_64-linux-gnu/libnss3.so (0x00007f0a1c000000)
+\tlibmissing.so => not found
+\t/lib64/ld-linux-x86-64.so.2 (0x00007f0a1c800000)
+";
+        assert_eq!(
+            parse_loaded_objects(output),
+            vec![
+                PathBuf::from("/usr/lib/x86_64-linux-gnu/libnss3.so"),
+                PathBuf::from("/lib64/ld-linux-x86-64.so.2"),
+            ]
+        );
+    }
+
+    #[test_case(b"/lib64/ld-linux-x86-64.so.2\0", true)]
+    #[test_case(b"/lib/ld-linux-aarch64.so.1\0", true)]
+    #[test_case(b"/lib/ld-musl-x86_64.so.1\0", false)]
+    #[test_case(b"", false)]
+    fn glibc_loaders(interp: &[u8], glibc: bool) {
+        assert_eq!(glibc_loader(interp), glibc);
+    }
+
+    #[test]
+    fn demangle_strips_hash() {
+        assert_eq!(
+            demangle("_ZN11quinn_proto10connection10Connection4poll17h0123456789abcdefE"),
+            "quinn_proto::connection::Connection::poll"
+        );
+        assert_eq!(demangle("EVP_AEAD_CTX_open"), "EVP_AEAD_CTX_open");
+    }
+}
@@ -0,0 +1,402 @@
+use super::{crypto::CRYPTO_PROBES, sched::comm_to_string, types, SYSCALLS};
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+use tracing::{info, warn};
+
+unsafe impl plain::Plain for types::event_io {}
+unsafe impl plain::Plain for types::event_sched {}
+unsafe impl plain::Plain for types::event_uprobe {}
+
+/// The first byte of every event in the ring buffer, see `EVENT_KIND_*` in `metrics.bpf.c`
+const EVENT_KIND_IO: u8 = 0;
+const EVENT_KIND_SCHED: u8 = 1;
+const EVENT_KIND_UPROBE: u8 = 2;
+const UPROBE_CRYPTO: u8 = 0;
+const UPROBE_COMPONENT: u8 = 1;
+
+/// Scheduling slices overlap with the syscalls of the same thread, so they are
+/// shown in a process of their own.
+const SCHED_PID: u32 = i32::MAX as u32;
+
+/// Events kept for the trace, enough for a few seconds of a busy transfer. Later
+/// events are dropped so that the collector does not grow without bound.
+const MAX_EVENTS: usize = 2_000_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    Syscall {
+        syscall: &'static str,
+        bytes: u32,
+    },
+    /// The thread ran on `cpu`
+    OnCpu {
+        cpu: u32,
+    },
+    /// The thread waited in the run queue until it ran on `cpu`
+    RunQueue {
+        cpu: u32,
+    },
+    /// A call of `CRYPTO_PROBES[probe]`
+    Crypto {
+        probe: u32,
+        bytes: u64,
+    },
+    /// A call into the `component`th component
+    Component {
+        component: u32,
+    },
+}
+
+/// An event of the monitored process, timed with `CLOCK_MONOTONIC`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub tid: u32,
+    /// Name of the thread, if the event carries it
+    pub comm: Option<String>,
+    pub start_ns: u64,
+    pub duration_ns: u64,
+    pub kind: EventKind,
+}
+
+/// Parses an event from the ring buffer.
+pub fn parse(ev: &[u8]) -> Option<Event> {
+    match *ev.first()? {
+        EVENT_KIND_IO => {
+            let ev = plain::from_bytes::<types::event_io>(ev).ok()?;
+            Some(Event {
+                tid: ev.tid,
+                comm: Some(comm_to_string(&ev.comm)),
+                start_ns: ev.start_ns,
+                duration_ns: ev.duration_ns,
+                kind: EventKind::Syscall {
+                    syscall: SYSCALLS
+                        .get(ev.syscall as usize)
+                        .copied()
+                        .unwrap_or("unknown"),
+                    bytes: ev.len,
+                },
+            })
+        }
+        EVENT_KIND_SCHED => {
+            let ev = plain::from_bytes::<types::event_sched>(ev).ok()?;
+            let kind = match ev.runq {
+                0 => EventKind::OnCpu { cpu: ev.cpu },
+                _ => EventKind::RunQueue { cpu: ev.cpu },
+            };
+            Some(Event {
+                tid: ev.tid,
+                comm: Some(comm_to_string(&ev.comm)),
+                start_ns: ev.start_ns,
+                duration_ns: ev.duration_ns,
+                kind,
+            })
+        }
+        EVENT_KIND_UPROBE => {
+            let ev = plain::from_bytes::<types::event_uprobe>(ev).ok()?;
+            let kind = match ev.source {
+                UPROBE_CRYPTO => EventKind::Crypto {
+                    probe: ev.id,
+                    bytes: ev.bytes,
+                },
+                UPROBE_COMPONENT => EventKind::Component { component: ev.id },
+                _ => return None,
+            };
+            Some(Event {
+                tid: ev.tid,
+                comm: None,
+                start_ns: ev.start_ns,
+                duration_ns: ev.duration_ns,
+                kind,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The streamed events of a run, kept until they are exported.
+#[derive(Debug, Default)]
+pub struct Trace {
+    events: Vec<Event>,
+    /// Events dropped after reaching [`MAX_EVENTS`]
+    dropped: u64,
+    /// Latest name of every thread
+    threads: BTreeMap<u32, String>,
+    /// The tid of every thread as the IUT sees it in its pid namespace, to the tid here
+    namespace_tids: BTreeMap<u32, u32>,
+}
+
+impl Trace {
+    pub fn record(&mut self, mut event: Event) {
+        if let Some(comm) = event.comm.take() {
+            if self.threads.insert(event.tid, comm).is_none() {
+                // read while the thread is alive, it may be gone once the trace is written
+                if let Some(ns_tid) = namespace_tid(event.tid) {
+                    self.namespace_tids.insert(ns_tid, event.tid);
+                }
+            }
+        }
+        if self.events.len() >= MAX_EVENTS {
+            self.dropped += 1;
+            return;
+        }
+        self.events.push(event);
+    }
+
+    /// Writes the events of the process `pid` as Chrome trace JSON, which Perfetto opens.
+    /// The spans the IUT wrote to `spans` are merged into the same process.
+    pub fn write(
+        &self,
+        path: &Path,
+        pid: u32,
+        components: &[String],
+        spans: Option<&Path>,
+    ) -> Result<()> {
+        let mut events = self.chrome_events(pid, components);
+        if let Some(spans) = spans {
+            events.extend(read_spans(spans, pid, &self.namespace_tids)?);
+        }
+        if self.dropped > 0 {
+            warn!(
+                "The trace misses the last {} events, it is limited to {}",
+                self.dropped, MAX_EVENTS
+            );
+        }
+
+        let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        serde_json::to_writer(
+            &mut out,
+            &json!({ "traceEvents": events, "displayTimeUnit": "ns" }),
+        )?;
+        out.flush()?;
+        info!(
+            "Trace of {} events written to {}",
+            events.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    fn chrome_events(&self, pid: u32, components: &[String]) -> Vec<Value> {
+        let process = self
+            .threads
+            .get(&pid)
+            .cloned()
+            .unwrap_or_else(|| pid.to_string());
+        let mut events = vec![
+            metadata("process_name", pid, pid, &process),
+            metadata(
+                "process_name",
+                SCHED_PID,
+                SCHED_PID,
+                &format!("{} scheduling", process),
+            ),
+        ];
+        for (tid, name) in &self.threads {
+            events.push(metadata("thread_name", pid, *tid, name));
+            events.push(metadata("thread_name", SCHED_PID, *tid, name));
+        }
+
+        for event in &self.events {
+            let (name, cat, pid, args) = match &event.kind {
+                EventKind::Syscall { syscall, bytes } => (
+                    syscall.to_string(),
+                    "syscall",
+                    pid,
+                    json!({ "bytes": bytes }),
+                ),
+                EventKind::OnCpu { cpu } => (
+                    "running".to_string(),
+                    "sched",
+                    SCHED_PID,
+                    json!({ "cpu": cpu }),
+                ),
+                EventKind::RunQueue { cpu } => (
+                    "runnable".to_string(),
+                    "sched",
+                    SCHED_PID,
+                    json!({ "cpu": cpu }),
+                ),
+                EventKind::Crypto { probe, bytes } => {
+                    let name = match CRYPTO_PROBES.get(*probe as usize) {
+                        Some(probe) => format!("{} {}", probe.backend, probe.op),
+                        None => format!("crypto {}", probe),
+                    };
+                    (name, "crypto", pid, json!({ "bytes": bytes }))
+                }
+                EventKind::Component { component } => {
+                    let name = components
+                        .get(*component as usize)
+                        .cloned()
+                        .unwrap_or_else(|| format!("component {}", component));
+                    (name, "component", pid, json!({}))
+                }
+            };
+            events.push(json!({
+                "name": name,
+                "cat": cat,
+                "ph": "X",
+                "ts": event.start_ns as f64 / 1e3,
+                "dur": event.duration_ns as f64 / 1e3,
+                "pid": pid,
+                "tid": event.tid,
+                "args": args,
+            }));
+        }
+        events
+    }
+}
+
+fn metadata(name: &str, pid: u32, tid: u32, value: &str) -> Value {
+    json!({ "name": name, "ph": "M", "pid": pid, "tid": tid, "args": { "name": value } })
+}
+
+/// The tid of the thread `tid` in its innermost pid namespace, from `NSpid` in its status.
+fn namespace_tid(tid: u32) -> Option<u32> {
+    let status = fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    parse_nspid(&status)
+}
+
+fn parse_nspid(status: &str) -> Option<u32> {
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))?;
+    line.split_whitespace().last()?.parse().ok()
+}
+
+/// Reads the span events the IUT wrote, one per line, and moves them into the process `pid`,
+/// as the IUT may see another pid in its namespace. Their tids are mapped with `namespace_tids`,
+/// spans of threads that never showed up in the trace keep the tid the IUT saw.
+fn read_spans(path: &Path, pid: u32, namespace_tids: &BTreeMap<u32, u32>) -> Result<Vec<Value>> {
+    let spans = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    let mut events = Vec::new();
+    let mut malformed = 0;
+    for line in spans.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str::<Value>(line) {
+            Ok(mut event) => {
+                event["pid"] = json!(pid);
+                let tid = event["tid"]
+                    .as_u64()
+                    .and_then(|tid| u32::try_from(tid).ok());
+                if let Some(tid) = tid.and_then(|tid| namespace_tids.get(&tid)) {
+                    event["tid"] = json!(tid);
+                }
+                events.push(event);
+            }
+            // the last line is cut off if the IUT was killed
+            Err(_) => malformed += 1,
+        }
+    }
+    if malformed > 0 {
+        warn!(
+            "Skipped {} malformed spans in {}",
+            malformed,
+            path.display()
+        );
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn event(tid: u32, comm: Option<&str>, start_ns: u64, kind: EventKind) -> Event {
+        Event {
+            tid,
+            comm: comm.map(String::from),
+            start_ns,
+            duration_ns: 1500,
+            kind,
+        }
+    }
+
+    #[test]
+    fn chrome_events() {
+        let mut trace = Trace::default();
+        let sendmsg = EventKind::Syscall {
+            syscall: "sendmsg",
+            bytes: 1200,
+        };
+        trace.record(event(42, Some("quinn"), 1_000_000, sendmsg));
+        trace.record(event(
+            43,
+            Some("tokio-rt"),
+            2_000_000,
+            EventKind::OnCpu { cpu: 3 },
+        ));
+        trace.record(event(
+            43,
+            None,
+            3_000_000,
+            EventKind::Component { component: 0 },
+        ));
+        trace.record(event(
+            43,
+            None,
+            4_000_000,
+            EventKind::Component { component: 7 },
+        ));
+
+        let events = trace.chrome_events(42, &[String::from("congestion_control")]);
+        assert_eq!(events[0]["args"]["name"], "quinn");
+        assert_eq!(events[1]["args"]["name"], "quinn scheduling");
+        assert_eq!(events.len(), 2 + 2 * 2 + 4);
+
+        let slices: Vec<(&str, u64, f64, f64)> = events[6..]
+            .iter()
+            .map(|e| {
+                (
+                    e["name"].as_str().unwrap(),
+                    e["pid"].as_u64().unwrap(),
+                    e["ts"].as_f64().unwrap(),
+                    e["dur"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            slices,
+            vec![
+                ("sendmsg", 42, 1000.0, 1.5),
+                ("running", SCHED_PID as u64, 2000.0, 1.5),
+                ("congestion_control", 42, 3000.0, 1.5),
+                ("component 7", 42, 4000.0, 1.5),
+            ]
+        );
+        assert_eq!(events[6]["args"]["bytes"], 1200);
+    }
+
+    #[test]
+    fn spans() {
+        let path = env::temp_dir().join(format!("nesquic-trace-{}.jsonl", std::process::id()));
+        fs::write(
+            &path,
+            "{\"name\":\"send\",\"ph\":\"X\",\"ts\":1.5,\"dur\":2,\"pid\":1,\"tid\":9}\n\n{\"name\":\"recv\"",
+        )
+        .unwrap();
+
+        let spans = read_spans(&path, 42, &BTreeMap::new()).unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0]["name"], "send");
+        assert_eq!(spans[0]["pid"], 42);
+        assert_eq!(spans[0]["tid"], 9);
+
+        let spans = read_spans(&path, 42, &BTreeMap::from([(9, 4711)])).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(spans[0]["tid"], 4711);
+    }
+
+    #[test]
+    fn nspid() {
+        let status = "Name:\tquinn\nTgid:\t4711\nNSpid:\t4711\t9\nPPid:\t1\n";
+        assert_eq!(parse_nspid(status), Some(9));
+        assert_eq!(parse_nspid("Name:\tquinn\n"), None);
+    }
+}
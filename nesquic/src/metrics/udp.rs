@@ -0,0 +1,172 @@
+use super::{escape_tag, types};
+use anyhow::{bail, Result};
+use libbpf_rs::{MapCore, MapFlags};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+
+unsafe impl plain::Plain for types::udp_stats {}
+
+/// Where the kernel describes the `kfree_skb` tracepoint, including the names of the drop reasons.
+const KFREE_SKB_FORMAT: &[&str] = &[
+    "/sys/kernel/tracing/events/skb/kfree_skb/format",
+    "/sys/kernel/debug/tracing/events/skb/kfree_skb/format",
+];
+
+/// Time spent in and packets lost by the kernel's UDP stack on the sockets of the monitored process.
+#[derive(Clone, Debug, Default)]
+pub struct UdpStats {
+    pub send_calls: u64,
+    pub send_bytes: u64,
+    pub send_ns: u64,
+    pub send_errors: u64,
+    pub recv_calls: u64,
+    pub recv_bytes: u64,
+    pub recv_ns: u64,
+    pub recv_errors: u64,
+    /// Packets that did not fit into a socket's receive buffer
+    pub rcvbuf_errors: u64,
+    /// Packets that could not be queued to a socket for other reasons
+    pub queue_errors: u64,
+    /// Packets freed by `kfree_skb`, per drop reason
+    pub drops: BTreeMap<String, u64>,
+}
+
+impl UdpStats {
+    pub fn dropped_packets(&self) -> u64 {
+        self.drops.values().sum()
+    }
+
+    pub fn line_protocol(&self, tag_str: &str, timestamp_ns: u128) -> Vec<String> {
+        let mut lines = vec![format!(
+            "nesquic_udp{} send_calls={}i,send_bytes={}i,send_ns={}i,send_errors={}i,recv_calls={}i,recv_bytes={}i,recv_ns={}i,recv_errors={}i,rcvbuf_errors={}i,queue_errors={}i,dropped_packets={}i {}",
+            tag_str,
+            self.send_calls,
+            self.send_bytes,
+            self.send_ns,
+            self.send_errors,
+            self.recv_calls,
+            self.recv_bytes,
+            self.recv_ns,
+            self.recv_errors,
+            self.rcvbuf_errors,
+            self.queue_errors,
+            self.dropped_packets(),
+            timestamp_ns
+        )];
+
+        for (reason, count) in self.drops.iter() {
+            lines.push(format!(
+                "nesquic_udp_drop{},reason={} count={}i {}",
+                tag_str,
+                escape_tag(reason),
+                count,
+                timestamp_ns
+            ));
+        }
+
+        lines
+    }
+}
+
+/// Parses the drop reason names out of the `print fmt` of the `kfree_skb` tracepoint, i.e.
+/// `__print_symbolic(REC->reason, { 2, "NOT_SPECIFIED" }, { 3, "NO_SOCKET" }, ...)`.
+fn parse_drop_reasons(format: &str) -> HashMap<u32, String> {
+    let Some((_, symbols)) = format.split_once("__print_symbolic(REC->reason") else {
+        return HashMap::new();
+    };
+
+    symbols
+        .split('{')
+        .skip(1)
+        .map_while(|symbol| {
+            let (symbol, _) = symbol.split_once('}')?;
+            let (id, name) = symbol.split_once(',')?;
+            Some((
+                id.trim().parse().ok()?,
+                name.trim().trim_matches('"').to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// The names of the drop reasons of the running kernel. Empty if tracefs is not available.
+pub fn drop_reasons() -> HashMap<u32, String> {
+    KFREE_SKB_FORMAT
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|format| parse_drop_reasons(&format))
+        .unwrap_or_default()
+}
+
+/// Read the per-CPU UDP statistics and drops, labelling drops with the names in `reasons`.
+pub fn read_udp(
+    udp: &impl MapCore,
+    skb_drops: &impl MapCore,
+    reasons: &HashMap<u32, String>,
+) -> Result<UdpStats> {
+    let mut stats = UdpStats::default();
+
+    let key = 0u32.to_ne_bytes();
+    if let Some(per_cpu) = udp.lookup_percpu(&key, MapFlags::ANY)? {
+        for value in per_cpu {
+            let mut raw = types::udp_stats::default();
+            if plain::copy_from_bytes(&mut raw, &value).is_err() {
+                bail!("Malformed udp_stats value");
+            }
+            stats.send_calls += raw.send_calls;
+            stats.send_bytes += raw.send_bytes;
+            stats.send_ns += raw.send_ns;
+            stats.send_errors += raw.send_errors;
+            stats.recv_calls += raw.recv_calls;
+            stats.recv_bytes += raw.recv_bytes;
+            stats.recv_ns += raw.recv_ns;
+            stats.recv_errors += raw.recv_errors;
+            stats.rcvbuf_errors += raw.rcvbuf_errors;
+            stats.queue_errors += raw.queue_errors;
+        }
+    }
+
+    for key in skb_drops.keys() {
+        let Some(per_cpu) = skb_drops.lookup_percpu(&key, MapFlags::ANY)? else {
+            continue;
+        };
+
+        let reason = u32::from_ne_bytes(key[..4].try_into()?);
+        let name = reasons
+            .get(&reason)
+            .cloned()
+            .unwrap_or_else(|| reason.to_string());
+        let count: u64 = per_cpu
+            .iter()
+            .filter_map(|v| v[..8].try_into().ok().map(u64::from_ne_bytes))
+            .sum();
+        *stats.drops.entry(name).or_default() += count;
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_reason_names() {
+        let format = r#"name: kfree_skb
+ID: 1519
+format:
+	field:void * skbaddr;	offset:8;	size:8;	signed:0;
+	field:enum skb_drop_reason reason;	offset:32;	size:4;	signed:0;
+
+print fmt: "skbaddr=%p rx_sk=%p protocol=%u location=%pS reason: %s", REC->skbaddr, REC->rx_sk, REC->protocol, REC->location, __print_symbolic(REC->reason, { 2, "NOT_SPECIFIED" }, { 3, "NO_SOCKET" }, { 10, "SOCKET_RCVBUFF" })
+"#;
+        let reasons = parse_drop_reasons(format);
+        assert_eq!(reasons.len(), 3);
+        assert_eq!(reasons[&2], "NOT_SPECIFIED");
+        assert_eq!(reasons[&10], "SOCKET_RCVBUFF");
+
+        assert!(parse_drop_reasons("print fmt: \"skbaddr=%p\", REC->skbaddr").is_empty());
+    }
+}
@@ -13,6 +13,8 @@ CPU_SYSTEM=0-7,12-39
 NUM_CPU=8
 
 NESQUIC_BENCHMARK=0
+# Set to build the IUTs with frame pointers for `nesquic --profile`
+NESQUIC_PROFILE="${NESQUIC_PROFILE:-0}"
 
 WORKSPACE=$(dirname "$(readlink -f "$0")")/..
 RES_DIR="${WORKSPACE}/res"
@@ -71,6 +73,7 @@ function run_client {
         -e INFLUX_TOKEN=${INFLUX_TOKEN:-nesquic-token} \
         -e INFLUX_ORG=${INFLUX_ORG:-nesquic} \
         -e INFLUX_BUCKET=${INFLUX_BUCKET:-nesquic} \
+        -e NESQUIC_IMAGE=$(image_name $1) \
         --name ${CLIENT_CONTAINER} \
         nesquic/$1 \
         client -j ${EXP_NAME} --cert /workspace/res/pem/cert.pem --blob ${EXP_BLOB} \
@@ -90,6 +93,7 @@ function run_server {
     CMD+="-e INFLUX_TOKEN=${INFLUX_TOKEN:-nesquic-token} "
     CMD+="-e INFLUX_ORG=${INFLUX_ORG:-nesquic} "
     CMD+="-e INFLUX_BUCKET=${INFLUX_BUCKET:-nesquic} "
+    CMD+="-e NESQUIC_IMAGE=$(image_name $1) "
     CMD+="nesquic/$1 "
     CMD+="server -j ${EXP_NAME} --cert /workspace/res/pem/cert.pem --key /workspace/res/pem/key.pem 0.0.0.0:4433  -L nesquic_run:${NESQUIC_RUN_LABEL} &"
 
@@ -131,10 +135,22 @@ function teardown {
     exit 0
 }
 
+# The image of an IUT with its short ID, e.g. nesquic/quinn@0123456789ab
+function image_name {
+    echo "nesquic/$1@$(docker image inspect --format '{{.Id}}' nesquic/$1 | cut -d: -f2 | cut -c1-12)"
+}
+
 function compile {
     echo -e "${COLOR_YELLOW}Building Docker image for ${1}${COLOR_OFF}"
     docker build -f ${WORKSPACE}/docker/Dockerfile.mahimahi -t nesquic/mahimahi ${WORKSPACE}
-    docker build -f ${WORKSPACE}/docker/Dockerfile.$1 -t nesquic/$1 ${WORKSPACE}
+    local PROFILE_ARGS=()
+    if [ ${NESQUIC_PROFILE} -eq 1 ]; then
+        PROFILE_ARGS=(--build-arg PROFILE_FRAME_POINTERS=1)
+    fi
+    docker build -f ${WORKSPACE}/docker/Dockerfile.$1 -t nesquic/$1 \
+        --build-arg NESQUIC_COMMIT=$(git -C ${WORKSPACE} describe --always --dirty --abbrev=12) \
+        "${PROFILE_ARGS[@]}" \
+        ${WORKSPACE}
 }
 
 function setup {
@@ -0,0 +1,52 @@
+use std::{env, fs, path::PathBuf, process::Command};
+
+/// Stamps the commit of the harness into `NESQUIC_COMMIT`, which image builds
+/// without the git history can set themselves, and sets `NESQUIC_FRAME_POINTERS`
+/// if the build keeps frame pointers.
+fn main() {
+    println!("cargo:rerun-if-env-changed=NESQUIC_COMMIT");
+    let commit = env::var("NESQUIC_COMMIT").ok().or_else(git_commit);
+    println!(
+        "cargo:rustc-env=NESQUIC_COMMIT={}",
+        commit.as_deref().unwrap_or("unknown")
+    );
+    if frame_pointers() {
+        println!("cargo:rustc-env=NESQUIC_FRAME_POINTERS=1");
+    }
+}
+
+/// Whether the last `-C force-frame-pointers` of the rustflags turns them on.
+fn frame_pointers() -> bool {
+    let flags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    flags
+        .rsplit('\x1f')
+        .find_map(|flag| {
+            flag.trim_start_matches("-C")
+                .strip_prefix("force-frame-pointers")
+        })
+        .is_some_and(|value| matches!(value, "" | "=yes" | "=y" | "=on" | "=true"))
+}
+
+fn git_commit() -> Option<String> {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR")?);
+    let git_dir = manifest_dir.parent()?.join(".git");
+    let head = git_dir.join("HEAD");
+    println!("cargo:rerun-if-changed={}", head.display());
+    if let Some(reference) = fs::read_to_string(&head)
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+    {
+        println!(
+            "cargo:rerun-if-changed={}",
+            git_dir.join(reference).display()
+        );
+    }
+
+    let output = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=12"])
+        .current_dir(&manifest_dir)
+        .output()
+        .ok()?;
+    let commit = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (output.status.success() && !commit.is_empty()).then_some(commit)
+}
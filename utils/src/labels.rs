@@ -0,0 +1,178 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::{collections::HashMap, env, ffi::OsString, fs, path::Path};
+
+/// Keys the IUTs and the run metadata set themselves, which labels must not override
+pub const RESERVED: &[&str] = &[
+    "library",
+    "crate",
+    "version",
+    "git_rev",
+    "features",
+    "tls",
+    "mode",
+    "log_level",
+    "job",
+    "kernel",
+    "hostname",
+    "cpu_model",
+    "cores",
+    "governor",
+    "image",
+    "harness_commit",
+    "frame_pointers",
+    "gso",
+    "gro",
+    "offload_iface",
+];
+/// Prefix of the environment variables that become labels, e.g. `NESQUIC_LABEL_RUN=first`
+pub const ENV_PREFIX: &str = "NESQUIC_LABEL_";
+
+/// Parses a `key:value` label. Only the first colon separates the key, so
+/// values may contain colons, e.g. timestamps or URLs.
+pub fn parse_label(label: &str) -> Result<(String, String)> {
+    let Some((key, value)) = label.split_once(':') else {
+        bail!("Label {:?} is not of the form key:value", label);
+    };
+    validate(key.trim(), value.trim())
+}
+
+fn validate(key: &str, value: &str) -> Result<(String, String)> {
+    if key.is_empty() {
+        bail!("Label key must not be empty");
+    }
+    if let Some(c) = key
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        bail!("Label key {:?} contains {:?}", key, c);
+    }
+    if RESERVED.contains(&key) {
+        bail!("Label key {} is reserved", key);
+    }
+    if value.is_empty() {
+        bail!("Label {} has no value", key);
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parses a labels file with one `key:value` per line. Blank lines and lines
+/// starting with `#` are skipped.
+fn parse_labels_file(contents: &str) -> Result<Vec<(String, String)>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| parse_label(line).with_context(|| format!("line {}", i + 1)))
+        .collect()
+}
+
+/// The labels of the `NESQUIC_LABEL_*` variables, keyed by the lowercase rest of their name.
+/// Other variables are skipped, even if they are not valid UTF-8.
+fn env_labels(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+) -> Result<Vec<(String, String)>> {
+    vars.into_iter()
+        .filter(|(name, _)| name.as_encoded_bytes().starts_with(ENV_PREFIX.as_bytes()))
+        .map(|(name, value)| {
+            let name = name
+                .into_string()
+                .map_err(|name| anyhow!("{:?} is not valid UTF-8", name))?;
+            let Ok(value) = value.into_string() else {
+                bail!("{} is not valid UTF-8", name);
+            };
+            let key = name[ENV_PREFIX.len()..].to_lowercase();
+            validate(&key, value.trim()).with_context(|| name.clone())
+        })
+        .collect()
+}
+
+/// The labels a user set for a run, from `file`, the environment and the command line,
+/// where later sources override earlier ones.
+pub fn user_labels(
+    cli: &[(String, String)],
+    file: Option<&Path>,
+) -> Result<HashMap<String, String>> {
+    let mut labels = HashMap::new();
+    if let Some(path) = file {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        labels.extend(parse_labels_file(&contents).with_context(|| path.display().to_string())?);
+    }
+    labels.extend(env_labels(env::vars_os())?);
+    labels.extend(cli.iter().cloned());
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+    use test_case::test_case;
+
+    #[test_case("nesquic_run:first", Some(("nesquic_run", "first")))]
+    #[test_case(" run : first ", Some(("run", "first")))]
+    #[test_case("started:2024-05-01T12:00:00Z", Some(("started", "2024-05-01T12:00:00Z")))]
+    #[test_case("url:https://example.org:4433", Some(("url", "https://example.org:4433")))]
+    #[test_case("first", None; "missing colon")]
+    #[test_case(":first", None; "empty key")]
+    #[test_case("run:", None)]
+    #[test_case("my run:first", None)]
+    #[test_case("library:quinn", None)]
+    #[test_case("mode:client", None)]
+    #[test_case("hostname:bench-1", None)]
+    #[test_case("job:other", None)]
+    fn labels(label: &str, expected: Option<(&str, &str)>) {
+        assert_eq!(
+            parse_label(label).ok(),
+            expected.map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+    }
+
+    #[test]
+    fn files() {
+        let labels = parse_labels_file("# testbed\nhost:a\n\n  rack : 3:2\n").unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                (String::from("host"), String::from("a")),
+                (String::from("rack"), String::from("3:2")),
+            ]
+        );
+
+        let error = parse_labels_file("host:a\nrack\n").unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "line 2: Label \"rack\" is not of the form key:value"
+        );
+    }
+
+    #[test]
+    fn env() {
+        let vars = [
+            ("NESQUIC_LABEL_RUN_ID", "7"),
+            ("NESQUIC_IMAGE", "nesquic/quinn"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(k, v)| (OsString::from(k), OsString::from(v)));
+        let latin1 = (
+            OsString::from("LC_NAME"),
+            OsString::from_vec(vec![0x4d, 0xfc]),
+        );
+        assert_eq!(
+            env_labels(vars.into_iter().chain([latin1.clone()])).unwrap(),
+            vec![(String::from("run_id"), String::from("7"))]
+        );
+
+        let vars = [(
+            OsString::from("NESQUIC_LABEL_MODE"),
+            OsString::from("client"),
+        )];
+        assert!(env_labels(vars).is_err());
+
+        let vars = [(OsString::from("NESQUIC_LABEL_SITE"), latin1.1)];
+        assert_eq!(
+            format!("{:#}", env_labels(vars).unwrap_err()),
+            "NESQUIC_LABEL_SITE is not valid UTF-8"
+        );
+    }
+}
@@ -1,3 +1,5 @@
 pub mod bin;
 pub mod io;
+pub mod labels;
+pub mod metadata;
 pub mod perf;
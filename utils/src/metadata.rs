@@ -0,0 +1,198 @@
+use std::{collections::HashMap, env, fs, mem};
+
+/// Name of the container image, set by `script/run.sh`
+pub const IMAGE_ENV: &str = "NESQUIC_IMAGE";
+/// Commit of the harness, stamped by `build.rs`
+pub const HARNESS_COMMIT: &str = env!("NESQUIC_COMMIT");
+/// Whether the binary keeps frame pointers, as built for profiling runs
+pub const FRAME_POINTERS: bool = option_env!("NESQUIC_FRAME_POINTERS").is_some();
+
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GGSO: u32 = 0x23;
+const ETHTOOL_GGRO: u32 = 0x2b;
+
+/// Describes the machine a run is measured on, so that results from different
+/// machines are never mixed: kernel, CPU, frequency governor, hostname, container
+/// image, harness commit, frame pointers and the GSO/GRO offloads of `iface`, or
+/// of the interface of the default route. Facts that cannot be read are left out.
+pub fn run_metadata(iface: Option<&str>) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    let mut insert = |key: &str, value: Option<String>| {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            metadata.insert(key.to_string(), value);
+        }
+    };
+
+    insert("kernel", read_trimmed("/proc/sys/kernel/osrelease"));
+    insert("hostname", read_trimmed("/proc/sys/kernel/hostname"));
+    insert(
+        "cpu_model",
+        fs::read_to_string("/proc/cpuinfo")
+            .ok()
+            .and_then(|cpuinfo| cpu_model(&cpuinfo)),
+    );
+    insert(
+        "cores",
+        read_trimmed("/sys/devices/system/cpu/online")
+            .and_then(|online| count_cpus(&online))
+            .map(|cores| cores.to_string()),
+    );
+    insert("governor", governors());
+    insert("image", env::var(IMAGE_ENV).ok());
+    insert("harness_commit", Some(HARNESS_COMMIT.to_string()));
+    insert("frame_pointers", FRAME_POINTERS.then(|| String::from("on")));
+
+    let iface = iface.map(String::from).or_else(|| {
+        fs::read_to_string("/proc/net/route")
+            .ok()
+            .and_then(|routes| default_route_iface(&routes))
+    });
+    if let Some(iface) = iface {
+        insert("gso", offload(&iface, ETHTOOL_GGSO).map(on_off));
+        insert("gro", offload(&iface, ETHTOOL_GGRO).map(on_off));
+        insert("offload_iface", Some(iface));
+    }
+
+    metadata
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+/// The `model name` of the first CPU in `/proc/cpuinfo`.
+fn cpu_model(cpuinfo: &str) -> Option<String> {
+    cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "model name")
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Counts the CPUs of a list like `0-3,8,10-11`.
+fn count_cpus(list: &str) -> Option<usize> {
+    let mut count = 0;
+    for range in list.trim().split(',') {
+        count += match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (first.parse::<usize>().ok()?, last.parse::<usize>().ok()?);
+                last.checked_sub(first)? + 1
+            }
+            None => {
+                range.parse::<usize>().ok()?;
+                1
+            }
+        };
+    }
+    Some(count)
+}
+
+/// The distinct frequency governors of all CPUs, e.g. `performance`.
+fn governors() -> Option<String> {
+    let mut governors: Vec<String> = fs::read_dir("/sys/devices/system/cpu")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.strip_prefix("cpu")
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        })
+        .filter_map(|entry| fs::read_to_string(entry.path().join("cpufreq/scaling_governor")).ok())
+        .map(|governor| governor.trim().to_string())
+        .collect();
+    governors.sort();
+    governors.dedup();
+    (!governors.is_empty()).then(|| governors.join(","))
+}
+
+/// The interface of the default route in `/proc/net/route`.
+fn default_route_iface(routes: &str) -> Option<String> {
+    routes.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace();
+        let (iface, destination) = (columns.next()?, columns.next()?);
+        (destination == "00000000").then(|| iface.to_string())
+    })
+}
+
+fn on_off(enabled: bool) -> String {
+    String::from(if enabled { "on" } else { "off" })
+}
+
+#[repr(C)]
+struct EthtoolValue {
+    cmd: u32,
+    data: u32,
+}
+
+/// Reads an offload of `iface` like `ethtool -k` does.
+fn offload(iface: &str, cmd: u32) -> Option<bool> {
+    if iface.len() >= libc::IFNAMSIZ {
+        return None;
+    }
+    let mut value = EthtoolValue { cmd, data: 0 };
+    // SAFETY: ifreq is plain old data, for which all zeros is valid
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(iface.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_data = &mut value as *mut EthtoolValue as *mut libc::c_char;
+
+    // SAFETY: plain syscalls, `ifr` and `value` outlive the ioctl
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return None;
+        }
+        let ret = libc::ioctl(fd, SIOCETHTOOL as _, &mut ifr);
+        libc::close(fd);
+        (ret == 0).then_some(value.data != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpuinfo() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Xeon(R) CPU @ 2.20GHz\n\nprocessor\t: 1\nmodel name\t: Intel(R) Xeon(R) CPU @ 2.20GHz\n";
+        assert_eq!(
+            cpu_model(cpuinfo).as_deref(),
+            Some("Intel(R) Xeon(R) CPU @ 2.20GHz")
+        );
+        assert_eq!(cpu_model("processor\t: 0\n"), None);
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(count_cpus("0-7\n"), Some(8));
+        assert_eq!(count_cpus("0-3,8,10-11"), Some(7));
+        assert_eq!(count_cpus("0"), Some(1));
+        assert_eq!(count_cpus("3-1"), None);
+        assert_eq!(count_cpus(""), None);
+    }
+
+    #[test]
+    fn default_route() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      docker0\t000011AC\t00000000\t0001\t0\t0\t0\t0000FFFF\n\
+                      eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\n";
+        assert_eq!(default_route_iface(routes).as_deref(), Some("eth0"));
+        assert_eq!(default_route_iface("Iface\tDestination\n"), None);
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = run_metadata(Some("lo"));
+        assert_eq!(
+            metadata.get("harness_commit").map(String::as_str),
+            Some(HARNESS_COMMIT)
+        );
+        assert_eq!(metadata.contains_key("frame_pointers"), FRAME_POINTERS);
+        assert_eq!(
+            metadata.get("offload_iface").map(String::as_str),
+            Some("lo")
+        );
+    }
+}